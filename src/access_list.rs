@@ -0,0 +1,102 @@
+use crate::akula::address::create_address;
+use crate::akula::evm::execute;
+use crate::akula::interface::State;
+use crate::akula::intra_block_state::IntraBlockState;
+use crate::akula::precompiled;
+use crate::akula::types::PartialHeader;
+use crate::akula::utils::{get_sender, with_access_list};
+use crate::error::ForkError;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::transaction::eip2930::{AccessList, AccessListItem};
+use ethers::types::{Address, NameOrAddress, H256, U256};
+use evmodin::Revision;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The generated access list, with the gas used by the transaction with and without it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessListWithGasComparison {
+    pub access_list: AccessList,
+    pub gas_used: U256,
+    pub gas_used_without_access_list: U256,
+}
+
+/// Runs the transaction without an access list, collects what it touched, and runs it again
+/// with that access list. The state is left untouched.
+pub async fn create_access_list<S: State>(
+    state: &mut IntraBlockState<S>,
+    header: &PartialHeader,
+    revision: Revision,
+    tx: &TypedTransaction,
+) -> Result<AccessListWithGasComparison, ForkError> {
+    let tx = with_access_list(tx, AccessList::default());
+    let (gas_used_without_access_list, accessed) =
+        run_isolated(state, header, revision, &tx).await?;
+
+    let sender = get_sender(&tx);
+    let recipient = match tx.to() {
+        Some(NameOrAddress::Address(address)) => *address,
+        // `resolve_recipient()` is the one resolving it
+        Some(NameOrAddress::Name(name)) => return Err(ForkError::Ens(name.clone())),
+        None => create_address(sender, state.get_nonce(sender).await?),
+    };
+
+    // The sender, the recipient and the precompiles are warm anyway, they're only kept for
+    // their storage keys, which is also what geth does.
+    let access_list = AccessList(
+        accessed
+            .into_iter()
+            .filter(|(address, keys)| {
+                !keys.is_empty()
+                    || (*address != sender
+                        && *address != recipient
+                        && !is_precompile(*address, revision))
+            })
+            .map(|(address, keys)| AccessListItem {
+                address,
+                storage_keys: keys.into_iter().collect(),
+            })
+            .collect(),
+    );
+
+    let tx = with_access_list(&tx, access_list.clone());
+    let (gas_used, _) = run_isolated(state, header, revision, &tx).await?;
+
+    Ok(AccessListWithGasComparison {
+        access_list,
+        gas_used: gas_used.into(),
+        gas_used_without_access_list: gas_used_without_access_list.into(),
+    })
+}
+
+async fn run_isolated<S: State>(
+    state: &mut IntraBlockState<S>,
+    header: &PartialHeader,
+    revision: Revision,
+    tx: &TypedTransaction,
+) -> Result<(u64, BTreeMap<Address, BTreeSet<H256>>), ForkError> {
+    let snapshot = state.take_snapshot();
+    let ret = execute(state, header, revision, tx, i64::MAX).await;
+    // it has to be taken before the revert, which would undo the accesses
+    let (addresses, storage_keys) = state.take_accessed();
+    state.revert_to_snapshot(snapshot);
+
+    let gas_used = (i64::MAX - ret?.gas_left) as u64;
+
+    let mut accessed = BTreeMap::new();
+    for address in addresses {
+        accessed.insert(address, BTreeSet::new());
+    }
+    for (address, keys) in storage_keys {
+        accessed
+            .entry(address)
+            .or_insert_with(BTreeSet::new)
+            .extend(keys);
+    }
+
+    Ok((gas_used, accessed))
+}
+
+fn is_precompile(address: Address, revision: Revision) -> bool {
+    !address.is_zero()
+        && address <= Address::from_low_u64_be(precompiled::num_of_contracts(revision) as u64)
+}
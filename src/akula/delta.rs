@@ -5,7 +5,7 @@ use ethers::types::*;
 use std::fmt::Debug;
 
 /// Reversible change made to `IntraBlockState`.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Delta {
     Create {
         address: Address,
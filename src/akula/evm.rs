@@ -1,9 +1,11 @@
 use crate::akula::address::{create2_address, create_address};
 use crate::akula::fee_params::{fee, param};
+use crate::akula::gas_cost::gas_cost;
 use crate::akula::interface::State;
 use crate::akula::intra_block_state::IntraBlockState;
+use crate::akula::tracer::{NoopTracer, Tracer};
 use crate::akula::types::{Log, PartialHeader};
-use crate::akula::utils::{get_effective_gas_price, get_sender};
+use crate::akula::utils::{get_access_list, get_effective_gas_price, get_sender, intrinsic_gas};
 use crate::akula::{precompiled, EMPTY_HASH};
 use async_recursion::async_recursion;
 use bytes::Bytes;
@@ -12,7 +14,7 @@ use ethers::types::{Address, NameOrAddress, H256, U256};
 use evmodin::{
     continuation::{interrupt::*, interrupt_data::*, resume_data::*, Interrupt},
     host::*,
-    CallKind, CreateMessage, Message, Output, Revision, StatusCode,
+    CallKind, CreateMessage, Message, OpCode, Output, Revision, StatusCode,
 };
 use sha3::{Digest, Keccak256};
 use std::{cmp::min, convert::TryFrom};
@@ -29,9 +31,11 @@ pub struct CallResult {
     pub output_data: Bytes,
     /// Only valid when it's create message
     pub create_address: Option<Address>,
+    /// Gas refunded at the end of the transaction, it's already included in `gas_left`.
+    pub gas_refund: i64,
 }
 
-struct Evm<'state, 'h, 't, B>
+struct Evm<'state, 'h, 't, 'tr, B>
 where
     B: State,
 {
@@ -40,6 +44,7 @@ where
     revision: Revision,
     txn: &'t TypedTransaction,
     beneficiary: Address,
+    tracer: &'tr mut dyn Tracer,
 }
 
 pub async fn execute<B: State>(
@@ -48,6 +53,17 @@ pub async fn execute<B: State>(
     revision: Revision,
     txn: &TypedTransaction,
     gas: i64,
+) -> anyhow::Result<CallResult> {
+    execute_with_tracer(state, header, revision, txn, gas, &mut NoopTracer).await
+}
+
+pub async fn execute_with_tracer<B: State>(
+    state: &mut IntraBlockState<B>,
+    header: &PartialHeader,
+    revision: Revision,
+    txn: &TypedTransaction,
+    gas: i64,
+    tracer: &mut dyn Tracer,
 ) -> anyhow::Result<CallResult> {
     let mut evm = Evm {
         header,
@@ -55,20 +71,65 @@ pub async fn execute<B: State>(
         revision,
         txn,
         beneficiary: header.beneficiary,
+        tracer,
     };
 
     let from = txn.from().cloned().unwrap_or_default();
 
-    let to = txn.to().map(|x| match x {
-        NameOrAddress::Name(_) => {
-            todo!()
+    let to = match txn.to() {
+        Some(NameOrAddress::Name(name)) => {
+            anyhow::bail!("the ENS name {} has to be resolved first", name)
         }
-        NameOrAddress::Address(address) => address.clone(),
-    });
+        Some(NameOrAddress::Address(address)) => Some(*address),
+        None => None,
+    };
 
     let input_data = txn.data().map(|x| x.0.clone()).unwrap_or_default();
     let value = txn.value().cloned().unwrap_or_default();
 
+    let intrinsic_gas = intrinsic_gas(
+        txn,
+        revision >= Revision::Homestead,
+        revision >= Revision::Istanbul,
+    ) as i64;
+    if gas < intrinsic_gas {
+        return Ok(CallResult {
+            status_code: StatusCode::OutOfGas,
+            gas_left: 0,
+            output_data: Bytes::new(),
+            create_address: None,
+            gas_refund: 0,
+        });
+    }
+    let gas_limit = gas;
+    let gas = gas - intrinsic_gas;
+    let refund_before = evm.state.get_refund();
+
+    // the accessed accounts and slots of previous transactions are cold again
+    evm.state.take_accessed();
+
+    // https://eips.ethereum.org/EIPS/eip-2929
+    if revision >= Revision::Berlin {
+        evm.state.access_account(from);
+        if let Some(to) = to {
+            evm.state.access_account(to);
+        }
+        for i in 1..=evm.number_of_precompiles() {
+            evm.state.access_account(Address::from_low_u64_be(i as u64));
+        }
+
+        // https://eips.ethereum.org/EIPS/eip-2930
+        for item in get_access_list(txn)
+            .map(|x| x.0.as_slice())
+            .unwrap_or_default()
+        {
+            evm.state.access_account(item.address);
+            for key in &item.storage_keys {
+                evm.state.access_storage(item.address, *key);
+            }
+        }
+    }
+
     let res = if let Some(to) = to {
         evm.call(Message {
             kind: CallKind::Call,
@@ -95,20 +156,55 @@ pub async fn execute<B: State>(
         .await?
     };
 
+    // https://eips.ethereum.org/EIPS/eip-3529
+    let max_refund_quotient = if revision >= Revision::London {
+        param::MAX_REFUND_QUOTIENT_LONDON
+    } else {
+        param::MAX_REFUND_QUOTIENT_FRONTIER
+    } as i64;
+    let gas_used = gas_limit - res.gas_left;
+    let gas_refund = min(
+        evm.state.get_refund().saturating_sub(refund_before) as i64,
+        gas_used / max_refund_quotient,
+    );
+
     Ok(CallResult {
         status_code: res.status_code,
-        gas_left: res.gas_left,
+        gas_left: res.gas_left + gas_refund,
         output_data: res.output_data,
         create_address: res.create_address,
+        gas_refund,
     })
 }
 
-impl<'state, 'h, 't, B> Evm<'state, 'h, 't, B>
+impl<'state, 'h, 't, 'tr, B> Evm<'state, 'h, 't, 'tr, B>
 where
     B: State,
 {
     #[async_recursion]
     async fn create(&mut self, message: CreateMessage) -> anyhow::Result<Output> {
+        let kind = if message.salt.is_some() {
+            CallKind::Create2
+        } else {
+            CallKind::Create
+        };
+        self.tracer.capture_enter(
+            kind,
+            false,
+            message.sender,
+            None,
+            &message.initcode,
+            message.gas,
+            message.endowment,
+        );
+
+        let res = self.create_inner(message).await?;
+
+        self.tracer.capture_exit(&res);
+        Ok(res)
+    }
+
+    async fn create_inner(&mut self, message: CreateMessage) -> anyhow::Result<Output> {
         let mut res = Output {
             status_code: StatusCode::Success,
             gas_left: message.gas,
@@ -211,6 +307,29 @@ where
 
     #[async_recursion]
     async fn call(&mut self, message: Message) -> anyhow::Result<Output> {
+        // a delegate call keeps the original sender, but it's the current contract making the call
+        let caller = if message.kind == CallKind::DelegateCall {
+            message.recipient
+        } else {
+            message.sender
+        };
+        self.tracer.capture_enter(
+            message.kind,
+            message.is_static,
+            caller,
+            Some(message.code_address),
+            &message.input_data,
+            message.gas,
+            message.value,
+        );
+
+        let res = self.call_inner(message).await?;
+
+        self.tracer.capture_exit(&res);
+        Ok(res)
+    }
+
+    async fn call_inner(&mut self, message: Message) -> anyhow::Result<Output> {
         let mut res = Output {
             status_code: StatusCode::Success,
             gas_left: message.gas,
@@ -296,12 +415,33 @@ where
 
     async fn execute(&mut self, msg: Message, code: Vec<u8>) -> anyhow::Result<Output> {
         let mut interrupt = evmodin::AnalyzedCode::analyze(code)
-            .execute_resumable(false, msg, self.revision)
+            .execute_resumable(self.tracer.trace_instructions(), msg, self.revision)
             .resume(());
 
         let output = loop {
             interrupt = match interrupt {
-                InterruptVariant::InstructionStart(_) => unreachable!("tracing is disabled"),
+                InterruptVariant::InstructionStart(i) => {
+                    let data = i.data();
+                    let address = data.state.message.recipient;
+                    let stack = &data.state.stack;
+
+                    let storage = if data.opcode == OpCode::SLOAD && stack.len() >= 1 {
+                        let key = u256_to_h256(*stack.get(0));
+                        let value = self.state.get_current_storage(address, key).await?;
+                        Some((key, value))
+                    } else if data.opcode == OpCode::SSTORE && stack.len() >= 2 {
+                        Some((u256_to_h256(*stack.get(0)), u256_to_h256(*stack.get(1))))
+                    } else {
+                        None
+                    };
+
+                    let gas_cost = gas_cost(self.state, data.opcode, &data.state).await?;
+
+                    self.tracer
+                        .capture_step(data.pc, data.opcode, gas_cost, &data.state, storage);
+
+                    i.resume(())
+                }
                 InterruptVariant::AccountExists(i) => {
                     let address = i.data().address;
                     let exists = if self.revision >= Revision::Spurious {
@@ -494,7 +634,7 @@ where
                     let block_timestamp = self.header.timestamp;
                     let block_gas_limit = self.header.gas_limit;
                     let block_difficulty = self.header.difficulty;
-                    let chain_id = 1.into();
+                    let chain_id = self.header.chain_id.into();
                     let block_base_fee = base_fee_per_gas;
 
                     let context = TxContext {
@@ -514,11 +654,18 @@ where
                 InterruptVariant::GetBlockHash(i) => {
                     let n = i.data().block_number;
 
+                    // only the 256 most recent blocks are available, the others are zero
                     let base_number = self.header.number;
-                    let distance = base_number - n;
-                    assert!(distance <= 256);
-
-                    let hash = self.state.db().read_block_header(n).await?.unwrap().hash;
+                    let hash = if n < base_number && base_number - n <= 256 {
+                        self.state
+                            .db()
+                            .read_block_header(n)
+                            .await?
+                            .ok_or_else(|| anyhow::anyhow!("block {} not found", n))?
+                            .hash
+                    } else {
+                        H256::zero()
+                    };
 
                     i.resume(BlockHash { hash })
                 }
@@ -565,17 +712,7 @@ where
     }
 
     fn number_of_precompiles(&self) -> u8 {
-        match self.revision {
-            Revision::Frontier | Revision::Homestead | Revision::Tangerine | Revision::Spurious => {
-                precompiled::NUM_OF_FRONTIER_CONTRACTS as u8
-            }
-            Revision::Byzantium | Revision::Constantinople | Revision::Petersburg => {
-                precompiled::NUM_OF_BYZANTIUM_CONTRACTS as u8
-            }
-            Revision::Istanbul | Revision::Berlin | Revision::London | Revision::Shanghai => {
-                precompiled::NUM_OF_ISTANBUL_CONTRACTS as u8
-            }
-        }
+        precompiled::num_of_contracts(self.revision) as u8
     }
 
     fn is_precompiled(&self, contract: Address) -> bool {
@@ -588,3 +725,9 @@ where
         }
     }
 }
+
+pub(crate) fn u256_to_h256(v: U256) -> H256 {
+    let mut buf = H256::zero();
+    v.to_big_endian(&mut buf.0);
+    buf
+}
@@ -0,0 +1,220 @@
+use crate::akula::evm::u256_to_h256;
+use crate::akula::fee_params::fee;
+use crate::akula::interface::State;
+use crate::akula::intra_block_state::IntraBlockState;
+use ethers::types::{Address, U256};
+use evmodin::{ExecutionState, OpCode};
+
+const COLD_ACCOUNT_ACCESS_COST: u64 = 2600;
+const CALL_VALUE_TRANSFER_COST: u64 = 9000;
+const CALL_NEW_ACCOUNT_COST: u64 = 25000;
+const SELF_DESTRUCT_COST: u64 = 5000;
+const CREATE_COST: u64 = 32000;
+const LOG_COST: u64 = 375;
+
+/// The gas an instruction is about to be charged, computed the way geth reports `gasCost` in its
+/// struct logs: the static cost plus memory expansion and EIP-2929 access costs. For the CALL
+/// family it also includes the gas forwarded to the callee.
+///
+/// Costs are the London ones, the only revision the provider runs. It has to be called before
+/// the instruction executes, as it doesn't touch the accessed addresses and slots.
+pub async fn gas_cost<B>(
+    state: &mut IntraBlockState<B>,
+    opcode: OpCode,
+    execution: &ExecutionState,
+) -> anyhow::Result<u64>
+where
+    B: State,
+{
+    let op = opcode.to_u8();
+    let stack = &execution.stack;
+    // an instruction with too few arguments fails before any dynamic cost is charged
+    let arg = |i: usize| {
+        if i < stack.len() {
+            *stack.get(i)
+        } else {
+            U256::zero()
+        }
+    };
+    let address = |i: usize| Address::from_slice(&u256_to_h256(arg(i))[12..]);
+    let memory = execution.memory.len();
+    let access_cost = |warm: bool| {
+        if warm {
+            fee::WARM_STORAGE_READ_COST
+        } else {
+            COLD_ACCOUNT_ACCESS_COST
+        }
+    };
+
+    let cost = match op {
+        // EXP
+        0x0a => {
+            let exponent_bytes = (arg(1).bits() as u64 + 7) / 8;
+            10 + 50 * exponent_bytes
+        }
+        // SHA3
+        0x20 => 30 + 6 * words(arg(1)) + memory_expansion(memory, &[(arg(0), arg(1))]),
+        // BALANCE, EXTCODESIZE, EXTCODEHASH
+        0x31 | 0x3b | 0x3f => access_cost(state.is_account_warm(address(0))),
+        // CALLDATACOPY, CODECOPY, RETURNDATACOPY
+        0x37 | 0x39 | 0x3e => 3 + 3 * words(arg(2)) + memory_expansion(memory, &[(arg(0), arg(2))]),
+        // EXTCODECOPY
+        0x3c => {
+            access_cost(state.is_account_warm(address(0)))
+                + 3 * words(arg(3))
+                + memory_expansion(memory, &[(arg(1), arg(3))])
+        }
+        // MLOAD, MSTORE
+        0x51 | 0x52 => 3 + memory_expansion(memory, &[(arg(0), 32.into())]),
+        // MSTORE8
+        0x53 => 3 + memory_expansion(memory, &[(arg(0), 1.into())]),
+        // SLOAD
+        0x54 => {
+            let key = u256_to_h256(arg(0));
+            if state.is_storage_warm(execution.message.recipient, key) {
+                fee::WARM_STORAGE_READ_COST
+            } else {
+                fee::COLD_SLOAD_COST
+            }
+        }
+        // SSTORE, https://eips.ethereum.org/EIPS/eip-2929
+        0x55 => {
+            let recipient = execution.message.recipient;
+            let key = u256_to_h256(arg(0));
+            let value = u256_to_h256(arg(1));
+            let cold = if state.is_storage_warm(recipient, key) {
+                0
+            } else {
+                fee::COLD_SLOAD_COST
+            };
+            let current = state.get_current_storage(recipient, key).await?;
+            let original = state.get_original_storage(recipient, key).await?;
+            cold + if current == value || original != current {
+                fee::WARM_STORAGE_READ_COST
+            } else if original.is_zero() {
+                fee::G_SSET
+            } else {
+                fee::G_SRESET - fee::COLD_SLOAD_COST
+            }
+        }
+        // LOG0..LOG4
+        0xa0..=0xa4 => {
+            LOG_COST * (op - 0xa0 + 1) as u64
+                + 8 * arg(1).min(u32::MAX.into()).as_u64()
+                + memory_expansion(memory, &[(arg(0), arg(1))])
+        }
+        // CREATE
+        0xf0 => CREATE_COST + memory_expansion(memory, &[(arg(1), arg(2))]),
+        // CREATE2
+        0xf5 => CREATE_COST + 6 * words(arg(2)) + memory_expansion(memory, &[(arg(1), arg(2))]),
+        // CALL, CALLCODE, DELEGATECALL, STATICCALL
+        0xf1 | 0xf2 | 0xf4 | 0xfa => {
+            let target = address(1);
+            // only CALL and CALLCODE take a value, it shifts the memory arguments by one
+            let (value, args) = if op == 0xf1 || op == 0xf2 {
+                (arg(2), 3)
+            } else {
+                (U256::zero(), 2)
+            };
+            let mut cost = access_cost(state.is_account_warm(target))
+                + memory_expansion(
+                    memory,
+                    &[(arg(args), arg(args + 1)), (arg(args + 2), arg(args + 3))],
+                );
+            if !value.is_zero() {
+                cost += CALL_VALUE_TRANSFER_COST;
+                if op == 0xf1 && state.is_dead(target).await? {
+                    cost += CALL_NEW_ACCOUNT_COST;
+                }
+            }
+            // https://eips.ethereum.org/EIPS/eip-150, the stipend isn't counted
+            let available = (execution.gas_left.max(0) as u64).saturating_sub(cost);
+            let forwarded = arg(0).min((available - available / 64).into()).as_u64();
+            cost.saturating_add(forwarded)
+        }
+        // SELFDESTRUCT
+        0xff => {
+            let beneficiary = address(0);
+            let mut cost = SELF_DESTRUCT_COST;
+            if !state.is_account_warm(beneficiary) {
+                cost += COLD_ACCOUNT_ACCESS_COST;
+            }
+            if state.is_dead(beneficiary).await?
+                && !state
+                    .get_balance(execution.message.recipient)
+                    .await?
+                    .is_zero()
+            {
+                cost += CALL_NEW_ACCOUNT_COST;
+            }
+            cost
+        }
+        // RETURN, REVERT
+        0xf3 | 0xfd => memory_expansion(memory, &[(arg(0), arg(1))]),
+        _ => static_cost(op),
+    };
+
+    Ok(cost)
+}
+
+fn static_cost(op: u8) -> u64 {
+    match op {
+        // MUL, DIV, SDIV, MOD, SMOD, SIGNEXTEND, SELFBALANCE
+        0x02 | 0x04..=0x07 | 0x0b | 0x47 => 5,
+        // ADDMOD, MULMOD, JUMP
+        0x08 | 0x09 | 0x56 => 8,
+        // ADD, SUB, comparisons, bitwise ops and shifts, CALLDATALOAD, PUSH1..PUSH32,
+        // DUP1..DUP16, SWAP1..SWAP16
+        0x01 | 0x03 | 0x10..=0x1d | 0x35 | 0x60..=0x9f => 3,
+        // ADDRESS, ORIGIN, CALLER, CALLVALUE, CALLDATASIZE, CODESIZE, GASPRICE, RETURNDATASIZE,
+        // block information, POP, PC, MSIZE, GAS, BASEFEE
+        0x30
+        | 0x32..=0x34
+        | 0x36
+        | 0x38
+        | 0x3a
+        | 0x3d
+        | 0x41..=0x46
+        | 0x48
+        | 0x50
+        | 0x58
+        | 0x59
+        | 0x5a => 2,
+        // BLOCKHASH
+        0x40 => 20,
+        // JUMPI
+        0x57 => 10,
+        // JUMPDEST
+        0x5b => 1,
+        // STOP, INVALID and undefined instructions
+        _ => 0,
+    }
+}
+
+/// The number of 32 byte words covering `size` bytes. Sizes above 4GB run out of gas anyway,
+/// they're capped so that the costs can't overflow.
+fn words(size: U256) -> u64 {
+    (size.min(u32::MAX.into()).as_u64() + 31) / 32
+}
+
+fn memory_cost(words: u64) -> u64 {
+    3 * words + words * words / 512
+}
+
+/// The cost of growing the memory of `current` bytes to cover every `(offset, size)` region,
+/// regions of size 0 don't need any memory.
+fn memory_expansion(current: usize, regions: &[(U256, U256)]) -> u64 {
+    let mut end = U256::zero();
+    for (offset, size) in regions {
+        if !size.is_zero() {
+            end = end.max(offset.saturating_add(*size));
+        }
+    }
+    let current = words(current.into());
+    let new = words(end);
+    if new <= current {
+        0
+    } else {
+        memory_cost(new) - memory_cost(current)
+    }
+}
@@ -9,7 +9,8 @@ use evmodin::host::AccessStatus;
 use hex_literal::hex;
 use std::collections::*;
 
-#[derive(Debug)]
+/// The default one is the snapshot of an empty journal.
+#[derive(Clone, Debug, Default)]
 pub struct Snapshot {
     journal_size: usize,
     log_size: usize,
@@ -213,6 +214,25 @@ impl<S: State> IntraBlockState<S> {
         Ok(())
     }
 
+    /// Removes the account with its storage, unlike `destruct()` it can be reverted.
+    pub async fn delete_account(&mut self, address: Address) -> anyhow::Result<()> {
+        if let Some(obj) = get_object(&self.db, &mut self.objects, address).await? {
+            self.journal.push(Delta::Update {
+                address,
+                previous: obj.clone(),
+            });
+            obj.current = None;
+        }
+        if let Some(removed) = self.storage.remove(&address) {
+            self.journal.push(Delta::StorageWipe {
+                address,
+                storage: removed,
+            });
+        }
+
+        Ok(())
+    }
+
     pub fn record_selfdestruct(&mut self, address: Address) {
         if self.self_destructs.insert(address) {
             self.journal.push(Delta::Selfdestruct { address });
@@ -411,6 +431,27 @@ impl<S: State> IntraBlockState<S> {
         }
     }
 
+    /// Whether `access_account()` would find the account warm, without accessing it.
+    pub fn is_account_warm(&self, address: Address) -> bool {
+        self.accessed_addresses.contains(&address)
+    }
+
+    /// Whether `access_storage()` would find the slot warm, without accessing it.
+    pub fn is_storage_warm(&self, address: Address, key: H256) -> bool {
+        self.accessed_storage_keys
+            .get(&address)
+            .map_or(false, |keys| keys.contains(&key))
+    }
+
+    /// Takes the EIP-2929 accessed addresses and storage keys out, leaving them empty.
+    /// It's not journaled.
+    pub fn take_accessed(&mut self) -> (HashSet<Address>, HashMap<Address, HashSet<H256>>) {
+        (
+            std::mem::take(&mut self.accessed_addresses),
+            std::mem::take(&mut self.accessed_storage_keys),
+        )
+    }
+
     async fn get_storage(
         &mut self,
         address: Address,
@@ -471,6 +512,21 @@ impl<S: State> IntraBlockState<S> {
         self.get_storage(address, key, true).await
     }
 
+    /// The storage slots of the account which have been read or written so far.
+    pub fn known_storage_keys(&self, address: Address) -> Vec<H256> {
+        let mut keys = vec![];
+        if let Some(storage) = self.storage.get(&address) {
+            keys.extend(storage.committed.keys());
+            keys.extend(
+                storage
+                    .current
+                    .keys()
+                    .filter(|key| !storage.committed.contains_key(key)),
+            );
+        }
+        keys
+    }
+
     pub async fn set_storage(
         &mut self,
         address: Address,
@@ -503,6 +559,11 @@ impl<S: State> IntraBlockState<S> {
             refund: self.refund,
         }
     }
+    /// The changes made after the snapshot was taken, oldest first.
+    pub fn journal_since(&self, snapshot: &Snapshot) -> &[Delta] {
+        &self.journal[snapshot.journal_size..]
+    }
+
     pub fn revert_to_snapshot(&mut self, snapshot: Snapshot) {
         for _ in 0..self.journal.len() - snapshot.journal_size {
             self.journal.pop().unwrap().revert(self);
@@ -542,6 +603,11 @@ impl<S: State> IntraBlockState<S> {
         &self.logs
     }
 
+    /// The logs emitted after the snapshot was taken.
+    pub fn logs_since(&self, snapshot: &Snapshot) -> &[Log] {
+        &self.logs[snapshot.log_size..]
+    }
+
     pub fn add_refund(&mut self, addend: u64) {
         self.refund += addend;
     }
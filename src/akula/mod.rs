@@ -6,9 +6,11 @@ pub mod blake2;
 pub mod delta;
 pub mod evm;
 pub mod fee_params;
+pub mod gas_cost;
 pub mod interface;
 pub mod intra_block_state;
 pub mod precompiled;
+pub mod tracer;
 pub mod types;
 pub mod utils;
 
@@ -73,6 +73,21 @@ pub const NUM_OF_FRONTIER_CONTRACTS: usize = 4;
 pub const NUM_OF_BYZANTIUM_CONTRACTS: usize = 8;
 pub const NUM_OF_ISTANBUL_CONTRACTS: usize = 9;
 
+/// The precompiled contracts of the revision are at the addresses from 1 to this number.
+pub fn num_of_contracts(revision: Revision) -> usize {
+    match revision {
+        Revision::Frontier | Revision::Homestead | Revision::Tangerine | Revision::Spurious => {
+            NUM_OF_FRONTIER_CONTRACTS
+        }
+        Revision::Byzantium | Revision::Constantinople | Revision::Petersburg => {
+            NUM_OF_BYZANTIUM_CONTRACTS
+        }
+        Revision::Istanbul | Revision::Berlin | Revision::London | Revision::Shanghai => {
+            NUM_OF_ISTANBUL_CONTRACTS
+        }
+    }
+}
+
 fn ecrecover_gas(_: Bytes, _: Revision) -> Option<u64> {
     Some(3_000)
 }
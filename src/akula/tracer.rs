@@ -0,0 +1,50 @@
+use bytes::Bytes;
+use ethers::types::{Address, H256, U256};
+use evmodin::{CallKind, ExecutionState, OpCode, Output};
+
+/// Receives the execution steps of a transaction from the interpreter.
+pub trait Tracer: Send {
+    /// Whether the interpreter should stop before every instruction.
+    fn trace_instructions(&self) -> bool {
+        false
+    }
+
+    /// Called before every instruction if `trace_instructions()` is on.
+    ///
+    /// `gas_cost` is what the instruction is about to be charged, see `gas_cost()`. `storage` is
+    /// the slot read by `SLOAD` or written by `SSTORE`, together with the value it holds (or is
+    /// about to hold).
+    fn capture_step(
+        &mut self,
+        _pc: usize,
+        _opcode: OpCode,
+        _gas_cost: u64,
+        _state: &ExecutionState,
+        _storage: Option<(H256, H256)>,
+    ) {
+    }
+
+    /// Called when a new call frame (including the top level one and precompiles) starts.
+    ///
+    /// `to` is `None` for contract creation, the address is only known in `capture_exit()`.
+    #[allow(clippy::too_many_arguments)]
+    fn capture_enter(
+        &mut self,
+        _kind: CallKind,
+        _is_static: bool,
+        _from: Address,
+        _to: Option<Address>,
+        _input: &Bytes,
+        _gas: i64,
+        _value: U256,
+    ) {
+    }
+
+    /// Called when the call frame started by the latest `capture_enter()` returns.
+    fn capture_exit(&mut self, _output: &Output) {}
+}
+
+#[derive(Debug, Default)]
+pub struct NoopTracer;
+
+impl Tracer for NoopTracer {}
@@ -29,6 +29,8 @@ pub struct PartialHeader {
     pub base_fee_per_gas: Option<U256>,
     pub hash: H256,
     pub beneficiary: Address,
+    /// It's not part of the header, but the `CHAINID` opcode needs it along with the others.
+    pub chain_id: u64,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -37,7 +39,7 @@ pub struct Object {
     pub current: Option<Account>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct CommittedValue {
     /// Value at the begining of the block
     pub initial: H256,
@@ -45,7 +47,7 @@ pub struct CommittedValue {
     pub original: H256,
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct Storage {
     pub committed: HashMap<H256, CommittedValue>,
     pub current: HashMap<H256, H256>,
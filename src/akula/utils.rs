@@ -1,5 +1,7 @@
+use crate::akula::fee_params::fee;
 use bytes::{Bytes, BytesMut};
 use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::transaction::eip2930::{AccessList, Eip2930TransactionRequest};
 use ethers::types::{Address, H256, U256};
 use sha3::{Digest, Keccak256};
 
@@ -34,12 +36,12 @@ pub fn get_effective_gas_price(tx: &TypedTransaction, base_fee_per_gas: U256) ->
         TypedTransaction::Eip2930(tx) => tx.tx.gas_price.unwrap_or_default(),
         TypedTransaction::Eip1559(tx) => {
             if let Some(max_fee_per_gas) = tx.max_fee_per_gas {
-                assert!(max_fee_per_gas >= base_fee_per_gas);
+                // a max fee below the base fee is rejected before execution, only calls get here
                 let priority_gas_fee = std::cmp::min(
                     tx.max_priority_fee_per_gas.unwrap_or_default(),
-                    max_fee_per_gas - base_fee_per_gas,
+                    max_fee_per_gas.saturating_sub(base_fee_per_gas),
                 );
-                priority_gas_fee + base_fee_per_gas
+                std::cmp::min(max_fee_per_gas, priority_gas_fee + base_fee_per_gas)
             } else {
                 // just query calls
                 U256::zero()
@@ -48,6 +50,77 @@ pub fn get_effective_gas_price(tx: &TypedTransaction, base_fee_per_gas: U256) ->
     }
 }
 
+pub fn get_max_fee_per_gas(tx: &TypedTransaction) -> U256 {
+    match tx {
+        TypedTransaction::Legacy(tx) => tx.gas_price.unwrap_or_default(),
+        TypedTransaction::Eip2930(tx) => tx.tx.gas_price.unwrap_or_default(),
+        TypedTransaction::Eip1559(tx) => tx.max_fee_per_gas.unwrap_or_default(),
+    }
+}
+
+pub fn get_max_priority_fee_per_gas(tx: &TypedTransaction) -> U256 {
+    match tx {
+        TypedTransaction::Eip1559(tx) => tx.max_priority_fee_per_gas.unwrap_or_default(),
+        _ => get_max_fee_per_gas(tx),
+    }
+}
+
 pub fn get_sender(tx: &TypedTransaction) -> Address {
     tx.from().cloned().unwrap_or_default()
 }
+
+pub fn get_access_list(tx: &TypedTransaction) -> Option<&AccessList> {
+    match tx {
+        TypedTransaction::Legacy(_) => None,
+        TypedTransaction::Eip2930(tx) => Some(&tx.access_list),
+        TypedTransaction::Eip1559(tx) => Some(&tx.access_list),
+    }
+}
+
+/// Attaches the access list, a legacy transaction is turned into an EIP-2930 one.
+pub fn with_access_list(tx: &TypedTransaction, access_list: AccessList) -> TypedTransaction {
+    match tx {
+        TypedTransaction::Legacy(tx) => TypedTransaction::Eip2930(Eip2930TransactionRequest {
+            tx: tx.clone(),
+            access_list,
+        }),
+        TypedTransaction::Eip2930(tx) => TypedTransaction::Eip2930(Eip2930TransactionRequest {
+            access_list,
+            ..tx.clone()
+        }),
+        TypedTransaction::Eip1559(tx) => {
+            let mut tx = tx.clone();
+            tx.access_list = access_list;
+            TypedTransaction::Eip1559(tx)
+        }
+    }
+}
+
+// https://eips.ethereum.org/EIPS/eip-2028
+// https://eips.ethereum.org/EIPS/eip-2930
+pub fn intrinsic_gas(tx: &TypedTransaction, homestead: bool, istanbul: bool) -> u64 {
+    let mut gas = fee::G_TRANSACTION;
+
+    if tx.to().is_none() && homestead {
+        gas += fee::G_TX_CREATE;
+    }
+
+    if let Some(access_list) = get_access_list(tx) {
+        for item in &access_list.0 {
+            gas += fee::ACCESS_LIST_ADDRESS_COST;
+            gas += item.storage_keys.len() as u64 * fee::ACCESS_LIST_STORAGE_KEY_COST;
+        }
+    }
+
+    let data = tx.data().map(|x| &x.0[..]).unwrap_or_default();
+    let non_zero_bytes = data.iter().filter(|&&b| b != 0).count() as u64;
+    let zero_bytes = data.len() as u64 - non_zero_bytes;
+
+    let non_zero_gas = if istanbul {
+        fee::G_TX_DATA_NON_ZERO_ISTANBUL
+    } else {
+        fee::G_TX_DATA_NON_ZERO_FRONTIER
+    };
+
+    gas + zero_bytes * fee::G_TX_DATA_ZERO + non_zero_bytes * non_zero_gas
+}
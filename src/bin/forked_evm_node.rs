@@ -0,0 +1,147 @@
+use clap::Parser;
+use ethers_forked_evm_provider::{ForkedEvmProvider, RpcHandler};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use hyper::header::{
+    HeaderValue, CONNECTION, CONTENT_TYPE, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE,
+};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// Serves a fork of mainnet over JSON-RPC, on HTTP and websocket at the same port.
+#[derive(Debug, Parser)]
+struct Args {
+    /// The block the fork starts from.
+    #[clap(long)]
+    fork_block_number: u64,
+    /// The websocket URL of the archive node, it's not used once the database is recorded.
+    #[clap(long, env = "ARCHIVE_WSS_URL")]
+    fork_url: String,
+    /// The sqlite database the fork state is recorded in, and read from if it exists.
+    #[clap(long)]
+    db_path: PathBuf,
+    #[clap(long, default_value = "1")]
+    chain_id: u64,
+    #[clap(long, default_value = "127.0.0.1")]
+    host: IpAddr,
+    #[clap(long, default_value = "8545")]
+    port: u16,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let provider = ForkedEvmProvider::new(args.fork_block_number, &args.fork_url, args.db_path)
+        .await?
+        .with_chain_id(args.chain_id);
+    let handler = RpcHandler::new(Arc::new(provider));
+
+    let make_service = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| serve(req, handler.clone()))) }
+    });
+
+    let addr = SocketAddr::new(args.host, args.port);
+    println!("Listening on {}", addr);
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+async fn serve(req: Request<Body>, handler: RpcHandler) -> Result<Response<Body>, Infallible> {
+    if req.headers().contains_key(SEC_WEBSOCKET_KEY) {
+        return Ok(upgrade(req, handler));
+    }
+    if req.method() != Method::POST {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return Ok(status(StatusCode::BAD_REQUEST)),
+    };
+    let text = handler.handle_text(&String::from_utf8_lossy(&body)).await;
+
+    let mut response = Response::new(Body::from(text));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(response)
+}
+
+/// Completes the websocket handshake, the connection is served once hyper hands it over.
+fn upgrade(req: Request<Body>, handler: RpcHandler) -> Response<Body> {
+    let accept = match req.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) => derive_accept_key(key.as_bytes()),
+        None => return status(StatusCode::BAD_REQUEST),
+    };
+    let accept = match HeaderValue::from_str(&accept) {
+        Ok(accept) => accept,
+        Err(_) => return status(StatusCode::BAD_REQUEST),
+    };
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                if let Err(e) = serve_websocket(ws, handler).await {
+                    eprintln!("websocket error: {}", e);
+                }
+            }
+            Err(e) => eprintln!("websocket upgrade error: {}", e),
+        }
+    });
+
+    let mut response = status(StatusCode::SWITCHING_PROTOCOLS);
+    let headers = response.headers_mut();
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(SEC_WEBSOCKET_ACCEPT, accept);
+    response
+}
+
+async fn serve_websocket(
+    mut ws: WebSocketStream<Upgraded>,
+    handler: RpcHandler,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let (sink, mut notifications) = mpsc::unbounded();
+    let handler = handler.with_notifications(sink);
+
+    loop {
+        let text = tokio::select! {
+            message = ws.next() => match message {
+                Some(message) => match message? {
+                    Message::Text(text) => text,
+                    Message::Binary(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                    Message::Close(_) => break,
+                    // pings are answered by tungstenite
+                    _ => continue,
+                },
+                None => break,
+            },
+            // the handler keeps a sender, so it never ends
+            Some(notification) = notifications.next() => {
+                ws.send(Message::Text(notification)).await?;
+                continue;
+            }
+        };
+        ws.send(Message::Text(handler.handle_text(&text).await))
+            .await?;
+    }
+    Ok(())
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,72 @@
+use crate::akula::utils::keccak256;
+use crate::tracers::state_diff::StateDiff;
+use ethers::types::{Address, Bytes, Log, H256, U256};
+use serde::Serialize;
+
+/// The outcome of a transaction of the bundle, in the shape of flashbots' `eth_callBundle`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleTransactionResult {
+    #[serde(with = "decimal")]
+    pub coinbase_diff: U256,
+    #[serde(with = "decimal")]
+    pub eth_sent_to_coinbase: U256,
+    pub from_address: Address,
+    /// The tips, the base fee is burnt and never reaches the coinbase.
+    #[serde(with = "decimal")]
+    pub gas_fees: U256,
+    #[serde(with = "decimal")]
+    pub gas_price: U256,
+    pub gas_used: u64,
+    pub to_address: Option<Address>,
+    pub tx_hash: H256,
+    /// The returned data, or the revert data.
+    pub value: Bytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The decoded revert reason.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revert: Option<String>,
+    pub logs: Vec<Log>,
+}
+
+/// The outcome of a bundle, in the shape of flashbots' `eth_callBundle`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleSimulation {
+    /// What the coinbase got per unit of gas.
+    #[serde(with = "decimal")]
+    pub bundle_gas_price: U256,
+    pub bundle_hash: H256,
+    #[serde(with = "decimal")]
+    pub coinbase_diff: U256,
+    #[serde(with = "decimal")]
+    pub eth_sent_to_coinbase: U256,
+    #[serde(with = "decimal")]
+    pub gas_fees: U256,
+    pub results: Vec<BundleTransactionResult>,
+    pub state_block_number: u64,
+    pub total_gas_used: u64,
+    /// Every account and storage slot the bundle modified.
+    #[serde(skip)]
+    pub state_diff: StateDiff,
+}
+
+/// Like flashbots, the hash of the hashes of the transactions.
+pub fn bundle_hash(hashes: impl Iterator<Item = H256>) -> H256 {
+    keccak256(
+        hashes
+            .flat_map(|hash| hash.to_fixed_bytes())
+            .collect::<Vec<u8>>(),
+    )
+}
+
+// flashbots returns the amounts of wei as decimal strings
+mod decimal {
+    use ethers::types::U256;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(value: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+}
@@ -0,0 +1,305 @@
+use async_trait::async_trait;
+use ethers::providers::{JsonRpcClient, ProviderError, PubsubClient, Ws};
+use ethers::types::U256;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A request to the archive node and its response, one per line of a cassette.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub method: String,
+    pub params: Value,
+    pub result: Value,
+}
+
+/// Writes the interactions into a cassette as they happen, the same one is only written once.
+/// They go to a `.partial` file next to the cassette until `finish()` moves it into place, so
+/// the cassette of a run which failed halfway isn't replayed.
+#[derive(Debug)]
+pub struct Recorder {
+    file: Mutex<(File, HashSet<String>)>,
+    path: PathBuf,
+    partial_path: PathBuf,
+}
+
+impl Recorder {
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut partial_path = path.clone().into_os_string();
+        partial_path.push(".partial");
+        let partial_path = PathBuf::from(partial_path);
+        Ok(Self {
+            file: Mutex::new((File::create(&partial_path)?, HashSet::new())),
+            path,
+            partial_path,
+        })
+    }
+
+    /// Moves the cassette recorded so far into place, the later interactions still go to it.
+    pub fn finish(&self) -> anyhow::Result<()> {
+        let mut lock = self.file.lock().unwrap();
+        lock.0.flush()?;
+        if self.partial_path.exists() {
+            std::fs::rename(&self.partial_path, &self.path)?;
+        }
+        Ok(())
+    }
+
+    fn record(&self, interaction: &Interaction) -> anyhow::Result<()> {
+        let line = serde_json::to_string(interaction)?;
+        let mut lock = self.file.lock().unwrap();
+        let (file, written) = &mut *lock;
+        if written.insert(line.clone()) {
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Answers the requests with the responses of a cassette, nothing is sent out. The responses to
+/// the same request are given in the order they were recorded, the last one is then repeated.
+#[derive(Debug)]
+pub struct Player {
+    responses: Mutex<HashMap<(String, String), (Vec<Value>, usize)>>,
+}
+
+impl Player {
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let mut responses = HashMap::<_, (Vec<Value>, usize)>::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let interaction: Interaction = serde_json::from_str(line.as_str())?;
+            responses
+                .entry((interaction.method, interaction.params.to_string()))
+                .or_default()
+                .0
+                .push(interaction.result);
+        }
+        Ok(Self {
+            responses: Mutex::new(responses),
+        })
+    }
+
+    fn play(&self, method: &str, params: &Value) -> Result<Value, ProviderError> {
+        let mut responses = self.responses.lock().unwrap();
+        let (results, played) = responses
+            .get_mut(&(method.to_string(), params.to_string()))
+            .ok_or_else(|| {
+                ProviderError::CustomError(format!(
+                    "{} {} isn't recorded in the cassette",
+                    method, params
+                ))
+            })?;
+        let result = results[(*played).min(results.len() - 1)].clone();
+        *played += 1;
+        Ok(result)
+    }
+}
+
+/// The connection to the archive node, which can be recorded into a cassette or replayed from
+/// one instead.
+#[derive(Clone, Debug)]
+pub enum RemoteClient {
+    Ws(Ws),
+    Record(Ws, Arc<Recorder>),
+    Replay(Arc<Player>),
+}
+
+#[async_trait]
+impl JsonRpcClient for RemoteClient {
+    type Error = ProviderError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let result = match self {
+            RemoteClient::Ws(ws) => return ws.request(method, params).await.map_err(Into::into),
+            RemoteClient::Record(ws, recorder) => {
+                let params = serde_json::to_value(params)?;
+                let result: Value = ws
+                    .request(method, params.clone())
+                    .await
+                    .map_err(Into::<ProviderError>::into)?;
+                let interaction = Interaction {
+                    method: method.to_string(),
+                    params,
+                    result,
+                };
+                recorder
+                    .record(&interaction)
+                    .map_err(|e| ProviderError::CustomError(e.to_string()))?;
+                interaction.result
+            }
+            RemoteClient::Replay(player) => player.play(method, &serde_json::to_value(params)?)?,
+        };
+        Ok(serde_json::from_value(result)?)
+    }
+}
+
+impl PubsubClient for RemoteClient {
+    type NotificationStream = <Ws as PubsubClient>::NotificationStream;
+
+    fn subscribe<T: Into<U256>>(&self, id: T) -> Result<Self::NotificationStream, Self::Error> {
+        match self {
+            RemoteClient::Ws(ws) | RemoteClient::Record(ws, _) => {
+                ws.subscribe(id).map_err(Into::into)
+            }
+            RemoteClient::Replay(_) => Err(ProviderError::CustomError(
+                "the notifications of a subscription aren't recorded".to_string(),
+            )),
+        }
+    }
+
+    fn unsubscribe<T: Into<U256>>(&self, id: T) -> Result<(), Self::Error> {
+        match self {
+            RemoteClient::Ws(ws) | RemoteClient::Record(ws, _) => {
+                ws.unsubscribe(id).map_err(Into::into)
+            }
+            RemoteClient::Replay(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ForkedEvmProvider;
+    use ethers::providers::Middleware;
+    use ethers::types::{Address, Block, BlockNumber, Bytes, H256};
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_replay() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("cassette.ndjson");
+
+        let balance = Interaction {
+            method: "eth_getBalance".to_string(),
+            params: json!([Address::zero(), BlockNumber::Number(13458688.into())]),
+            result: json!("0x64"),
+        };
+        let recorder = Recorder::new(&file_path).unwrap();
+        recorder.record(&balance).unwrap();
+        // recorded once only
+        recorder.record(&balance).unwrap();
+        // and only there once it's finished
+        assert!(!file_path.exists());
+        recorder.finish().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&file_path).unwrap().lines().count(),
+            1
+        );
+
+        let client = RemoteClient::Replay(Arc::new(Player::new(&file_path).unwrap()));
+        let value: U256 = client
+            .request(
+                "eth_getBalance",
+                (Address::zero(), BlockNumber::Number(13458688.into())),
+            )
+            .await
+            .unwrap();
+        assert_eq!(value, 100.into());
+
+        // another block isn't recorded
+        let unrecorded = client
+            .request::<_, U256>(
+                "eth_getBalance",
+                (Address::zero(), BlockNumber::Number(13458689.into())),
+            )
+            .await;
+        assert!(unrecorded.is_err());
+
+        dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_provider_replays_recording() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("fork.ndjson");
+        let token = Address::from_low_u64_be(0x10);
+        let slot = H256::from_low_u64_be(3);
+        let at_fork = BlockNumber::Number(100.into());
+
+        // what the fork at block 100 asks the archive node to read the token
+        let header = Block::<H256> {
+            number: Some(101.into()),
+            hash: Some(H256::from_low_u64_be(101)),
+            gas_limit: 30_000_000.into(),
+            base_fee_per_gas: Some(7.into()),
+            ..Default::default()
+        };
+        let recorder = Recorder::new(&file_path).unwrap();
+        for (method, params, result) in vec![
+            ("eth_chainId", Value::Null, json!("0x5")),
+            (
+                "eth_getBlockByNumber",
+                json!([BlockNumber::Number(101.into()), false]),
+                json!(header),
+            ),
+            ("eth_getBalance", json!([token, at_fork]), json!("0x64")),
+            (
+                "eth_getTransactionCount",
+                json!([token, at_fork]),
+                json!("0x1"),
+            ),
+            ("eth_getCode", json!([token, at_fork]), json!("0x6000")),
+            (
+                "eth_getStorageAt",
+                json!([token, slot, at_fork]),
+                json!(H256::from_low_u64_be(9)),
+            ),
+        ] {
+            let interaction = Interaction {
+                method: method.to_string(),
+                params,
+                result,
+            };
+            recorder.record(&interaction).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        // the cassette exists, so the url isn't connected to
+        let provider = ForkedEvmProvider::new_with_cassette(100, "ws://127.0.0.1:1", file_path)
+            .await
+            .unwrap();
+        assert_eq!(provider.get_chainid().await.unwrap(), 5.into());
+        assert_eq!(provider.get_balance(token, None).await.unwrap(), 100.into());
+        assert_eq!(
+            provider.get_transaction_count(token, None).await.unwrap(),
+            1.into()
+        );
+        assert_eq!(
+            provider.get_code(token, None).await.unwrap(),
+            Bytes::from(vec![0x60, 0x00])
+        );
+        assert_eq!(
+            provider.get_storage_at(token, slot, None).await.unwrap(),
+            H256::from_low_u64_be(9)
+        );
+        provider.finish_recording().await.unwrap();
+
+        // an account the recording never read
+        let unrecorded = provider
+            .get_balance(Address::from_low_u64_be(0x11), None)
+            .await
+            .unwrap_err();
+        assert!(unrecorded
+            .to_string()
+            .contains("isn't recorded in the cassette"));
+
+        dir.close().unwrap();
+    }
+}
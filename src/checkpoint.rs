@@ -0,0 +1,403 @@
+use crate::akula::delta::Delta;
+use crate::akula::interface::State;
+use crate::akula::intra_block_state::{IntraBlockState, Snapshot};
+use crate::error::ForkError;
+use crate::tracers::state_diff::{AccountState, StateDiff};
+use ethers::types::{Address, Transaction, TransactionReceipt, H256, U256};
+use rusqlite::{params, Connection};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::str::FromStr;
+
+/// An account as a local block left it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OverlayAccount {
+    /// The storage it had before the block was wiped, because it was destructed or created
+    /// again, so the slots which aren't in `state` are zero.
+    pub wiped: bool,
+    /// `None` means it was destructed.
+    pub state: Option<AccountState>,
+}
+
+/// The accounts a local block changed, as it left them.
+pub type Overlay = BTreeMap<Address, OverlayAccount>;
+
+/// The local changes on top of the fork block. They're saved into a database of their own,
+/// the one recording the fork block is left untouched.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Checkpoint {
+    pub fork_block_number: u64,
+    /// One per local block, followed by the changes made since the latest one was mined.
+    pub blocks: Vec<Overlay>,
+    /// The mined transactions with their receipts, in block order.
+    pub transactions: Vec<(Transaction, TransactionReceipt)>,
+    /// The accounts funded by `set_balance()`.
+    pub accounts: Vec<Address>,
+}
+
+impl Checkpoint {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let mut db = Connection::open(path)?;
+        let tx = db.transaction()?;
+
+        tx.execute_batch(r"
+            DROP TABLE IF EXISTS checkpoint;
+            DROP TABLE IF EXISTS account;
+            DROP TABLE IF EXISTS storage;
+
+            CREATE TABLE checkpoint(fork_block_number INTEGER, blocks INTEGER, transactions TEXT NOT NULL, accounts TEXT NOT NULL);
+            CREATE TABLE account(block INTEGER, address TEXT NOT NULL, balance TEXT, nonce TEXT, code TEXT, wiped INTEGER);
+            CREATE TABLE storage(block INTEGER, address TEXT NOT NULL, slot TEXT NOT NULL, value TEXT NOT NULL);
+        ")?;
+
+        tx.execute(
+            "INSERT INTO checkpoint(fork_block_number, blocks, transactions, accounts) VALUES(?1, ?2, ?3, ?4)",
+            params![
+                self.fork_block_number,
+                self.blocks.len() as u64,
+                serde_json::to_string(&self.transactions)?,
+                serde_json::to_string(&self.accounts)?,
+            ],
+        )?;
+
+        for (block, overlay) in self.blocks.iter().enumerate() {
+            for (address, account) in overlay {
+                let address_text = hex::encode(address.as_bytes());
+                let state = match &account.state {
+                    Some(state) => state,
+                    None => {
+                        // a destructed account has no balance
+                        tx.execute(
+                            "INSERT INTO account(block, address) VALUES(?1, ?2)",
+                            params![block as u64, address_text],
+                        )?;
+                        continue;
+                    }
+                };
+
+                tx.execute(
+                    "INSERT INTO account(block, address, balance, nonce, code, wiped) VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        block as u64,
+                        address_text.as_str(),
+                        format!("{}", state.balance),
+                        format!("{}", state.nonce),
+                        hex::encode(&state.code),
+                        account.wiped,
+                    ],
+                )?;
+                for (key, value) in state.storage.iter() {
+                    tx.execute(
+                        "INSERT INTO storage(block, address, slot, value) VALUES(?1, ?2, ?3, ?4)",
+                        params![
+                            block as u64,
+                            address_text.as_str(),
+                            hex::encode(key.as_bytes()),
+                            hex::encode(value.as_bytes()),
+                        ],
+                    )?;
+                }
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let db = Connection::open(path)?;
+
+        let (fork_block_number, blocks, transactions_text, accounts_text): (
+            u64,
+            u64,
+            String,
+            String,
+        ) = db.query_row(
+            "SELECT fork_block_number, blocks, transactions, accounts FROM checkpoint",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+        let mut checkpoint = Self {
+            fork_block_number,
+            blocks: vec![Overlay::new(); blocks as usize],
+            transactions: serde_json::from_str(transactions_text.as_str())?,
+            accounts: serde_json::from_str(accounts_text.as_str())?,
+        };
+
+        let mut statement =
+            db.prepare("SELECT block, address, balance, nonce, code, wiped FROM account")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let block: u64 = row.get(0)?;
+            let address_text: String = row.get(1)?;
+            let balance_text: Option<String> = row.get(2)?;
+
+            let account = match balance_text {
+                Some(balance_text) => {
+                    let nonce_text: String = row.get(3)?;
+                    let code_text: String = row.get(4)?;
+                    let wiped: bool = row.get(5)?;
+                    OverlayAccount {
+                        wiped,
+                        state: Some(AccountState {
+                            balance: U256::from_dec_str(balance_text.as_str())?,
+                            nonce: nonce_text.parse()?,
+                            code: hex::decode(code_text)?.into(),
+                            storage: Default::default(),
+                        }),
+                    }
+                }
+                None => OverlayAccount {
+                    wiped: true,
+                    state: None,
+                },
+            };
+            checkpoint
+                .overlay(block)?
+                .insert(Address::from_str(address_text.as_str())?, account);
+        }
+
+        let mut statement = db.prepare("SELECT block, address, slot, value FROM storage")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let block: u64 = row.get(0)?;
+            let address_text: String = row.get(1)?;
+            let key_text: String = row.get(2)?;
+            let value_text: String = row.get(3)?;
+
+            let address = Address::from_str(address_text.as_str())?;
+            let account = checkpoint
+                .overlay(block)?
+                .get_mut(&address)
+                .and_then(|account| account.state.as_mut())
+                .ok_or_else(|| {
+                    ForkError::InvalidCheckpoint(format!(
+                        "storage of a missing account {:?}",
+                        address
+                    ))
+                })?;
+            account.storage.insert(
+                H256::from_str(key_text.as_str())?,
+                H256::from_str(value_text.as_str())?,
+            );
+        }
+
+        Ok(checkpoint)
+    }
+
+    fn overlay(&mut self, block: u64) -> anyhow::Result<&mut Overlay> {
+        self.blocks.get_mut(block as usize).ok_or_else(|| {
+            ForkError::InvalidCheckpoint(format!("block {} is out of the checkpoint", block)).into()
+        })
+    }
+}
+
+/// The accounts changed since the snapshot, as they are now.
+pub async fn overlay_since<S: State>(
+    state: &mut IntraBlockState<S>,
+    snapshot: &Snapshot,
+) -> anyhow::Result<Overlay> {
+    let wiped = wiped_since(state, snapshot);
+    let diff = StateDiff::since(state, snapshot).await?;
+    Ok(diff
+        .0
+        .into_iter()
+        .map(|(address, diff)| {
+            let account = OverlayAccount {
+                wiped: diff.post.is_none() || wiped.contains(&address),
+                state: diff.post,
+            };
+            (address, account)
+        })
+        .collect())
+}
+
+/// The changes of the overlays made one after the other, as a single one.
+pub fn merge_overlays<'a>(overlays: impl IntoIterator<Item = &'a Overlay>) -> Overlay {
+    let mut merged = Overlay::new();
+    for overlay in overlays {
+        for (address, later) in overlay {
+            let earlier = match merged.get_mut(address) {
+                Some(earlier) if !later.wiped => earlier,
+                _ => {
+                    merged.insert(*address, later.clone());
+                    continue;
+                }
+            };
+
+            // the slots the later one didn't change are the earlier ones
+            let mut storage = earlier
+                .state
+                .take()
+                .map(|state| state.storage)
+                .unwrap_or_default();
+            earlier.state = later.state.clone().map(|mut state| {
+                storage.append(&mut state.storage);
+                state.storage = storage;
+                state
+            });
+        }
+    }
+    merged
+}
+
+/// The accounts whose storage was wiped after the snapshot, by a destruct or by creating them
+/// again.
+fn wiped_since<S: State>(state: &IntraBlockState<S>, snapshot: &Snapshot) -> BTreeSet<Address> {
+    state
+        .journal_since(snapshot)
+        .iter()
+        .filter_map(|delta| match delta {
+            Delta::StorageWipe { address, .. } | Delta::StorageCreate { address } => Some(*address),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Makes the changes of the overlay on top of the state, they're journaled like any other.
+pub async fn apply_overlay<S: State>(
+    state: &mut IntraBlockState<S>,
+    overlay: &Overlay,
+) -> anyhow::Result<()> {
+    for (address, overlay_account) in overlay {
+        let account = match &overlay_account.state {
+            Some(account) => account,
+            None => {
+                state.delete_account(*address).await?;
+                continue;
+            }
+        };
+
+        if overlay_account.wiped {
+            // a new incarnation, the storage of the account below isn't read anymore
+            state.create_contract(*address).await?;
+            state.set_code(*address, account.code.0.clone()).await?;
+        } else if state.get_code(*address).await?.unwrap_or_default() != account.code.0 {
+            state.set_code(*address, account.code.0.clone()).await?;
+        }
+        state.set_balance(*address, account.balance).await?;
+        state.set_nonce(*address, account.nonce).await?;
+        for (key, value) in account.storage.iter() {
+            state.set_storage(*address, *key, *value).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_state::{Genesis, MemoryState};
+    use crate::state_dump::GenesisAccount;
+    use address_literal::addr;
+    use tempfile::tempdir;
+    use u256_literal::u256;
+
+    #[test]
+    fn test_checkpoint_save_and_load() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("checkpoint.db");
+
+        let deployed = AccountState {
+            balance: u256!(1234),
+            nonce: 1,
+            code: vec![0x60, 0x00].into(),
+            storage: vec![(H256::from_low_u64_be(1), H256::from_low_u64_be(2))]
+                .into_iter()
+                .collect(),
+        };
+        let funded = AccountState {
+            balance: u256!(5678),
+            ..Default::default()
+        };
+        let checkpoint = Checkpoint {
+            fork_block_number: 13458688,
+            blocks: vec![
+                vec![(
+                    addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+                    OverlayAccount {
+                        wiped: true,
+                        state: Some(deployed),
+                    },
+                )]
+                .into_iter()
+                .collect(),
+                Overlay::new(),
+                vec![
+                    (
+                        addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+                        OverlayAccount {
+                            wiped: true,
+                            state: None,
+                        },
+                    ),
+                    (
+                        addr!("0x2260fac5e5542a773aa44fbcfedf7c193bc2c599"),
+                        OverlayAccount {
+                            wiped: false,
+                            state: Some(funded),
+                        },
+                    ),
+                ]
+                .into_iter()
+                .collect(),
+            ],
+            transactions: vec![],
+            accounts: vec![addr!("0x2260fac5e5542a773aa44fbcfedf7c193bc2c599")],
+        };
+
+        checkpoint.save(&file_path).unwrap();
+        assert_eq!(Checkpoint::load(&file_path).unwrap(), checkpoint);
+
+        // saving again replaces it
+        let empty = Checkpoint::default();
+        empty.save(&file_path).unwrap();
+        assert_eq!(Checkpoint::load(&file_path).unwrap(), empty);
+
+        dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_apply_wiped_account() {
+        let weth = addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2");
+        let mut genesis = Genesis {
+            dev_accounts: vec![],
+            ..Default::default()
+        };
+        genesis.alloc.insert(
+            weth,
+            GenesisAccount {
+                balance: u256!(100),
+                storage: vec![(H256::from_low_u64_be(1), H256::from_low_u64_be(2))]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+        );
+        let mut state = IntraBlockState::new(MemoryState::new(&genesis));
+
+        // destructed and created again, the slot it had before is gone
+        let overlay = vec![(
+            weth,
+            OverlayAccount {
+                wiped: true,
+                state: Some(AccountState {
+                    balance: u256!(5),
+                    ..Default::default()
+                }),
+            },
+        )]
+        .into_iter()
+        .collect();
+        apply_overlay(&mut state, &overlay).await.unwrap();
+
+        assert_eq!(state.get_balance(weth).await.unwrap(), u256!(5));
+        assert_eq!(
+            state
+                .get_current_storage(weth, H256::from_low_u64_be(1))
+                .await
+                .unwrap(),
+            H256::zero()
+        );
+    }
+}
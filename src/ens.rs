@@ -0,0 +1,125 @@
+use crate::akula::evm::execute;
+use crate::akula::interface::State;
+use crate::akula::intra_block_state::IntraBlockState;
+use crate::akula::types::PartialHeader;
+use crate::error::ForkError;
+use ethers::abi::{ParamType, Token};
+use ethers::providers::ens::{namehash, ADDR_SELECTOR, ENS_ADDRESS, NAME_SELECTOR};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{Address, NameOrAddress, TransactionRequest};
+use evmodin::{Revision, StatusCode};
+
+/// resolver(bytes32)
+const RESOLVER_SELECTOR: [u8; 4] = [0x01, 0x78, 0xb8, 0xbf];
+
+/// Resolves the name with the registry and resolver contracts of the forked state.
+pub async fn resolve_name<S: State>(
+    state: &mut IntraBlockState<S>,
+    header: &PartialHeader,
+    revision: Revision,
+    ens_name: &str,
+) -> Result<Address, ForkError> {
+    let resolver = resolver(state, header, revision, ens_name).await?;
+    let data = view(state, header, revision, resolver, ADDR_SELECTOR, ens_name).await?;
+
+    match decode(&data, ParamType::Address).as_slice() {
+        [Token::Address(address)] if !address.is_zero() => Ok(*address),
+        _ => Err(ForkError::Ens(ens_name.to_string())),
+    }
+}
+
+/// The reverse record of the address, it only counts if the name resolves back to it.
+pub async fn lookup_address<S: State>(
+    state: &mut IntraBlockState<S>,
+    header: &PartialHeader,
+    revision: Revision,
+    address: Address,
+) -> Result<String, ForkError> {
+    // the same as `ethers::providers::ens::reverse_address()`
+    let reverse_name = format!("{}.addr.reverse", hex::encode(address.as_bytes()));
+
+    let resolver = resolver(state, header, revision, &reverse_name).await?;
+    let data = view(
+        state,
+        header,
+        revision,
+        resolver,
+        NAME_SELECTOR,
+        &reverse_name,
+    )
+    .await?;
+
+    let name = match decode(&data, ParamType::String).as_slice() {
+        [Token::String(name)] if !name.is_empty() => name.clone(),
+        _ => return Err(ForkError::Ens(reverse_name)),
+    };
+
+    if resolve_name(state, header, revision, &name).await? != address {
+        return Err(ForkError::Ens(name));
+    }
+    Ok(name)
+}
+
+/// Replaces the ENS name of the recipient with its address.
+pub async fn resolve_recipient<S: State>(
+    state: &mut IntraBlockState<S>,
+    header: &PartialHeader,
+    revision: Revision,
+    tx: &TypedTransaction,
+) -> Result<TypedTransaction, ForkError> {
+    let mut tx = tx.clone();
+    if let Some(NameOrAddress::Name(name)) = tx.to().cloned() {
+        let address = resolve_name(state, header, revision, &name).await?;
+        tx.set_to(address);
+    }
+    Ok(tx)
+}
+
+async fn resolver<S: State>(
+    state: &mut IntraBlockState<S>,
+    header: &PartialHeader,
+    revision: Revision,
+    ens_name: &str,
+) -> Result<Address, ForkError> {
+    let data = view(
+        state,
+        header,
+        revision,
+        ENS_ADDRESS,
+        RESOLVER_SELECTOR,
+        ens_name,
+    )
+    .await?;
+
+    match decode(&data, ParamType::Address).as_slice() {
+        [Token::Address(resolver)] if !resolver.is_zero() => Ok(*resolver),
+        _ => Err(ForkError::Ens(ens_name.to_string())),
+    }
+}
+
+/// Calls `selector(namehash(ens_name))` on the contract, nothing is changed.
+async fn view<S: State>(
+    state: &mut IntraBlockState<S>,
+    header: &PartialHeader,
+    revision: Revision,
+    contract: Address,
+    selector: [u8; 4],
+    ens_name: &str,
+) -> Result<Vec<u8>, ForkError> {
+    let data = [&selector[..], namehash(ens_name).as_bytes()].concat();
+    let tx = TransactionRequest::new().to(contract).data(data).into();
+
+    let snapshot = state.take_snapshot();
+    let ret = execute(state, header, revision, &tx, i64::MAX).await;
+    state.revert_to_snapshot(snapshot);
+
+    let ret = ret?;
+    if ret.status_code != StatusCode::Success {
+        return Err(ForkError::Ens(ens_name.to_string()));
+    }
+    Ok(ret.output_data.to_vec())
+}
+
+fn decode(data: &[u8], param: ParamType) -> Vec<Token> {
+    ethers::abi::decode(&[param], data).unwrap_or_default()
+}
@@ -0,0 +1,97 @@
+use crate::revert::RevertError;
+use ethers::providers::ProviderError;
+use evmodin::StatusCode;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum ForkError {
+    /// The local database doesn't have the data, it has to be recorded from an archive node.
+    BackendMiss(String),
+    /// The archive node failed to answer.
+    Rpc(ProviderError),
+    /// The transaction is malformed or can't be executed, e.g. it doesn't have enough gas.
+    InvalidTransaction(String),
+    /// The ENS name has no resolver or address, or the address has no reverse record.
+    Ens(String),
+    /// The transaction reverted or failed in the EVM.
+    Reverted(RevertError),
+    /// The transaction runs out of gas even with all the gas allowed, which is the block gas
+    /// limit, the gas of the transaction or what the sender can afford, whichever is lower.
+    GasCapTooLow(u64),
+    /// The transaction always fails, for something other than running out of gas or reverting.
+    Failed(StatusCode),
+    /// Anything else that went wrong inside the EVM or the state.
+    Evm(anyhow::Error),
+    /// The `ForkManager` has no fork of that name, or none is selected yet.
+    UnknownFork(String),
+    /// The checkpoint is malformed, e.g. it has no blocks.
+    InvalidCheckpoint(String),
+    /// A fork's chain id isn't the one its archive node reported before, e.g. its database was
+    /// recorded from another chain behind the same URL.
+    ChainIdMismatch(u64, u64),
+}
+
+impl Display for ForkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForkError::BackendMiss(what) => write!(f, "not found in the local database: {}", what),
+            ForkError::Rpc(e) => write!(f, "archive node error: {}", e),
+            ForkError::InvalidTransaction(reason) => write!(f, "invalid transaction: {}", reason),
+            ForkError::Ens(name) => write!(f, "ens name not found: {}", name),
+            ForkError::Reverted(e) => write!(f, "{}", e),
+            ForkError::GasCapTooLow(cap) => write!(f, "gas required exceeds allowance ({})", cap),
+            ForkError::Failed(status_code) => {
+                write!(f, "always failing transaction: {:?}", status_code)
+            }
+            ForkError::Evm(e) => write!(f, "evm error: {:?}", e),
+            ForkError::UnknownFork(name) => write!(f, "unknown fork: {}", name),
+            ForkError::InvalidCheckpoint(reason) => write!(f, "invalid checkpoint: {}", reason),
+            ForkError::ChainIdMismatch(found, expected) => {
+                write!(
+                    f,
+                    "chain id {} doesn't match the expected {}",
+                    found, expected
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ForkError {}
+
+impl From<anyhow::Error> for ForkError {
+    /// The `State` implementations return `anyhow::Error`, this recovers the original error.
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<ForkError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+
+        match e.downcast::<ProviderError>() {
+            Ok(e) => ForkError::Rpc(e),
+            Err(e) => ForkError::Evm(e),
+        }
+    }
+}
+
+impl From<rlp::DecoderError> for ForkError {
+    fn from(e: rlp::DecoderError) -> Self {
+        ForkError::InvalidTransaction(format!("rlp: {}", e))
+    }
+}
+
+impl From<RevertError> for ForkError {
+    fn from(e: RevertError) -> Self {
+        ForkError::Reverted(e)
+    }
+}
+
+impl From<ForkError> for ProviderError {
+    fn from(e: ForkError) -> Self {
+        match e {
+            ForkError::Rpc(e) => e,
+            ForkError::Ens(name) => ProviderError::EnsError(name),
+            e => ProviderError::JsonRpcClientError(Box::new(e)),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::akula::evm::{execute, CallResult};
+use crate::akula::interface::State;
+use crate::akula::intra_block_state::IntraBlockState;
+use crate::akula::types::PartialHeader;
+use crate::akula::utils::{get_effective_gas_price, get_sender, intrinsic_gas};
+use crate::error::ForkError;
+use crate::revert::{RevertDecoder, RevertError};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::U256;
+use evmodin::{Revision, StatusCode};
+
+/// Binary searches the lowest gas limit that the transaction succeeds with.
+///
+/// Because of the EIP-150 rule, which only passes 63/64 of the gas to sub calls, and the
+/// refunds, which are only given back at the end, it's not simply the gas used.
+pub async fn estimate_gas<S: State>(
+    state: &mut IntraBlockState<S>,
+    header: &PartialHeader,
+    revision: Revision,
+    tx: &TypedTransaction,
+    decoder: &RevertDecoder,
+) -> Result<u64, ForkError> {
+    // it can't use more than the block allows anyway
+    let mut cap = tx
+        .gas()
+        .filter(|gas| !gas.is_zero())
+        .map(|gas| (*gas).min(header.gas_limit.into()).as_u64())
+        .unwrap_or(header.gas_limit);
+
+    // the sender has to be able to pay for it
+    let gas_price = get_effective_gas_price(tx, header.base_fee_per_gas.unwrap_or_default());
+    if !gas_price.is_zero() {
+        let balance = state.get_balance(get_sender(tx)).await?;
+        let value = tx.value().cloned().unwrap_or_default();
+        let allowance = balance.saturating_sub(value) / gas_price;
+        if allowance < U256::from(cap) {
+            cap = allowance.as_u64();
+        }
+    }
+
+    let ret = run_isolated(state, header, revision, tx, cap).await?;
+    match ret.status_code {
+        StatusCode::Success => {}
+        StatusCode::OutOfGas => return Err(ForkError::GasCapTooLow(cap)),
+        StatusCode::Revert => {
+            let gas_used = cap - ret.gas_left as u64;
+            return Err(RevertError::new(&ret, gas_used, decoder).into());
+        }
+        status_code => return Err(ForkError::Failed(status_code)),
+    }
+
+    // everything below the gas used before the refund fails for sure
+    let intrinsic_gas = intrinsic_gas(
+        tx,
+        revision >= Revision::Homestead,
+        revision >= Revision::Istanbul,
+    );
+    let used = cap - ret.gas_left as u64 + ret.gas_refund as u64;
+    let mut lo = used.max(intrinsic_gas) - 1;
+    let mut hi = cap;
+
+    // the gas reserved by the 63/64 rule is usually enough, try it first
+    let optimistic = used * 64 / 63;
+    if optimistic < hi {
+        if succeeds(state, header, revision, tx, optimistic).await? {
+            hi = optimistic;
+        } else {
+            lo = optimistic;
+        }
+    }
+
+    while lo + 1 < hi {
+        let mid = lo + (hi - lo) / 2;
+        if succeeds(state, header, revision, tx, mid).await? {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+
+    Ok(hi)
+}
+
+async fn succeeds<S: State>(
+    state: &mut IntraBlockState<S>,
+    header: &PartialHeader,
+    revision: Revision,
+    tx: &TypedTransaction,
+    gas: u64,
+) -> Result<bool, ForkError> {
+    let ret = run_isolated(state, header, revision, tx, gas).await?;
+    Ok(ret.status_code == StatusCode::Success)
+}
+
+async fn run_isolated<S: State>(
+    state: &mut IntraBlockState<S>,
+    header: &PartialHeader,
+    revision: Revision,
+    tx: &TypedTransaction,
+    gas: u64,
+) -> Result<CallResult, ForkError> {
+    let snapshot = state.take_snapshot();
+    let ret = execute(state, header, revision, tx, gas as i64).await;
+    state.revert_to_snapshot(snapshot);
+
+    Ok(ret?)
+}
@@ -0,0 +1,580 @@
+use crate::akula::utils::keccak256;
+use crate::error::ForkError;
+use crate::forked_evm_provider::ForkedEvmProvider;
+use crate::state_muxer::{BackendConfig, StateMuxer};
+use async_trait::async_trait;
+use ethers::core::types::transaction::eip2718::TypedTransaction;
+use ethers::core::types::transaction::eip2930::AccessListWithGasUsed;
+use ethers::core::types::{
+    Block, BlockId, BlockNumber, FeeHistory, Log, NameOrAddress, Transaction, TransactionReceipt,
+    TxHash, TxpoolContent, TxpoolInspect, TxpoolStatus,
+};
+use ethers::providers::{
+    JsonRpcClient, Middleware, PendingTransaction, Provider, ProviderError, PubsubClient,
+    SubscriptionStream,
+};
+use ethers::types::{Address, Bytes, Filter, H256, U256, U64};
+use futures::channel::mpsc::UnboundedReceiver;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// What's carried over to the selected fork for the persistent accounts.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct AccountState {
+    pub balance: U256,
+    pub nonce: u64,
+    pub code: bytes::Bytes,
+    // only the slots the previous fork has read or written
+    pub storage: Vec<(H256, H256)>,
+}
+
+// the selected fork, not a tokio lock as `PubsubClient::subscribe()` isn't async
+type Active = Arc<RwLock<Option<(String, Arc<ForkedEvmProvider>)>>>;
+
+/// Several named forks, of different chains or blocks, behind a single `Middleware` which
+/// talks to the selected one. The forks of the same archive node share its connection and its
+/// code cache, and the forks of the same block share their database too. A database records
+/// the accounts at its fork block, so it isn't shared by the other blocks of the chain.
+///
+/// ```ignore
+/// let manager = ForkManager::new(Some("forks".into()));
+/// manager.create_fork("mainnet", "wss://mainnet-archive-node", 13458688).await?;
+/// manager.create_fork("before", "wss://mainnet-archive-node", 13458000).await?;
+/// manager.make_persistent(bot_address).await;
+/// manager.select_fork("before").await?;
+/// ```
+#[derive(Debug)]
+pub struct ForkManager {
+    // the databases are named after the archive node and the fork block
+    cache_dir: Option<PathBuf>,
+    // by archive node and fork block, with the chain id of the node
+    backends: Mutex<HashMap<(String, u64), (StateMuxer, u64)>>,
+    forks: Mutex<BTreeMap<String, Arc<ForkedEvmProvider>>>,
+    // the accounts copied over from the previous fork by select_fork()
+    persistent: Mutex<BTreeSet<Address>>,
+    active: Active,
+    dummy_provider: Provider<ActiveFork>,
+}
+
+impl ForkManager {
+    /// The fork states are recorded into `cache_dir` if it's given, like
+    /// `ForkedEvmProvider::new()` does, otherwise they're all read from the archive nodes.
+    pub fn new(cache_dir: Option<PathBuf>) -> Self {
+        let active = Active::default();
+        Self {
+            cache_dir,
+            backends: Mutex::new(HashMap::new()),
+            forks: Mutex::new(BTreeMap::new()),
+            persistent: Mutex::new(BTreeSet::new()),
+            active: active.clone(),
+            dummy_provider: Provider::new(ActiveFork { active })
+                .interval(Duration::from_millis(10)),
+        }
+    }
+
+    /// Forks the chain of the archive node at `block_number`, a fork of the same name is
+    /// replaced. It's selected if no other fork is.
+    pub async fn create_fork(
+        &self,
+        name: &str,
+        archive_wss_url: &str,
+        block_number: u64,
+    ) -> Result<Arc<ForkedEvmProvider>, ForkError> {
+        let (state_mux, chain_id) = self.backend(archive_wss_url, block_number).await?;
+        // the header carries the chain id the database was recorded with, which is another one
+        // if the URL pointed to another chain back then
+        let fork = ForkedEvmProvider::from_state_muxer(block_number, state_mux).await?;
+        let recorded = fork.fork_header().chain_id;
+        if recorded != chain_id {
+            return Err(ForkError::ChainIdMismatch(recorded, chain_id));
+        }
+        Ok(self.insert_fork(name, fork).await)
+    }
+
+    /// Adds a fork of the caller's, e.g. `ForkedEvmProvider::new_in_memory()`, a fork of the
+    /// same name is replaced. It's selected if no other fork is.
+    pub async fn insert_fork(&self, name: &str, fork: ForkedEvmProvider) -> Arc<ForkedEvmProvider> {
+        let fork = Arc::new(fork);
+
+        self.forks
+            .lock()
+            .await
+            .insert(name.to_string(), fork.clone());
+
+        let mut active = self.active.write().unwrap();
+        match active.as_mut() {
+            Some((active_name, active_fork)) if active_name == name => *active_fork = fork.clone(),
+            Some(_) => {}
+            None => *active = Some((name.to_string(), fork.clone())),
+        }
+        fork
+    }
+
+    /// Makes the fork the one the `Middleware` methods go to. The persistent accounts are
+    /// copied over from the previous fork, the other local changes of both forks are kept.
+    pub async fn select_fork(&self, name: &str) -> Result<(), ForkError> {
+        let fork = self
+            .fork(name)
+            .await
+            .ok_or_else(|| ForkError::UnknownFork(name.to_string()))?;
+
+        if let Ok(previous) = self.active_fork() {
+            if !Arc::ptr_eq(&previous, &fork) {
+                for address in self.persistent.lock().await.iter() {
+                    let account = previous.account_state(*address).await?;
+                    fork.set_account_state(*address, &account).await?;
+                }
+            }
+        }
+
+        *self.active.write().unwrap() = Some((name.to_string(), fork));
+        Ok(())
+    }
+
+    /// The account is carried over to the forks selected from now on.
+    pub async fn make_persistent(&self, address: Address) {
+        self.persistent.lock().await.insert(address);
+    }
+
+    pub async fn revoke_persistent(&self, address: Address) {
+        self.persistent.lock().await.remove(&address);
+    }
+
+    pub async fn is_persistent(&self, address: Address) -> bool {
+        self.persistent.lock().await.contains(&address)
+    }
+
+    /// The name of the selected fork.
+    pub fn active_fork_name(&self) -> Option<String> {
+        let active = self.active.read().unwrap();
+        active.as_ref().map(|(name, _)| name.clone())
+    }
+
+    pub fn active_fork(&self) -> Result<Arc<ForkedEvmProvider>, ForkError> {
+        active_fork(&self.active)
+    }
+
+    pub async fn fork(&self, name: &str) -> Option<Arc<ForkedEvmProvider>> {
+        self.forks.lock().await.get(name).cloned()
+    }
+
+    pub async fn fork_names(&self) -> Vec<String> {
+        self.forks.lock().await.keys().cloned().collect()
+    }
+
+    /// The forks of the same block share the same backend, the others of the same archive node
+    /// fork it again so the connection is shared. It's returned with the chain id of the node.
+    ///
+    /// The backends are keyed by archive node rather than by chain id: the chain id is only
+    /// known once connected, and the databases are named after the node so that they can be
+    /// opened again without it. Two nodes of the same chain don't share anything then, and a
+    /// node has to stay on the chain it reported first.
+    async fn backend(&self, url: &str, block_number: u64) -> Result<(StateMuxer, u64), ForkError> {
+        let mut backends = self.backends.lock().await;
+        let key = (url.to_string(), block_number);
+        if let Some(backend) = backends.get(&key) {
+            return Ok(backend.clone());
+        }
+
+        let db_path = self.db_path(url, block_number);
+        let connected = backends
+            .iter()
+            .find(|((other_url, _), (backend, _))| other_url == url && backend.is_connected());
+        let backend = match (connected, db_path) {
+            (Some((_, (backend, _))), db_path) => backend.fork_at(block_number, db_path)?,
+            (None, Some(db_path)) if db_path.exists() => {
+                StateMuxer::new(block_number, BackendConfig::LocalOnly { db_path }).await?
+            }
+            (None, Some(db_path)) => {
+                let config = BackendConfig::TeeWeb3ToLocal {
+                    wss_url: url.to_string(),
+                    db_path,
+                };
+                StateMuxer::new(block_number, config).await?
+            }
+            (None, None) => {
+                let config = BackendConfig::AllViaWeb3 {
+                    wss_url: url.to_string(),
+                };
+                StateMuxer::new(block_number, config).await?
+            }
+        };
+
+        // like `StateMuxer::new()`, a new database records the chain id
+        let chain_id = backend.read_chain_id().await?;
+        let other = backends
+            .iter()
+            .find(|((other_url, _), (_, other))| other_url == url && *other != chain_id);
+        if let Some((_, (_, other))) = other {
+            return Err(ForkError::ChainIdMismatch(chain_id, *other));
+        }
+
+        backends.insert(key, (backend.clone(), chain_id));
+        Ok((backend, chain_id))
+    }
+
+    /// One database per archive node and fork block, not per chain: the accounts and storage
+    /// slots it records are those of the fork block.
+    fn db_path(&self, url: &str, block_number: u64) -> Option<PathBuf> {
+        let cache_dir = self.cache_dir.as_ref()?;
+        let url_hash = hex::encode(&keccak256(url).as_bytes()[..8]);
+        Some(cache_dir.join(format!("{}-{}.db", url_hash, block_number)))
+    }
+}
+
+fn active_fork(active: &Active) -> Result<Arc<ForkedEvmProvider>, ForkError> {
+    let active = active.read().unwrap();
+    active
+        .as_ref()
+        .map(|(_, fork)| fork.clone())
+        .ok_or_else(|| ForkError::UnknownFork("no fork is selected".to_string()))
+}
+
+/// The loopback of the selected fork, for `PendingTransaction` and `SubscriptionStream`. A
+/// subscription stays with the fork it was made on.
+#[derive(Debug)]
+pub struct ActiveFork {
+    active: Active,
+}
+
+#[async_trait]
+impl JsonRpcClient for ActiveFork {
+    type Error = ProviderError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let fork = active_fork(&self.active)?;
+        fork.loopback().request(method, params).await
+    }
+}
+
+impl PubsubClient for ActiveFork {
+    type NotificationStream = UnboundedReceiver<Box<RawValue>>;
+
+    fn subscribe<T: Into<U256>>(&self, id: T) -> Result<Self::NotificationStream, Self::Error> {
+        active_fork(&self.active)?.loopback().subscribe(id)
+    }
+
+    fn unsubscribe<T: Into<U256>>(&self, id: T) -> Result<(), Self::Error> {
+        active_fork(&self.active)?.loopback().unsubscribe(id)
+    }
+}
+
+/// Every method `ForkedEvmProvider` answers goes to the selected fork.
+#[async_trait]
+impl Middleware for ForkManager {
+    type Error = ProviderError;
+    type Provider = ActiveFork;
+    type Inner = Self;
+
+    /// Like `ForkedEvmProvider`, the unsupported methods mustn't recurse forever.
+    fn inner(&self) -> &Self::Inner {
+        unreachable!("There is no inner provider here")
+    }
+
+    async fn client_version(&self) -> Result<String, Self::Error> {
+        self.active_fork()?.client_version().await
+    }
+
+    async fn fill_transaction(
+        &self,
+        tx: &mut TypedTransaction,
+        block: Option<BlockId>,
+    ) -> Result<(), Self::Error> {
+        self.active_fork()?.fill_transaction(tx, block).await
+    }
+
+    async fn get_block_number(&self) -> Result<U64, Self::Error> {
+        self.active_fork()?.get_block_number().await
+    }
+
+    async fn get_block<T: Into<BlockId> + Send + Sync>(
+        &self,
+        block_hash_or_number: T,
+    ) -> Result<Option<Block<H256>>, Self::Error> {
+        self.active_fork()?.get_block(block_hash_or_number).await
+    }
+
+    async fn get_block_with_txs<T: Into<BlockId> + Send + Sync>(
+        &self,
+        block_hash_or_number: T,
+    ) -> Result<Option<Block<Transaction>>, Self::Error> {
+        self.active_fork()?
+            .get_block_with_txs(block_hash_or_number)
+            .await
+    }
+
+    async fn get_uncle_count<T: Into<BlockId> + Send + Sync>(
+        &self,
+        block_hash_or_number: T,
+    ) -> Result<U256, Self::Error> {
+        self.active_fork()?
+            .get_uncle_count(block_hash_or_number)
+            .await
+    }
+
+    async fn get_uncle<T: Into<BlockId> + Send + Sync>(
+        &self,
+        block_hash_or_number: T,
+        idx: U64,
+    ) -> Result<Option<Block<H256>>, Self::Error> {
+        self.active_fork()?
+            .get_uncle(block_hash_or_number, idx)
+            .await
+    }
+
+    async fn get_chainid(&self) -> Result<U256, Self::Error> {
+        self.active_fork()?.get_chainid().await
+    }
+
+    async fn get_net_version(&self) -> Result<String, Self::Error> {
+        self.active_fork()?.get_net_version().await
+    }
+
+    async fn get_balance<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> Result<U256, Self::Error> {
+        self.active_fork()?.get_balance(from, block).await
+    }
+
+    async fn get_transaction_count<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> Result<U256, Self::Error> {
+        self.active_fork()?.get_transaction_count(from, block).await
+    }
+
+    async fn get_gas_price(&self) -> Result<U256, Self::Error> {
+        self.active_fork()?.get_gas_price().await
+    }
+
+    async fn estimate_eip1559_fees(
+        &self,
+        estimator: Option<fn(U256, Vec<Vec<U256>>) -> (U256, U256)>,
+    ) -> Result<(U256, U256), Self::Error> {
+        self.active_fork()?.estimate_eip1559_fees(estimator).await
+    }
+
+    async fn get_accounts(&self) -> Result<Vec<Address>, Self::Error> {
+        self.active_fork()?.get_accounts().await
+    }
+
+    async fn send_transaction<T: Into<TypedTransaction> + Send + Sync>(
+        &self,
+        tx: T,
+        block: Option<BlockId>,
+    ) -> Result<PendingTransaction<'_, Self::Provider>, Self::Error> {
+        let hash = *self.active_fork()?.send_transaction(tx, block).await?;
+        Ok(PendingTransaction::new(hash, &self.dummy_provider))
+    }
+
+    async fn send_raw_transaction<'a>(
+        &'a self,
+        tx: Bytes,
+    ) -> Result<PendingTransaction<'a, Self::Provider>, Self::Error> {
+        let hash = *self.active_fork()?.send_raw_transaction(tx).await?;
+        Ok(PendingTransaction::new(hash, &self.dummy_provider))
+    }
+
+    async fn get_transaction<T: Send + Sync + Into<TxHash>>(
+        &self,
+        transaction_hash: T,
+    ) -> Result<Option<Transaction>, Self::Error> {
+        self.active_fork()?.get_transaction(transaction_hash).await
+    }
+
+    async fn get_transaction_receipt<T: Send + Sync + Into<TxHash>>(
+        &self,
+        transaction_hash: T,
+    ) -> Result<Option<TransactionReceipt>, Self::Error> {
+        self.active_fork()?
+            .get_transaction_receipt(transaction_hash)
+            .await
+    }
+
+    async fn txpool_content(&self) -> Result<TxpoolContent, Self::Error> {
+        self.active_fork()?.txpool_content().await
+    }
+
+    async fn txpool_inspect(&self) -> Result<TxpoolInspect, Self::Error> {
+        self.active_fork()?.txpool_inspect().await
+    }
+
+    async fn txpool_status(&self) -> Result<TxpoolStatus, Self::Error> {
+        self.active_fork()?.txpool_status().await
+    }
+
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> Result<Bytes, Self::Error> {
+        self.active_fork()?.call(tx, block).await
+    }
+
+    async fn get_code<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        at: T,
+        block: Option<BlockId>,
+    ) -> Result<Bytes, Self::Error> {
+        self.active_fork()?.get_code(at, block).await
+    }
+
+    async fn get_storage_at<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        address: T,
+        location: H256,
+        block: Option<BlockId>,
+    ) -> Result<H256, Self::Error> {
+        self.active_fork()?
+            .get_storage_at(address, location, block)
+            .await
+    }
+
+    async fn create_access_list(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> Result<AccessListWithGasUsed, Self::Error> {
+        self.active_fork()?.create_access_list(tx, block).await
+    }
+
+    async fn resolve_name(&self, ens_name: &str) -> Result<Address, Self::Error> {
+        self.active_fork()?.resolve_name(ens_name).await
+    }
+
+    async fn lookup_address(&self, address: Address) -> Result<String, Self::Error> {
+        self.active_fork()?.lookup_address(address).await
+    }
+
+    async fn fee_history<T: Into<U256> + Serialize + Send + Sync>(
+        &self,
+        block_count: T,
+        last_block: BlockNumber,
+        reward_percentiles: &[f64],
+    ) -> Result<FeeHistory, Self::Error> {
+        self.active_fork()?
+            .fee_history(block_count, last_block, reward_percentiles)
+            .await
+    }
+
+    async fn subscribe<T, R>(
+        &self,
+        params: T,
+    ) -> Result<SubscriptionStream<'_, Self::Provider, R>, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send + Sync,
+    {
+        self.dummy_provider.subscribe(params).await
+    }
+
+    async fn unsubscribe<T>(&self, id: T) -> Result<bool, Self::Error>
+    where
+        T: Into<U256> + Send + Sync,
+    {
+        self.dummy_provider.unsubscribe(id).await
+    }
+
+    async fn subscribe_blocks(
+        &self,
+    ) -> Result<SubscriptionStream<'_, Self::Provider, Block<TxHash>>, Self::Error> {
+        self.dummy_provider.subscribe_blocks().await
+    }
+
+    async fn subscribe_pending_txs(
+        &self,
+    ) -> Result<SubscriptionStream<'_, Self::Provider, TxHash>, Self::Error> {
+        self.dummy_provider.subscribe_pending_txs().await
+    }
+
+    async fn subscribe_logs<'a>(
+        &'a self,
+        filter: &Filter,
+    ) -> Result<SubscriptionStream<'a, Self::Provider, Log>, Self::Error> {
+        let filter = serde_json::to_value(filter)?;
+        self.dummy_provider
+            .subscribe([serde_json::json!("logs"), filter])
+            .await
+    }
+
+    async fn estimate_gas(&self, tx: &TypedTransaction) -> Result<U256, Self::Error> {
+        self.active_fork()?.estimate_gas(tx).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite_backend::SqliteDumper;
+
+    #[tokio::test]
+    async fn test_no_fork_selected() {
+        let manager = ForkManager::new(None);
+        assert!(manager.active_fork_name().is_none());
+        assert!(matches!(
+            manager.select_fork("mainnet").await,
+            Err(ForkError::UnknownFork(_))
+        ));
+        assert!(manager.get_block_number().await.is_err());
+
+        manager.make_persistent(Address::zero()).await;
+        assert!(manager.is_persistent(Address::zero()).await);
+        manager.revoke_persistent(Address::zero()).await;
+        assert!(!manager.is_persistent(Address::zero()).await);
+    }
+
+    #[test]
+    fn test_db_path() {
+        let manager = ForkManager::new(Some(PathBuf::from("forks")));
+        let mainnet = manager.db_path("wss://mainnet", 1).unwrap();
+        assert_eq!(mainnet.parent(), Some(PathBuf::from("forks").as_path()));
+        assert!(mainnet.to_str().unwrap().ends_with("-1.db"));
+        assert_ne!(manager.db_path("wss://goerli", 1), Some(mainnet));
+        assert!(ForkManager::new(None).db_path("wss://mainnet", 1).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chain_id_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = ForkManager::new(Some(dir.path().to_path_buf()));
+        let url = "wss://archive";
+
+        // the node was on goerli when the second block was recorded
+        for (block_number, chain_id) in [(1, 1), (2, 5)] {
+            let mut dumper =
+                SqliteDumper::new(manager.db_path(url, block_number).unwrap()).unwrap();
+            dumper.dump_chain_id(chain_id).unwrap();
+            dumper
+                .dump_block_header(
+                    block_number + 1,
+                    H256::zero(),
+                    U256::zero(),
+                    0,
+                    30_000_000,
+                    U256::zero(),
+                    Address::zero(),
+                )
+                .unwrap();
+        }
+
+        manager.create_fork("mainnet", url, 1).await.unwrap();
+        assert!(matches!(
+            manager.create_fork("goerli", url, 2).await,
+            Err(ForkError::ChainIdMismatch(5, 1))
+        ));
+    }
+}
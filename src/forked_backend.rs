@@ -1,40 +1,223 @@
+use crate::akula::fee_params::param;
 use crate::akula::types::{Account, Incarnation, PartialHeader};
 use crate::akula::utils::keccak256;
+use crate::cassette::{Player, Recorder, RemoteClient};
+use crate::error::ForkError;
 use bytes::Bytes;
 use ethers::prelude::*;
 use futures::future;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+/// The time between two blocks since the merge.
+const SECONDS_PER_SLOT: u64 = 12;
+
+#[derive(Clone)]
 pub struct Web3RemoteState {
-    provider: Provider<Ws>,
+    provider: Provider<RemoteClient>,
     block_number: u64,
+    // read once when connecting, the headers carry it
+    chain_id: u64,
     code_hash_map: Arc<Mutex<HashMap<H256, Bytes>>>,
+    cache: Arc<Mutex<RemoteCache>>,
+}
+
+/// The accounts and storage slots already read at the block of the state.
+#[derive(Clone, Debug, Default)]
+struct RemoteCache {
+    accounts: HashMap<Address, Option<Account>>,
+    storage: HashMap<(Address, H256), H256>,
+}
+
+impl RemoteCache {
+    /// Brings the cached entries up to date with the changes of a transaction, in the shape of
+    /// parity's `stateDiff`. The code it deploys goes to `code_hash_map`.
+    fn apply(&mut self, diff: &StateDiff, code_hash_map: &mut HashMap<H256, Bytes>) {
+        for (address, account_diff) in diff.0.iter() {
+            if matches!(account_diff.balance, Diff::Died(_)) {
+                self.accounts.insert(*address, None);
+                self.storage.retain(|(a, _), _| a != address);
+                continue;
+            }
+
+            if let Some(cached) = self.accounts.get_mut(address) {
+                let mut account = cached.clone().unwrap_or_else(|| Account {
+                    code_hash: keccak256(&[]),
+                    ..Default::default()
+                });
+                if let Some(balance) = changed(&account_diff.balance) {
+                    account.balance = balance;
+                }
+                if let Some(nonce) = changed(&account_diff.nonce) {
+                    account.nonce = nonce.as_u64();
+                }
+                if let Some(code) = changed(&account_diff.code) {
+                    account.code_hash = keccak256(code.as_ref());
+                    code_hash_map.insert(account.code_hash, code.0);
+                }
+                *cached = Some(account);
+            }
+
+            for (slot, slot_diff) in account_diff.storage.iter() {
+                if let Some(cached) = self.storage.get_mut(&(*address, *slot)) {
+                    *cached = match slot_diff {
+                        Diff::Died(_) => H256::zero(),
+                        slot_diff => changed(slot_diff).unwrap_or(*cached),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// The value after the change, if it's been set.
+fn changed<T: Clone>(diff: &Diff<T>) -> Option<T> {
+    match diff {
+        Diff::Born(value) => Some(value.clone()),
+        Diff::Changed(ChangedType { to, .. }) => Some(to.clone()),
+        Diff::Same | Diff::Died(_) => None,
+    }
+}
+
+/// The base fee of the block after `parent`, see EIP-1559.
+fn next_base_fee(base_fee: U256, gas_used: U256, gas_limit: U256) -> U256 {
+    let target = gas_limit / param::ELASTICITY_MULTIPLIER;
+    if target.is_zero() || gas_used == target {
+        return base_fee;
+    }
+
+    let denominator = U256::from(param::BASE_FEE_MAX_CHANGE_DENOMINATOR);
+    if gas_used > target {
+        let delta = base_fee * (gas_used - target) / target / denominator;
+        base_fee + delta.max(U256::one())
+    } else {
+        base_fee.saturating_sub(base_fee * (target - gas_used) / target / denominator)
+    }
 }
 
 impl Web3RemoteState {
     pub async fn new(block_number: u64, ws_url: &str) -> anyhow::Result<Self> {
         let ws = Ws::connect(ws_url).await?;
-        let provider = Provider::new(ws);
+        Self::with_client(block_number, RemoteClient::Ws(ws)).await
+    }
 
+    /// Like `new()`, every request and its response are recorded into the cassette too.
+    pub async fn record<P: AsRef<Path>>(
+        block_number: u64,
+        ws_url: &str,
+        cassette_path: P,
+    ) -> anyhow::Result<Self> {
+        let ws = Ws::connect(ws_url).await?;
+        let recorder = Arc::new(Recorder::new(cassette_path)?);
+        Self::with_client(block_number, RemoteClient::Record(ws, recorder)).await
+    }
+
+    /// The archive node as recorded into the cassette, a request it doesn't have fails.
+    pub async fn replay<P: AsRef<Path>>(
+        block_number: u64,
+        cassette_path: P,
+    ) -> anyhow::Result<Self> {
+        let player = Arc::new(Player::new(cassette_path)?);
+        Self::with_client(block_number, RemoteClient::Replay(player)).await
+    }
+
+    async fn with_client(block_number: u64, client: RemoteClient) -> anyhow::Result<Self> {
+        let provider = Provider::new(client);
+        let chain_id = provider.get_chainid().await?.as_u64();
         Ok(Self {
             provider,
             block_number,
+            chain_id,
             code_hash_map: Arc::new(Mutex::new(Default::default())),
+            cache: Arc::new(Mutex::new(Default::default())),
         })
     }
+
+    /// Moves the cassette into place if the requests are being recorded, see `Recorder`.
+    pub fn finish_recording(&self) -> anyhow::Result<()> {
+        match self.provider.as_ref() {
+            RemoteClient::Record(_, recorder) => recorder.finish(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads the state at another block, reusing the same connection. The code cache is shared,
+    /// the code of a hash is the same at every block.
+    pub fn at_block(&self, block_number: u64) -> Self {
+        Self {
+            provider: self.provider.clone(),
+            block_number,
+            chain_id: self.chain_id,
+            code_hash_map: self.code_hash_map.clone(),
+            cache: Arc::new(Mutex::new(Default::default())),
+        }
+    }
+
+    /// The state at a later block of the chain, like `at_block()`, but the cached accounts and
+    /// storage slots are carried over and brought up to date with the state diffs of
+    /// `trace_replayBlockTransactions`. They're dropped if the archive node can't trace.
+    pub async fn advance(&self, block_number: u64) -> Self {
+        let mut cache = self.cache.lock().await.clone();
+        for n in self.block_number + 1..=block_number {
+            let traces = self
+                .provider
+                .trace_replay_block_transactions(n.into(), vec![TraceType::StateDiff])
+                .await;
+            let diffs = traces.map(|traces| {
+                traces
+                    .into_iter()
+                    .map(|trace| trace.state_diff)
+                    .collect::<Option<Vec<_>>>()
+            });
+
+            match diffs {
+                Ok(Some(diffs)) => {
+                    let mut code_hash_map = self.code_hash_map.lock().await;
+                    for diff in diffs.iter() {
+                        cache.apply(diff, &mut code_hash_map);
+                    }
+                }
+                _ => {
+                    cache = RemoteCache::default();
+                    break;
+                }
+            }
+        }
+
+        Self {
+            provider: self.provider.clone(),
+            block_number,
+            chain_id: self.chain_id,
+            code_hash_map: self.code_hash_map.clone(),
+            cache: Arc::new(Mutex::new(cache)),
+        }
+    }
+
+    /// The new blocks of the chain, as they're mined.
+    pub async fn subscribe_new_heads(
+        &self,
+    ) -> anyhow::Result<SubscriptionStream<'_, RemoteClient, Block<H256>>> {
+        Ok(self.provider.subscribe_blocks().await?)
+    }
 }
 
 impl Debug for Web3RemoteState {
-    fn fmt(&self, _f: &mut Formatter<'_>) -> std::fmt::Result {
-        todo!()
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Web3RemoteState")
+            .field("block_number", &self.block_number)
+            .finish_non_exhaustive()
     }
 }
 
 impl Web3RemoteState {
     pub async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        if let Some(account) = self.cache.lock().await.accounts.get(&address) {
+            return Ok(account.clone());
+        }
+
         let (balance, nonce, code) = future::try_join3(
             self.provider
                 .get_balance(address, Some(self.block_number.into())),
@@ -45,8 +228,8 @@ impl Web3RemoteState {
         )
         .await?;
 
-        if balance.is_zero() && nonce.is_zero() && code.0.is_empty() {
-            Ok(None)
+        let account = if balance.is_zero() && nonce.is_zero() && code.0.is_empty() {
+            None
         } else {
             // cache the code has, it would be used later on by read_code()
             let code_hash = keccak256(code.0.as_ref());
@@ -55,18 +238,25 @@ impl Web3RemoteState {
                 lock.insert(code_hash, code.0.clone());
             }
 
-            Ok(Some(Account {
+            Some(Account {
                 nonce: nonce.as_u64(),
                 balance,
                 code_hash,
                 incarnation: Default::default(),
-            }))
-        }
+            })
+        };
+
+        let mut cache = self.cache.lock().await;
+        cache.accounts.insert(address, account.clone());
+        Ok(account)
     }
 
     pub async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
         let lock = self.code_hash_map.lock().await;
-        Ok(lock.get(&code_hash).cloned().unwrap())
+        // the code is only known after its account is read by read_account()
+        lock.get(&code_hash)
+            .cloned()
+            .ok_or_else(|| ForkError::BackendMiss(format!("code of hash {:?}", code_hash)).into())
     }
 
     pub async fn read_storage(
@@ -75,10 +265,111 @@ impl Web3RemoteState {
         _incarnation: Incarnation,
         location: H256,
     ) -> anyhow::Result<H256> {
-        Ok(self
+        if let Some(value) = self.cache.lock().await.storage.get(&(address, location)) {
+            return Ok(*value);
+        }
+
+        let value = self
             .provider
             .get_storage_at(address, location, Some(self.block_number.into()))
-            .await?)
+            .await?;
+
+        let mut cache = self.cache.lock().await;
+        cache.storage.insert((address, location), value);
+        Ok(value)
+    }
+
+    /// The storage slots each transaction of the block changed, by the hash of the transaction,
+    /// from the state diffs of `trace_replayBlockTransactions`.
+    pub async fn read_block_storage_changes(
+        &self,
+        block_number: u64,
+    ) -> anyhow::Result<Vec<(H256, Address, H256)>> {
+        let traces = self
+            .provider
+            .trace_replay_block_transactions(block_number.into(), vec![TraceType::StateDiff])
+            .await?;
+
+        let mut changes = vec![];
+        for trace in traces {
+            let hash = trace.transaction_hash.unwrap_or_default();
+            let diff = trace
+                .state_diff
+                .ok_or_else(|| anyhow::anyhow!("no state diff for transaction {:?}", hash))?;
+            for (address, account_diff) in diff.0 {
+                for (slot, slot_diff) in account_diff.storage {
+                    if !matches!(slot_diff, Diff::Same) {
+                        changes.push((hash, address, slot));
+                    }
+                }
+            }
+        }
+        Ok(changes)
+    }
+
+    /// The transactions of the block together with their receipts.
+    pub async fn read_block_transactions(
+        &self,
+        block_number: u64,
+    ) -> anyhow::Result<Option<Vec<(Transaction, TransactionReceipt)>>> {
+        let block = match self.provider.get_block_with_txs(block_number).await? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let receipts = future::try_join_all(
+            block
+                .transactions
+                .iter()
+                .map(|tx| self.provider.get_transaction_receipt(tx.hash)),
+        )
+        .await?;
+
+        block
+            .transactions
+            .into_iter()
+            .zip(receipts)
+            .map(|(tx, receipt)| {
+                let receipt =
+                    receipt.ok_or_else(|| anyhow::anyhow!("no receipt of {:?}", tx.hash))?;
+                Ok((tx, receipt))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    pub async fn read_transaction_block_number(&self, hash: H256) -> anyhow::Result<Option<u64>> {
+        let tx = self.provider.get_transaction(hash).await?;
+        Ok(tx.and_then(|tx| tx.block_number).map(|n| n.as_u64()))
+    }
+
+    /// The header of the block after `block_number` before it's mined, built from its parent
+    /// like a miner would. Its hash isn't known yet, the parent's one stands for it.
+    pub async fn read_pending_header(
+        &self,
+        block_number: u64,
+    ) -> anyhow::Result<Option<PartialHeader>> {
+        let parent = self.provider.get_block(block_number).await?;
+        Ok(parent.map(|b| PartialHeader {
+            difficulty: b.difficulty,
+            number: block_number + 1,
+            gas_limit: b.gas_limit.as_u64(),
+            timestamp: b.timestamp.as_u64() + SECONDS_PER_SLOT,
+            base_fee_per_gas: b
+                .base_fee_per_gas
+                .map(|base_fee| next_base_fee(base_fee, b.gas_used, b.gas_limit)),
+            beneficiary: b.author,
+            hash: b.hash.unwrap_or_default(),
+            chain_id: self.chain_id,
+        }))
+    }
+
+    pub async fn read_chain_id(&self) -> anyhow::Result<u64> {
+        Ok(self.chain_id)
+    }
+
+    pub async fn read_block_logs(&self, block_number: u64) -> anyhow::Result<Vec<Log>> {
+        let filter = Filter::new().select(block_number);
+        Ok(self.provider.get_logs(&filter).await?)
     }
 
     pub async fn read_block_header(
@@ -88,12 +379,78 @@ impl Web3RemoteState {
         let block = self.provider.get_block(block_number).await?;
         Ok(block.map(|b| PartialHeader {
             difficulty: b.difficulty,
-            number: self.block_number,
+            number: block_number,
             gas_limit: b.gas_limit.as_u64(),
             timestamp: b.timestamp.as_u64(),
             base_fee_per_gas: b.base_fee_per_gas,
             beneficiary: b.author,
             hash: b.hash.unwrap_or_default(),
+            chain_id: self.chain_id,
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_base_fee() {
+        let base_fee = U256::from(1_000_000_000u64);
+        let gas_limit = U256::from(30_000_000u64);
+
+        assert_eq!(
+            next_base_fee(base_fee, 15_000_000u64.into(), gas_limit),
+            base_fee
+        );
+        // full blocks raise it by 12.5%, empty ones lower it by as much
+        assert_eq!(
+            next_base_fee(base_fee, gas_limit, gas_limit),
+            U256::from(1_125_000_000u64)
+        );
+        assert_eq!(
+            next_base_fee(base_fee, U256::zero(), gas_limit),
+            U256::from(875_000_000u64)
+        );
+    }
+
+    #[test]
+    fn test_remote_cache_apply() {
+        let address = Address::from_low_u64_be(1);
+        let slot = H256::from_low_u64_be(2);
+        let mut cache = RemoteCache::default();
+        cache.accounts.insert(
+            address,
+            Some(Account {
+                nonce: 1,
+                balance: 100.into(),
+                ..Default::default()
+            }),
+        );
+        cache
+            .storage
+            .insert((address, slot), H256::from_low_u64_be(3));
+
+        let changed_diff =
+            |from: String, to: String| serde_json::json!({ "*": { "from": from, "to": to } });
+        let diff: StateDiff = serde_json::from_value(serde_json::json!({
+            format!("{:?}", address): {
+                "balance": changed_diff("0x64".to_string(), "0x32".to_string()),
+                "nonce": changed_diff("0x1".to_string(), "0x2".to_string()),
+                "code": "=",
+                "storage": {
+                    format!("{:?}", slot): changed_diff(
+                        format!("{:?}", H256::from_low_u64_be(3)),
+                        format!("{:?}", H256::from_low_u64_be(4)),
+                    ),
+                },
+            },
+        }))
+        .unwrap();
+        cache.apply(&diff, &mut HashMap::new());
+
+        let account = cache.accounts[&address].clone().unwrap();
+        assert_eq!((account.nonce, account.balance), (2, 50.into()));
+        assert_eq!(cache.storage[&(address, slot)], H256::from_low_u64_be(4));
+    }
+}
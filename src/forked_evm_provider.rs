@@ -1,35 +1,102 @@
-use crate::akula::evm::execute;
+use crate::access_list::{create_access_list, AccessListWithGasComparison};
+use crate::akula::delta::Delta;
+use crate::akula::evm::{execute, execute_with_tracer, CallResult};
 use crate::akula::interface::State;
-use crate::akula::intra_block_state::IntraBlockState;
+use crate::akula::intra_block_state::{IntraBlockState, Snapshot};
 use crate::akula::types::PartialHeader;
+use crate::akula::utils::{
+    get_effective_gas_price, get_max_fee_per_gas, get_sender, intrinsic_gas, keccak256,
+};
+use crate::bundle::{bundle_hash, BundleSimulation, BundleTransactionResult};
+use crate::checkpoint::{
+    apply_overlay, merge_overlays, overlay_since, Checkpoint, Overlay, OverlayAccount,
+};
+use crate::ens::{lookup_address, resolve_name, resolve_recipient};
+use crate::error::ForkError;
+use crate::estimate_gas::estimate_gas;
+use crate::fork_manager::AccountState;
+use crate::history::LocalBlocks;
+use crate::memory_state::Genesis;
+use crate::mempool::{Mempool, PoolTransaction};
+use crate::replay::{
+    compare_receipts, typed_transaction, Mismatch, ReplayReport, StorageMismatch, TransactionReplay,
+};
+use crate::revert::{RevertDecoder, RevertError, RevertReason};
+use crate::signed_transaction::SignedTransaction;
+use crate::state_dump::{AnvilState, GenesisAlloc};
 use crate::state_muxer::{BackendConfig, StateMuxer};
-use anyhow::anyhow;
+use crate::subscriptions::{SubscriptionKind, Subscriptions};
+use crate::tracers::call_tracer::{CallFrame, CallTracer};
+use crate::tracers::state_diff::StateDiff;
+use crate::tracers::struct_logger::{StructLogTrace, StructLogger, StructLoggerConfig};
 use async_trait::async_trait;
 use ethers::abi::ethereum_types::H256;
+use ethers::abi::Abi;
 use ethers::core::types::transaction::eip2718::TypedTransaction;
-use ethers::core::types::{BlockId, NameOrAddress};
-use ethers::providers::{JsonRpcClient, Middleware, PendingTransaction, Provider, ProviderError};
-use ethers::types::{Address, Bytes, U64};
+use ethers::core::types::transaction::eip2930::AccessListWithGasUsed;
+use ethers::core::types::{
+    Block, BlockId, BlockNumber, FeeHistory, Log, NameOrAddress, Transaction, TransactionReceipt,
+    TxHash, TxpoolContent, TxpoolInspect, TxpoolInspectSummary, TxpoolStatus,
+};
+use ethers::providers::{
+    JsonRpcClient, Middleware, PendingTransaction, Provider, ProviderError, PubsubClient,
+    SubscriptionStream,
+};
+use ethers::types::{Address, Bytes, Filter, U64};
 use evmodin::{Revision, StatusCode};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::StreamExt;
 use primitive_types::U256;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::convert::TryFrom;
+use serde_json::value::RawValue;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
 use std::ops::DerefMut;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::{Mutex as StdMutex, RwLock};
+use std::time::Duration;
 use tokio::sync::Mutex;
 
+/// The tip on top of the base fee suggested by `get_gas_price()`, the same as geth's default.
+const DEFAULT_PRIORITY_FEE: u64 = 1_000_000_000;
+
 #[derive(Debug)]
 pub struct ForkedEvmProvider {
-    header: PartialHeader,
-    state_block_number: u64,
+    // the header of the block after the fork block, which the local blocks are built from, it
+    // only changes on reset() and sync code reads it, so it's not a tokio lock
+    header: RwLock<PartialHeader>,
+    // the backend the provider was created with, its database only records that fork block
+    origin: StateMuxer,
+    origin_block_number: u64,
     backend: Arc<Mutex<IntraBlockState<StateMuxer>>>,
+    // the changes of every local block, the state journals the ones since the latest
+    blocks: Mutex<LocalBlocks>,
+    // the call trees of all executions since call tracing was enabled
+    call_traces: Mutex<Option<Vec<CallFrame>>>,
+    revert_decoder: Mutex<RevertDecoder>,
+    // the accounts funded by set_balance(), returned by get_accounts()
+    accounts: Mutex<Vec<Address>>,
 
+    // the transactions waiting to be mined, and whether they're mined as soon as they're sent
+    mempool: Arc<Mutex<Mempool>>,
+    automine: Mutex<bool>,
+    mined: Arc<Mutex<MinedTransactions>>,
+    // not a tokio one, `PubsubClient::subscribe()` isn't async
+    subscriptions: Arc<StdMutex<Subscriptions>>,
     dummy_provider: Provider<LoopbackProvider>,
 }
 
+/// The mined transactions sent with `send_transaction()` and `send_raw_transaction()`, shared
+/// with `LoopbackProvider` so `PendingTransaction` can find their receipts.
+#[derive(Debug, Default)]
+pub struct MinedTransactions {
+    transactions: HashMap<H256, (Transaction, TransactionReceipt)>,
+    block_number: u64,
+}
+
 impl ForkedEvmProvider {
     /// A file path, if that path exists, we don't send request to remote RPC calls.
     /// An URL to access archive node. It would only be used when the above file doesn't exist or log query.
@@ -38,7 +105,7 @@ impl ForkedEvmProvider {
         state_block_number: u64,
         archive_wss_url: &str,
         db_path: PathBuf,
-    ) -> anyhow::Result<Self> {
+    ) -> Result<Self, ForkError> {
         let config = if db_path.as_path().exists() {
             // use db as the first choice, otherwise use the tee mode
             BackendConfig::LocalOnly { db_path }
@@ -50,25 +117,13 @@ impl ForkedEvmProvider {
         };
 
         let state_mux = StateMuxer::new(state_block_number, config).await?;
-        let header = state_mux
-            .read_block_header(state_block_number + 1)
-            .await?
-            .expect("failed to get header");
-
-        let intra_block_state = IntraBlockState::new(state_mux);
-
-        Ok(Self {
-            header,
-            state_block_number,
-            backend: Arc::new(Mutex::new(intra_block_state)),
-            dummy_provider: Provider::new(LoopbackProvider),
-        })
+        Self::from_state_muxer(state_block_number, state_mux).await
     }
 
     pub async fn new_with_remote(
         state_block_number: u64,
         archive_wss_url: &str,
-    ) -> anyhow::Result<Self> {
+    ) -> Result<Self, ForkError> {
         let state_mux = StateMuxer::new(
             state_block_number,
             BackendConfig::AllViaWeb3 {
@@ -76,194 +131,2160 @@ impl ForkedEvmProvider {
             },
         )
         .await?;
-        let mut header = state_mux
+        Self::from_state_muxer(state_block_number, state_mux).await
+    }
+
+    /// Like `new()`, but the archive node's requests and responses are recorded into a cassette
+    /// of JSON lines rather than into a database, so it can be reviewed and diffed. If the
+    /// cassette exists, it's replayed instead and nothing is sent out, a request it doesn't have
+    /// fails with `ForkError::Rpc`. The recording only becomes the cassette with
+    /// `finish_recording()`, a run which fails before it records again the next time.
+    pub async fn new_with_cassette(
+        state_block_number: u64,
+        archive_wss_url: &str,
+        cassette_path: PathBuf,
+    ) -> Result<Self, ForkError> {
+        let config = if cassette_path.as_path().exists() {
+            BackendConfig::ReplayWeb3 { cassette_path }
+        } else {
+            BackendConfig::RecordWeb3 {
+                wss_url: archive_wss_url.to_string(),
+                cassette_path,
+            }
+        };
+
+        let state_mux = StateMuxer::new(state_block_number, config).await?;
+        Self::from_state_muxer(state_block_number, state_mux).await
+    }
+
+    /// Moves the cassette of `new_with_cassette()` into place, once the run recorded what it
+    /// needs. It does nothing if the cassette is replayed.
+    pub async fn finish_recording(&self) -> Result<(), ForkError> {
+        let lock = self.backend.lock().await;
+        Ok(lock.db().finish_recording()?)
+    }
+
+    /// A new chain of its own instead of a fork, which starts from the genesis accounts and
+    /// only lives in memory. It's the fastest backend for tests which deploy their own
+    /// contracts, see `Genesis` for the defaults.
+    pub async fn new_in_memory(genesis: Genesis) -> Result<Self, ForkError> {
+        let dev_accounts = genesis.dev_accounts.clone();
+        let state_mux = StateMuxer::new(0, BackendConfig::InMemory { genesis }).await?;
+        let mut provider = Self::from_state_muxer(0, state_mux).await?;
+        *provider.accounts.get_mut() = dev_accounts;
+        Ok(provider)
+    }
+
+    /// A fork over a backend of the caller's, e.g. layers of `crate::layers` composed
+    /// together. It's read at `state_block_number`, the header of the block after it is the
+    /// one of the first local block. Only the archive node of `new()` can read the logs, the
+    /// earlier blocks or the new heads of the chain.
+    pub async fn from_state<S: State + 'static>(
+        state_block_number: u64,
+        state: S,
+    ) -> Result<Self, ForkError> {
+        let state_mux = StateMuxer::new(
+            state_block_number,
+            BackendConfig::Custom {
+                state: Arc::new(state),
+            },
+        )
+        .await?;
+        Self::from_state_muxer(state_block_number, state_mux).await
+    }
+
+    /// The provider over a backend which may be shared with other forks, see `ForkManager`.
+    pub(crate) async fn from_state_muxer(
+        state_block_number: u64,
+        state_mux: StateMuxer,
+    ) -> Result<Self, ForkError> {
+        let header = state_mux
             .read_block_header(state_block_number + 1)
             .await?
-            .expect("failed to get header");
-        header.number += 1;
+            .ok_or_else(|| ForkError::BackendMiss(format!("block {}", state_block_number + 1)))?;
 
+        let origin = state_mux.clone();
         let intra_block_state = IntraBlockState::new(state_mux);
+        let mined = Arc::new(Mutex::new(MinedTransactions {
+            block_number: state_block_number,
+            ..Default::default()
+        }));
+        let mempool = Arc::new(Mutex::new(Mempool::default()));
+        let subscriptions = Arc::new(StdMutex::new(Subscriptions::default()));
 
         Ok(Self {
-            header,
-            state_block_number,
+            header: RwLock::new(header),
+            origin,
+            origin_block_number: state_block_number,
             backend: Arc::new(Mutex::new(intra_block_state)),
-            dummy_provider: Provider::new(LoopbackProvider),
+            blocks: Mutex::new(LocalBlocks::default()),
+            call_traces: Mutex::new(None),
+            revert_decoder: Mutex::new(Default::default()),
+            accounts: Mutex::new(vec![]),
+            mempool: mempool.clone(),
+            automine: Mutex::new(true),
+            mined: mined.clone(),
+            subscriptions: subscriptions.clone(),
+            dummy_provider: Provider::new(LoopbackProvider {
+                mempool,
+                mined,
+                subscriptions,
+            })
+            .interval(Duration::from_millis(10)),
         })
     }
 
-    pub async fn set_balance(&self, account: Address, value: impl Into<U256>) {
+    /// The chain id returned by `get_chainid()` and the `CHAINID` opcode, it's 1 by default.
+    pub fn with_chain_id(mut self, chain_id: u64) -> Self {
+        self.header.get_mut().unwrap().chain_id = chain_id;
+        self
+    }
+
+    /// Forks again at `block_number`, or at the current fork block if it's `None`. The local
+    /// blocks, the mempool and every other local change are dropped, but the connection to the
+    /// archive node and its code cache are kept. The database only records the block the
+    /// provider was created with, the others are read from the archive node.
+    pub async fn reset(&self, block_number: Option<u64>) -> Result<(), ForkError> {
+        let mut backend = self.backend.lock().await;
+        let block_number = block_number.unwrap_or_else(|| self.fork_block_number());
+
+        let state_mux = if block_number == self.origin_block_number {
+            self.origin.clone()
+        } else {
+            self.origin.at_block(block_number)?
+        };
+        let mut header = state_mux
+            .read_block_header(block_number + 1)
+            .await?
+            .ok_or_else(|| ForkError::BackendMiss(format!("block {}", block_number + 1)))?;
+        header.chain_id = self.fork_header().chain_id;
+
+        *backend = IntraBlockState::new(state_mux);
+        let mut mempool = self.mempool.lock().await;
+        let mut blocks = self.blocks.lock().await;
+        let mut mined = self.mined.lock().await;
+        *mempool = Mempool::default();
+        *blocks = LocalBlocks::default();
+        *mined = MinedTransactions {
+            block_number,
+            ..Default::default()
+        };
+        *self.header.write().unwrap() = header;
+        self.accounts.lock().await.clear();
+        Ok(())
+    }
+
+    /// Follows the chain of the archive node: the fork is rebased onto every new head, which
+    /// the subscriptions are notified of along with its logs. With `replay_pending`, the local
+    /// transactions which haven't made it on chain are sent again on top of it, every other
+    /// local change is dropped like with `reset()`. It runs until the archive node's
+    /// subscription ends, so it's usually spawned.
+    pub async fn follow(&self, replay_pending: bool) -> Result<(), ForkError> {
+        let mut heads = self.origin.subscribe_new_heads().await?;
+        while let Some(head) = heads.next().await {
+            if let Some(block_number) = head.number {
+                self.rebase(block_number.as_u64(), replay_pending).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn rebase(&self, block_number: u64, replay_pending: bool) -> Result<(), ForkError> {
+        let mut backend = self.backend.lock().await;
+        // a head seen already, or a reorg the archive node will follow up on
+        if block_number <= self.fork_block_number() {
+            return Ok(());
+        }
+
+        let state_mux = backend.db().advance(block_number).await?;
+        let mut header = state_mux
+            .read_pending_header(block_number)
+            .await?
+            .ok_or_else(|| ForkError::BackendMiss(format!("block {}", block_number)))?;
+        header.chain_id = self.fork_header().chain_id;
+        let logs = state_mux.read_block_logs(block_number).await?;
+
+        let mut mempool = self.mempool.lock().await;
+        let mut blocks = self.blocks.lock().await;
+        let mut mined = self.mined.lock().await;
+
+        // the local transactions in the order they were sent, the ones on chain are pruned
+        // with the nonces of the new head
+        let mut local = std::mem::take(&mut mined.transactions)
+            .into_values()
+            .map(|(tx, _)| tx)
+            .collect::<Vec<_>>();
+        local.sort_by_key(|tx| (tx.block_number, tx.transaction_index));
+        let (pending, queued) = mempool.content(&HashMap::new());
+
+        *backend = IntraBlockState::new(state_mux);
+        *mempool = Mempool::default();
+        *blocks = LocalBlocks::default();
+        *mined = MinedTransactions {
+            block_number,
+            ..Default::default()
+        };
+        *self.header.write().unwrap() = header;
+        if replay_pending {
+            for pooled in local.iter().map(resend).chain(pending).chain(queued) {
+                mempool.insert(pooled.tx, pooled.hash, pooled.signed)?;
+            }
+        }
+        drop(mined);
+        drop(blocks);
+        drop(mempool);
+        drop(backend);
+
+        if let Some(block) = self
+            .block::<H256>(BlockId::Number(block_number.into()))
+            .await?
+        {
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .notify_block(&block, &logs);
+        }
+        if replay_pending && *self.automine.lock().await {
+            self.mine_pending(&[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Saves the local blocks, their transactions and the changes made since into a database
+    /// of their own, so a lengthy setup can be restored with `load_checkpoint()` instead of
+    /// being run again. The database of the fork block isn't touched, and the mempool isn't
+    /// saved.
+    pub async fn save_checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), ForkError> {
+        let mut backend = self.backend.lock().await;
+        let blocks = self.blocks.lock().await;
+        let mined = self.mined.lock().await;
+
+        // the changes of every local block, then the ones since the latest
+        let mut overlays = blocks.overlays().cloned().collect::<Vec<_>>();
+        overlays.push(overlay_since(backend.deref_mut(), &Snapshot::default()).await?);
+
+        let mut transactions = mined.transactions.values().cloned().collect::<Vec<_>>();
+        transactions.sort_by_key(|(tx, _)| (tx.block_number, tx.transaction_index));
+
+        let checkpoint = Checkpoint {
+            fork_block_number: self.fork_block_number(),
+            blocks: overlays,
+            transactions,
+            accounts: self.accounts.lock().await.clone(),
+        };
+        Ok(checkpoint.save(path)?)
+    }
+
+    /// Forks again at the block the checkpoint was saved on, like `reset()`, and rebuilds its
+    /// local blocks on top. Only the local changes are read from the checkpoint, the rest of
+    /// the state comes from the backend as usual.
+    pub async fn load_checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), ForkError> {
+        let checkpoint = Checkpoint::load(path)?;
+        let (latest, local_blocks) = checkpoint
+            .blocks
+            .split_last()
+            .ok_or_else(|| ForkError::InvalidCheckpoint("it has no blocks".to_string()))?;
+        self.reset(Some(checkpoint.fork_block_number)).await?;
+
+        let mut backend = self.backend.lock().await;
+        let mut blocks = self.blocks.lock().await;
+        let mut mined = self.mined.lock().await;
+        for overlay in local_blocks {
+            apply_overlay(backend.deref_mut(), overlay).await?;
+            // the timestamps aren't kept, the blocks are a second apart again
+            let timestamp = blocks.timestamp(blocks.len(), self.fork_header().timestamp);
+            blocks.push(overlay.clone(), timestamp);
+            backend.clear_journal_and_substate();
+        }
+        apply_overlay(backend.deref_mut(), latest).await?;
+
+        mined.block_number = checkpoint.fork_block_number + local_blocks.len() as u64;
+        for (tx, receipt) in checkpoint.transactions {
+            mined.transactions.insert(tx.hash, (tx, receipt));
+        }
+        *self.accounts.lock().await = checkpoint.accounts;
+        Ok(())
+    }
+
+    /// The accounts changed on top of the fork block, in geth's genesis `alloc` format. Only
+    /// the storage slots they changed are included, and the destructed accounts, which
+    /// `alloc` can't express, are left out.
+    pub async fn export_alloc(&self) -> Result<GenesisAlloc, ForkError> {
+        let mut backend = self.backend.lock().await;
+        let blocks = self.blocks.lock().await;
+        let latest = overlay_since(backend.deref_mut(), &Snapshot::default()).await?;
+        let overlay = merge_overlays(blocks.overlays().chain(std::iter::once(&latest)));
+        Ok(overlay
+            .into_iter()
+            .filter_map(|(address, account)| Some((address, account.state?.into())))
+            .collect())
+    }
+
+    /// Sets the balance, nonce, code and storage slots of the accounts, on top of the latest
+    /// block like `set_balance()`. The slots which aren't listed are left as they are.
+    pub async fn import_alloc(&self, alloc: &GenesisAlloc) -> Result<(), ForkError> {
+        let overlay: Overlay = alloc
+            .iter()
+            .map(|(address, account)| {
+                let account = OverlayAccount {
+                    wiped: false,
+                    state: Some(account.clone().into()),
+                };
+                (*address, account)
+            })
+            .collect();
+        let mut lock = self.backend.lock().await;
+        Ok(apply_overlay(lock.deref_mut(), &overlay).await?)
+    }
+
+    /// The accounts changed on top of the fork block like `export_alloc()`, as anvil's
+    /// `anvil_dumpState` returns them.
+    pub async fn dump_state(&self) -> Result<Bytes, ForkError> {
+        let state = AnvilState::from(self.export_alloc().await?);
+        Ok(serde_json::to_vec(&state)
+            .map_err(anyhow::Error::from)?
+            .into())
+    }
+
+    /// Imports the state of anvil's `anvil_dumpState`, like `import_alloc()`.
+    pub async fn load_state(&self, state: &[u8]) -> Result<(), ForkError> {
+        let state: AnvilState = serde_json::from_slice(state).map_err(anyhow::Error::from)?;
+        self.import_alloc(&state.into()).await
+    }
+
+    pub async fn set_balance(
+        &self,
+        account: Address,
+        value: impl Into<U256>,
+    ) -> Result<(), ForkError> {
         let value = value.into();
 
         let mut lock = self.backend.lock().await;
-        let _ = lock.set_balance(account, value).await.expect("failed to set the balance");
+        lock.set_balance(account, value).await?;
+
+        let mut accounts = self.accounts.lock().await;
+        if !accounts.contains(&account) {
+            accounts.push(account);
+        }
+        Ok(())
+    }
+
+    /// The balance, nonce, code and the storage slots known so far of the account.
+    pub(crate) async fn account_state(&self, address: Address) -> Result<AccountState, ForkError> {
+        let mut lock = self.backend.lock().await;
+        let mut storage = vec![];
+        for key in lock.known_storage_keys(address) {
+            storage.push((key, lock.get_current_storage(address, key).await?));
+        }
+
+        Ok(AccountState {
+            balance: lock.get_balance(address).await?,
+            nonce: lock.get_nonce(address).await?,
+            code: lock.get_code(address).await?.unwrap_or_default(),
+            storage,
+        })
     }
 
-    pub async fn deploy(&self, tx: &TypedTransaction) -> anyhow::Result<Address> {
+    /// Overwrites the account with `account_state()` of another fork, the slots it doesn't
+    /// know are left alone.
+    pub(crate) async fn set_account_state(
+        &self,
+        address: Address,
+        account: &AccountState,
+    ) -> Result<(), ForkError> {
         let mut lock = self.backend.lock().await;
-        let ret = execute(
-            lock.deref_mut(),
-            &self.header,
-            Revision::London,
-            tx,
-            tx.gas().cloned().unwrap_or_default().as_u64() as i64,
-        )
-        .await
-        .unwrap();
-        Ok(ret
-            .create_address
-            .ok_or_else(|| anyhow!("failed to create address"))?)
+        lock.set_balance(address, account.balance).await?;
+        lock.set_nonce(address, account.nonce).await?;
+        lock.set_code(address, account.code.clone()).await?;
+        for (key, value) in account.storage.iter() {
+            lock.set_storage(address, *key, *value).await?;
+        }
+        Ok(())
     }
 
-    pub async fn transact(&self, tx: &TypedTransaction) -> Result<(u64, Vec<u8>), ProviderError> {
+    /// The client `PendingTransaction` and `SubscriptionStream` poll.
+    pub(crate) fn loopback(&self) -> &LoopbackProvider {
+        self.dummy_provider.as_ref()
+    }
+
+    /// Like `transact()` for a contract creation, returns the created address.
+    pub async fn deploy(&self, tx: &TypedTransaction) -> Result<Address, ForkError> {
+        let gas = gas_limit(tx)?;
+
+        let header = self.pending_header().await;
         let mut lock = self.backend.lock().await;
-        let ret = execute(
-            lock.deref_mut(),
-            &self.header,
-            Revision::London,
-            tx,
-            i64::MAX,
-        )
-        .await
-        .unwrap();
+        let ret = self.execute_tx(lock.deref_mut(), &header, tx, gas).await?;
+        self.mine(&mut lock, vec![]).await?;
+        if ret.status_code != StatusCode::Success {
+            return Err(self.revert_error(&ret, gas).await.into());
+        }
+
+        ret.create_address.ok_or_else(|| {
+            ForkError::InvalidTransaction("a deployment needs an empty `to`".to_string())
+        })
+    }
+
+    /// Executes the transaction without the checks of `send_transaction()` and returns the gas
+    /// used with the output. It's mined in a block of its own right away, even if automine is
+    /// off, as the caller gets the outcome now; the pending transactions stay in the mempool.
+    pub async fn transact(&self, tx: &TypedTransaction) -> Result<(u64, Vec<u8>), ForkError> {
+        let header = self.pending_header().await;
+        let mut lock = self.backend.lock().await;
+        let ret = self
+            .execute_tx(lock.deref_mut(), &header, tx, i64::MAX)
+            .await?;
+        self.mine(&mut lock, vec![]).await?;
 
         // only return the output data if it's successful
         if ret.status_code == StatusCode::Success {
             Ok(((i64::MAX - ret.gas_left) as u64, ret.output_data.to_vec()))
         } else {
-            Err(ProviderError::CustomError(format!(
-                "reverted with {:?}",
-                ret.output_data
-            )))
+            Err(self.revert_error(&ret, i64::MAX).await.into())
         }
     }
-}
 
-#[derive(Debug)]
-pub struct LoopbackProvider;
+    /// Executes the transaction like `transact()` and reports every account and storage slot
+    /// it changed, see `StateDiff::to_prestate_diff()` and `StateDiff::to_parity_state_diff()`.
+    pub async fn transact_with_state_diff(
+        &self,
+        tx: &TypedTransaction,
+    ) -> Result<StateDiff, ForkError> {
+        self.state_diff(tx, true).await
+    }
 
-#[async_trait]
-impl JsonRpcClient for LoopbackProvider {
-    type Error = ProviderError;
+    /// The same as `transact_with_state_diff()`, but the state changes are thrown away.
+    pub async fn call_with_state_diff(
+        &self,
+        tx: &TypedTransaction,
+    ) -> Result<StateDiff, ForkError> {
+        self.state_diff(tx, false).await
+    }
 
-    /// This is used by PendingTransaction only.
-    ///         self.request("eth_getTransactionByHash", [hash]).await
-    /// we can safely panic on other cases.
-    async fn request<T, R>(&self, _method: &str, _params: T) -> Result<R, Self::Error>
-    where
-        T: Debug + Serialize + Send + Sync,
-        R: DeserializeOwned,
-    {
-        unreachable!("It shall not send out actual requests.")
+    async fn state_diff(
+        &self,
+        tx: &TypedTransaction,
+        commit: bool,
+    ) -> Result<StateDiff, ForkError> {
+        let header = self.pending_header().await;
+        let mut lock = self.backend.lock().await;
+        let snapshot = lock.take_snapshot();
+
+        let ret = self
+            .execute_tx(lock.deref_mut(), &header, tx, i64::MAX)
+            .await?;
+        let diff = StateDiff::since(lock.deref_mut(), &snapshot).await?;
+
+        if commit {
+            self.mine(&mut lock, vec![]).await?;
+        } else {
+            lock.revert_to_snapshot(snapshot);
+        }
+
+        if ret.status_code == StatusCode::Success {
+            Ok(diff)
+        } else {
+            Err(self.revert_error(&ret, i64::MAX).await.into())
+        }
     }
-}
 
-#[async_trait]
-impl Middleware for ForkedEvmProvider {
-    type Error = ProviderError;
-    type Provider = LoopbackProvider;
-    type Inner = Self;
+    /// Generates the EIP-2930 access list of the transaction on top of `block`, the latest one
+    /// by default, and reports the gas used with and without it. It's executed in isolation,
+    /// nothing is changed.
+    pub async fn create_access_list_with_gas_comparison(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> Result<AccessListWithGasComparison, ForkError> {
+        if let Some((mut state, header)) = self.state_at(block).await? {
+            let tx = resolve_recipient(&mut state, &header, Revision::London, tx).await?;
+            return Ok(create_access_list(&mut state, &header, Revision::London, &tx).await?);
+        }
 
-    fn inner(&self) -> &Self::Inner {
-        unreachable!("There is no inner provider here")
+        let header = self.pending_header().await;
+        let mut lock = self.backend.lock().await;
+        let tx = resolve_recipient(lock.deref_mut(), &header, Revision::London, tx).await?;
+        Ok(create_access_list(lock.deref_mut(), &header, Revision::London, &tx).await?)
     }
 
-    async fn get_block_number(&self) -> Result<U64, Self::Error> {
-        Ok(self.state_block_number.into())
+    /// Registers the custom errors of the ABI, so they're decoded in the revert errors.
+    pub async fn register_abi(&self, abi: &Abi) {
+        let mut lock = self.revert_decoder.lock().await;
+        lock.register_abi(abi);
     }
 
-    async fn get_balance<T: Into<NameOrAddress> + Send + Sync>(
+    async fn revert_error(&self, ret: &CallResult, gas_limit: i64) -> RevertError {
+        let lock = self.revert_decoder.lock().await;
+        RevertError::new(ret, (gas_limit - ret.gas_left) as u64, &lock)
+    }
+
+    /// Starts or stops collecting the call tree of every execution, including `transact()`,
+    /// `call()` and `send_transaction()`. Stopping it drops the collected ones.
+    pub async fn set_call_tracing(&self, enabled: bool) {
+        let mut lock = self.call_traces.lock().await;
+        *lock = if enabled { Some(vec![]) } else { None };
+    }
+
+    /// Takes the call trees collected so far, in the shape of geth's `callTracer` output.
+    pub async fn take_call_traces(&self) -> Vec<CallFrame> {
+        let mut lock = self.call_traces.lock().await;
+        lock.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    async fn execute_tx(
         &self,
-        from: T,
-        _block: Option<BlockId>,
-    ) -> Result<U256, ProviderError> {
-        let from = match from.into() {
-            NameOrAddress::Name(_) => todo!(),
-            NameOrAddress::Address(addr) => addr,
-        };
+        state: &mut IntraBlockState<StateMuxer>,
+        header: &PartialHeader,
+        tx: &TypedTransaction,
+        gas: i64,
+    ) -> Result<CallResult, ForkError> {
+        let tx = &resolve_recipient(state, header, Revision::London, tx).await?;
 
-        let mut lock = self.backend.lock().await;
-        Ok(lock.get_balance(from).await.unwrap())
+        let mut call_traces = self.call_traces.lock().await;
+
+        if let Some(call_traces) = call_traces.as_mut() {
+            let mut tracer = CallTracer::default();
+            let ret =
+                execute_with_tracer(state, header, Revision::London, tx, gas, &mut tracer).await?;
+            call_traces.extend(tracer.into_call_frame());
+            Ok(ret)
+        } else {
+            Ok(execute(state, header, Revision::London, tx, gas).await?)
+        }
     }
 
-    async fn get_transaction_count<T: Into<NameOrAddress> + Send + Sync>(
+    /// Resolves the ENS name against the latest state.
+    async fn address_of(&self, name_or_address: NameOrAddress) -> Result<Address, ForkError> {
+        match name_or_address {
+            NameOrAddress::Name(name) => {
+                let header = self.pending_header().await;
+                let mut lock = self.backend.lock().await;
+                resolve_name(lock.deref_mut(), &header, Revision::London, &name).await
+            }
+            NameOrAddress::Address(address) => Ok(address),
+        }
+    }
+
+    /// Mines the state changes since the previous local block in a new one. The transactions
+    /// from the mempool come with their receipts, and the subscriptions are notified of the new
+    /// block and of its logs.
+    async fn mine(
         &self,
-        from: T,
-        _block: Option<BlockId>,
-    ) -> Result<U256, Self::Error> {
-        let from = match from.into() {
-            NameOrAddress::Name(_) => todo!(),
-            NameOrAddress::Address(addr) => addr,
+        state: &mut IntraBlockState<StateMuxer>,
+        transactions: Vec<(Transaction, TransactionReceipt)>,
+    ) -> Result<(), ForkError> {
+        let mut blocks = self.blocks.lock().await;
+        let block_number = self.fork_block_number() + blocks.len() + 1;
+        let header = self.header_at(&blocks, block_number)?;
+        let parent = self.header_at(&blocks, block_number - 1);
+
+        let logs: Vec<Log> = if transactions.is_empty() {
+            // the ones of transact() and the like, which have no receipt, the journal and the
+            // logs start over with every block
+            state
+                .logs()
+                .iter()
+                .enumerate()
+                .map(|(i, log)| Log {
+                    address: log.address,
+                    topics: log.topics.clone(),
+                    data: log.data.clone().into(),
+                    block_hash: Some(header.hash),
+                    block_number: Some(block_number.into()),
+                    log_index: Some(i.into()),
+                    ..Default::default()
+                })
+                .collect()
+        } else {
+            transactions
+                .iter()
+                .flat_map(|(_, receipt)| receipt.logs.clone())
+                .collect()
         };
+        // the earlier blocks are read from their changes, the journal isn't needed anymore
+        blocks.push(
+            overlay_since(state, &Snapshot::default()).await?,
+            header.timestamp,
+        );
+        state.clear_journal_and_substate();
 
-        let mut lock = self.backend.lock().await;
-        Ok(lock.get_nonce(from).await.unwrap().into())
+        let mut mined = self.mined.lock().await;
+        mined.block_number = block_number;
+        for (tx, receipt) in transactions {
+            mined.transactions.insert(tx.hash, (tx, receipt));
+        }
+        drop(mined);
+        drop(blocks);
+
+        let parent_hash = match parent {
+            Ok(parent) => parent.hash,
+            // the fork block, the backend is locked by the caller so it's read from the state
+            Err(_) => match state.db().read_block_header(block_number - 1).await {
+                Ok(Some(parent)) => parent.hash,
+                _ => H256::zero(),
+            },
+        };
+        let block = to_block(&header, block_number, parent_hash);
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .notify_block(&block, &logs);
+        Ok(())
     }
 
-    async fn send_transaction<T: Into<TypedTransaction> + Send + Sync>(
-        &self,
-        tx: T,
-        _block: Option<BlockId>,
-    ) -> Result<PendingTransaction<'_, Self::Provider>, Self::Error> {
-        let tx = tx.into();
-        let gas = tx
-            .gas()
-            .map(|x| i64::try_from(x.as_u64()).unwrap())
-            .unwrap_or_default();
+    /// The subscriptions of `subscribe_blocks()` and the like, which the websocket server
+    /// shares.
+    pub(crate) fn subscriptions(&self) -> &StdMutex<Subscriptions> {
+        &self.subscriptions
+    }
 
-        let mut lock = self.backend.lock().await;
-        let _ = execute(lock.deref_mut(), &self.header, Revision::London, &tx, gas)
-            .await
-            .unwrap();
+    /// Disables or re-enables mining a block as soon as a transaction is sent. Without it,
+    /// the sent transactions wait in the mempool until `mine_block()`. It doesn't apply to
+    /// `transact()` and the like, which always mine their own block.
+    pub async fn set_automine(&self, enabled: bool) {
+        *self.automine.lock().await = enabled;
+    }
 
-        // TODO:
-        Ok(PendingTransaction::new(H256::zero(), &self.dummy_provider))
+    /// Mines the pending transactions of the mempool in a new local block, ordered like geth's
+    /// miner does. The ones that became invalid in the meantime are dropped. Returns the hashes
+    /// of the mined transactions in block order.
+    pub async fn mine_block(&self) -> Result<Vec<H256>, ForkError> {
+        let (hashes, _) = self.mine_pending(&[]).await?;
+        Ok(hashes)
     }
 
-    async fn call(
+    /// Mines `count` blocks like `mine_block()`, the pending transactions go to the first one.
+    /// The blocks are `interval` seconds apart, one by default.
+    pub async fn mine_blocks(
+        &self,
+        count: u64,
+        interval: Option<u64>,
+    ) -> Result<Vec<H256>, ForkError> {
+        let mut hashes = vec![];
+        for i in 0..count {
+            if let (Some(interval), true) = (interval, i > 0) {
+                let mut blocks = self.blocks.lock().await;
+                let latest = blocks.timestamp(blocks.len() - 1, self.fork_header().timestamp);
+                blocks.set_next_timestamp(latest.saturating_add(interval));
+            }
+            hashes.extend(self.mine_block().await?);
+        }
+        Ok(hashes)
+    }
+
+    /// Validates the transaction against the latest state and adds it to the mempool, then
+    /// mines it straight away if automine is on and its nonce has no gap.
+    async fn send(
         &self,
         tx: &TypedTransaction,
-        _block: Option<BlockId>,
-    ) -> Result<Bytes, Self::Error> {
+        signed: Option<SignedTransaction>,
+    ) -> Result<H256, ForkError> {
+        let header = self.pending_header().await;
+        let gas = check_transaction(tx, &header)?;
+
         let mut lock = self.backend.lock().await;
-        let ret = execute(
-            lock.deref_mut(),
-            &self.header,
-            Revision::London,
-            tx,
-            i64::MAX,
-        )
-        .await
-        .unwrap();
+        let mut tx = resolve_recipient(lock.deref_mut(), &header, Revision::London, tx).await?;
 
-        // only return the output data if it's successful
-        if ret.status_code == StatusCode::Success {
-            Ok(ret.output_data.into())
-        } else {
-            Err(ProviderError::CustomError(format!(
-                "reverted with {:?}",
-                ret.output_data
-            )))
+        let from = get_sender(&tx);
+        let account_nonce = lock.get_nonce(from).await?;
+        let mut mempool = self.mempool.lock().await;
+        // `check_transaction()` made sure it fits
+        let nonce = match tx.nonce() {
+            Some(nonce) => nonce.as_u64(),
+            None => mempool.next_nonce(from, account_nonce),
+        };
+        if nonce < account_nonce {
+            return Err(ForkError::InvalidTransaction("nonce too low".to_string()));
+        }
+        tx.set_nonce(nonce);
+
+        // like geth, the sender has to afford the max fee, not only the effective one
+        if lock.get_balance(from).await? < max_cost(&tx, gas)? {
+            return Err(ForkError::InvalidTransaction(
+                "insufficient funds for gas * price + value".to_string(),
+            ));
+        }
+
+        // impersonated transactions aren't signed, anything unique does as their hash
+        let hash = match &signed {
+            Some(signed) => signed.hash,
+            None => keccak256(serde_json::to_vec(&tx).map_err(anyhow::Error::from)?),
+        };
+        if mempool.get(hash).is_some() || self.mined.lock().await.transactions.contains_key(&hash) {
+            return Err(ForkError::InvalidTransaction("already known".to_string()));
+        }
+
+        mempool.insert(tx, hash, signed)?;
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .notify_pending_transaction(hash);
+        let is_pending = mempool.next_nonce(from, account_nonce) > nonce;
+        drop(mempool);
+        drop(lock);
+
+        if is_pending && *self.automine.lock().await {
+            let (_, mut dropped) = self.mine_pending(&[]).await?;
+            if let Some(e) = dropped.remove(&hash) {
+                return Err(e);
+            }
         }
+        Ok(hash)
     }
 
-    async fn get_storage_at<T: Into<NameOrAddress> + Send + Sync>(
+    /// Mines a block with the bundle on top, followed by the pending transactions that fit in
+    /// it. Returns the hashes of the included ones together with why the others have been
+    /// dropped. Nothing is mined if a transaction of the bundle fails.
+    async fn mine_pending(
         &self,
-        address: T,
-        location: H256,
-        _block: Option<BlockId>,
-    ) -> Result<H256, Self::Error> {
-        let address = match address.into() {
-            NameOrAddress::Name(_) => {
-                todo!()
+        bundle: &[PoolTransaction],
+    ) -> Result<(Vec<H256>, HashMap<H256, ForkError>), ForkError> {
+        let header = self.pending_header().await;
+        let base_fee_per_gas = header.base_fee_per_gas.unwrap_or_default();
+
+        let mut lock = self.backend.lock().await;
+        let mut mempool = self.mempool.lock().await;
+
+        let block_start = lock.take_snapshot();
+        let mut block: Vec<(Transaction, TransactionReceipt)> = vec![];
+
+        for pooled in bundle {
+            let e = match self
+                .apply_transaction(lock.deref_mut(), &header, pooled, &block)
+                .await
+            {
+                Ok((tx, receipt, ret)) if ret.status_code == StatusCode::Success => {
+                    block.push((tx, receipt));
+                    continue;
+                }
+                Ok((_, receipt, ret)) => {
+                    let gas_used = receipt.gas_used.unwrap_or_default().as_u64();
+                    let decoder = self.revert_decoder.lock().await;
+                    ForkError::from(RevertError::new(&ret, gas_used, &decoder))
+                }
+                Err(e) => e,
+            };
+            lock.revert_to_snapshot(block_start);
+            return Err(e);
+        }
+
+        // after the bundle, which may have used some of their nonces
+        let account_nonces = match self.account_nonces(lock.deref_mut(), &mut mempool).await {
+            Ok(account_nonces) => account_nonces,
+            Err(e) => {
+                lock.revert_to_snapshot(block_start);
+                return Err(e);
             }
-            NameOrAddress::Address(address) => address,
         };
+        let mut dropped = HashMap::new();
+        // the senders whose next transactions can't be included any more
+        let mut skipped = HashSet::new();
 
-        let mut lock = self.backend.lock().await;
-        Ok(lock
-            .get_current_storage(address, location)
-            .await
-            .map_err(|e| ProviderError::CustomError(format!("{:?}", e)))?)
+        for pooled in mempool.best(&account_nonces, base_fee_per_gas) {
+            let from = pooled.from();
+            if skipped.contains(&from) {
+                continue;
+            }
+
+            let gas_used = block
+                .last()
+                .map(|(_, receipt)| receipt.cumulative_gas_used)
+                .unwrap_or_default();
+            let gas = pooled.tx.gas().cloned().unwrap_or_default();
+            if gas + gas_used > U256::from(header.gas_limit) {
+                // it waits for the next block
+                skipped.insert(from);
+                continue;
+            }
+
+            match self
+                .apply_transaction(lock.deref_mut(), &header, &pooled, &block)
+                .await
+            {
+                Ok((tx, receipt, _)) => block.push((tx, receipt)),
+                Err(e @ ForkError::InvalidTransaction(_)) => {
+                    skipped.insert(from);
+                    dropped.insert(pooled.hash, e);
+                }
+                Err(e) => {
+                    lock.revert_to_snapshot(block_start);
+                    return Err(e);
+                }
+            }
+            mempool.remove(from, pooled.nonce());
+        }
+        let hashes = block.iter().map(|(tx, _)| tx.hash).collect();
+        self.mine(&mut lock, block).await?;
+        Ok((hashes, dropped))
+    }
+
+    /// The account nonces of the senders in the mempool, the transactions whose nonces have
+    /// been used by `transact()` and the like are dropped.
+    async fn account_nonces(
+        &self,
+        state: &mut IntraBlockState<StateMuxer>,
+        mempool: &mut Mempool,
+    ) -> Result<HashMap<Address, u64>, ForkError> {
+        let mut account_nonces = HashMap::new();
+        for from in mempool.senders() {
+            account_nonces.insert(from, state.get_nonce(from).await?);
+        }
+        mempool.prune(&account_nonces);
+        Ok(account_nonces)
+    }
+
+    /// Executes the transaction after the ones already in the block like a node would: the
+    /// nonce is bumped, the gas is bought at the effective gas price and the tip goes to the
+    /// coinbase. Failed transactions are included as well, only invalid ones are rejected.
+    async fn apply_transaction(
+        &self,
+        state: &mut IntraBlockState<StateMuxer>,
+        header: &PartialHeader,
+        pooled: &PoolTransaction,
+        block: &[(Transaction, TransactionReceipt)],
+    ) -> Result<(Transaction, TransactionReceipt, CallResult), ForkError> {
+        let tx = &pooled.tx;
+        let gas = gas_limit(tx)?;
+        let from = pooled.from();
+
+        let nonce = state.get_nonce(from).await?;
+        match pooled.nonce().cmp(&nonce) {
+            Ordering::Less => {
+                return Err(ForkError::InvalidTransaction("nonce too low".to_string()))
+            }
+            Ordering::Greater => {
+                return Err(ForkError::InvalidTransaction("nonce too high".to_string()))
+            }
+            Ordering::Equal => {}
+        }
+        if state.get_balance(from).await? < max_cost(tx, gas)? {
+            return Err(ForkError::InvalidTransaction(
+                "insufficient funds for gas * price + value".to_string(),
+            ));
+        }
+
+        let base_fee_per_gas = header.base_fee_per_gas.unwrap_or_default();
+        let gas_price = get_effective_gas_price(tx, base_fee_per_gas);
+        let logs_before = state.logs().len();
+
+        let snapshot = state.take_snapshot();
+        let ret = async {
+            // `max_cost()` bounds them, but a pooled transaction must never panic the miner
+            state
+                .subtract_from_balance(from, gas_price.saturating_mul(gas.into()))
+                .await?;
+            // contract creation bumps it in the interpreter
+            if tx.to().is_some() {
+                state.set_nonce(from, nonce + 1).await?;
+            }
+
+            let ret = self.execute_tx(state, header, tx, gas).await?;
+
+            let gas_used = (gas - ret.gas_left) as u64;
+            state
+                .add_to_balance(from, gas_price.saturating_mul(ret.gas_left.into()))
+                .await?;
+            state
+                .add_to_balance(
+                    header.beneficiary,
+                    gas_price
+                        .saturating_sub(base_fee_per_gas)
+                        .saturating_mul(gas_used.into()),
+                )
+                .await?;
+            Ok::<_, ForkError>((ret, gas_used))
+        }
+        .await;
+        let (ret, gas_used) = match ret {
+            Ok(ret) => ret,
+            Err(e) => {
+                state.revert_to_snapshot(snapshot);
+                return Err(e);
+            }
+        };
+
+        let block_hash = Some(header.hash);
+        let block_number = Some(header.number.into());
+        let transaction_index = U64::from(block.len());
+        let cumulative_gas_used = block
+            .last()
+            .map(|(_, receipt)| receipt.cumulative_gas_used)
+            .unwrap_or_default()
+            + gas_used;
+        let log_index = block
+            .iter()
+            .map(|(_, receipt)| receipt.logs.len())
+            .sum::<usize>();
+
+        let transaction = Transaction {
+            block_hash,
+            block_number,
+            transaction_index: Some(transaction_index),
+            ..pooled.transaction()
+        };
+        let logs = state.logs()[logs_before..]
+            .iter()
+            .enumerate()
+            .map(|(i, log)| Log {
+                address: log.address,
+                topics: log.topics.clone(),
+                data: log.data.clone().into(),
+                block_hash,
+                block_number,
+                transaction_hash: Some(pooled.hash),
+                transaction_index: Some(transaction_index),
+                log_index: Some((log_index + i).into()),
+                ..Default::default()
+            })
+            .collect();
+        let receipt = TransactionReceipt {
+            transaction_hash: pooled.hash,
+            transaction_index,
+            block_hash,
+            block_number,
+            cumulative_gas_used,
+            gas_used: Some(gas_used.into()),
+            contract_address: ret.create_address,
+            logs,
+            status: Some(((ret.status_code == StatusCode::Success) as u64).into()),
+            ..Default::default()
+        };
+
+        Ok((transaction, receipt, ret))
+    }
+
+    /// Simulates the signed transactions of the bundle in order on top of the latest state, in a
+    /// block of the given number and timestamp, like flashbots' `eth_callBundle`. Reverted
+    /// transactions are reported in the results, invalid ones fail the simulation. Nothing is
+    /// changed.
+    pub async fn simulate_bundle(
+        &self,
+        txs: &[Bytes],
+        block_number: u64,
+        timestamp: Option<u64>,
+    ) -> Result<BundleSimulation, ForkError> {
+        if block_number <= self.fork_block_number() {
+            return Err(ForkError::InvalidTransaction(format!(
+                "block {} isn't after the fork block",
+                block_number
+            )));
+        }
+        let mut header = self.header_at(&*self.blocks.lock().await, block_number)?;
+        if let Some(timestamp) = timestamp {
+            header.timestamp = timestamp;
+        }
+        let bundle = self.decode_bundle(txs, &header)?;
+
+        let mut lock = self.backend.lock().await;
+        let snapshot = lock.take_snapshot();
+        let simulation = self
+            .simulate(lock.deref_mut(), &header, &bundle, &snapshot)
+            .await;
+        lock.revert_to_snapshot(snapshot);
+
+        simulation
+    }
+
+    /// Mines the signed transactions of the bundle at the top of a new local block, followed by
+    /// the pending transactions of the mempool. The bundle is all or nothing: if one of its
+    /// transactions fails, nothing is mined. Returns the hashes of the mined transactions.
+    pub async fn send_bundle(&self, txs: &[Bytes]) -> Result<Vec<H256>, ForkError> {
+        let header = self.pending_header().await;
+        let bundle = self.decode_bundle(txs, &header)?;
+
+        let (hashes, _) = self.mine_pending(&bundle).await?;
+        Ok(hashes)
+    }
+
+    /// Decodes the signed transactions, their nonces and balances are only checked when they're
+    /// executed as they depend on the transactions before them.
+    fn decode_bundle(
+        &self,
+        txs: &[Bytes],
+        header: &PartialHeader,
+    ) -> Result<Vec<PoolTransaction>, ForkError> {
+        let mut bundle = vec![];
+        for raw in txs {
+            let signed = SignedTransaction::decode(&raw.0[..])?;
+            self.check_chain_id(&signed)?;
+            check_transaction(&signed.tx, header)?;
+            bundle.push(PoolTransaction::new(
+                signed.tx.clone(),
+                signed.hash,
+                Some(signed),
+            ));
+        }
+        Ok(bundle)
+    }
+
+    async fn simulate(
+        &self,
+        state: &mut IntraBlockState<StateMuxer>,
+        header: &PartialHeader,
+        bundle: &[PoolTransaction],
+        snapshot: &Snapshot,
+    ) -> Result<BundleSimulation, ForkError> {
+        let base_fee_per_gas = header.base_fee_per_gas.unwrap_or_default();
+        let coinbase = header.beneficiary;
+
+        let mut block = vec![];
+        let mut results = vec![];
+        for pooled in bundle {
+            let coinbase_before = state.get_balance(coinbase).await?;
+            let (tx, receipt, ret) = self
+                .apply_transaction(state, header, pooled, &block)
+                .await?;
+            let coinbase_diff = state
+                .get_balance(coinbase)
+                .await?
+                .saturating_sub(coinbase_before);
+
+            let gas_used = receipt.gas_used.unwrap_or_default().as_u64();
+            let gas_price = get_effective_gas_price(&pooled.tx, base_fee_per_gas)
+                .saturating_sub(base_fee_per_gas);
+            let gas_fees = gas_price * gas_used;
+            let (error, revert) = if ret.status_code == StatusCode::Success {
+                (None, None)
+            } else {
+                let decoder = self.revert_decoder.lock().await;
+                let e = RevertError::new(&ret, gas_used, &decoder);
+                let revert = match &e.reason {
+                    RevertReason::Unknown => None,
+                    reason => Some(reason.to_string()),
+                };
+                (Some(e.to_string()), revert)
+            };
+
+            results.push(BundleTransactionResult {
+                coinbase_diff,
+                eth_sent_to_coinbase: coinbase_diff.saturating_sub(gas_fees),
+                from_address: tx.from,
+                gas_fees,
+                gas_price,
+                gas_used,
+                to_address: tx.to,
+                tx_hash: tx.hash,
+                value: ret.output_data.to_vec().into(),
+                error,
+                revert,
+                logs: receipt.logs.clone(),
+            });
+            block.push((tx, receipt));
+        }
+
+        let sum = |f: fn(&BundleTransactionResult) -> U256| {
+            results.iter().map(f).fold(U256::zero(), |a, b| a + b)
+        };
+        let coinbase_diff = sum(|r| r.coinbase_diff);
+        let total_gas_used = results.iter().map(|r| r.gas_used).sum::<u64>();
+        let bundle_hash = bundle_hash(bundle.iter().map(|tx| tx.hash));
+
+        Ok(BundleSimulation {
+            bundle_gas_price: coinbase_diff
+                .checked_div(total_gas_used.into())
+                .unwrap_or_default(),
+            bundle_hash,
+            coinbase_diff,
+            eth_sent_to_coinbase: sum(|r| r.eth_sent_to_coinbase),
+            gas_fees: sum(|r| r.gas_fees),
+            state_block_number: self.latest_block_number().await,
+            total_gas_used,
+            state_diff: StateDiff::since(state, snapshot).await?,
+            results,
+        })
+    }
+
+    /// Replays a mainnet block on top of the state of its parent, and compares the outcome with
+    /// the receipts and the storage of the archive node. Replaying the block after the fork
+    /// block works offline once it's been recorded, other blocks need the archive node.
+    /// Nothing is changed.
+    pub async fn replay_block(&self, block_number: u64) -> Result<ReplayReport, ForkError> {
+        self.replay(block_number, None).await
+    }
+
+    /// Replays the block of the transaction like `replay_block()`, but only reports the
+    /// transaction and the storage it wrote, either on chain or in the replay.
+    pub async fn replay_transaction(&self, hash: H256) -> Result<ReplayReport, ForkError> {
+        // the block after the fork block is the usual one, and it may be recorded
+        let next_block_number = self.fork_block_number() + 1;
+        let lock = self.backend.lock().await;
+        let next_block = match lock.db().read_block_transactions(next_block_number).await {
+            Ok(transactions) => transactions.unwrap_or_default(),
+            Err(e) => match ForkError::from(e) {
+                ForkError::BackendMiss(_) => vec![],
+                e => return Err(e),
+            },
+        };
+
+        let block_number = if next_block.iter().any(|(tx, _)| tx.hash == hash) {
+            next_block_number
+        } else {
+            lock.db()
+                .read_transaction_block_number(hash)
+                .await?
+                .ok_or_else(|| ForkError::BackendMiss(format!("transaction {:?}", hash)))?
+        };
+        drop(lock);
+
+        self.replay(block_number, Some(hash)).await
+    }
+
+    async fn replay(
+        &self,
+        block_number: u64,
+        only: Option<H256>,
+    ) -> Result<ReplayReport, ForkError> {
+        let parent = block_number
+            .checked_sub(1)
+            .ok_or_else(|| ForkError::BackendMiss("the block before the genesis".to_string()))?;
+
+        let (mut state, header) = {
+            let backend = self.backend.lock().await;
+            if parent == self.fork_block_number() {
+                let db = backend.db().clone();
+                (IntraBlockState::new(db), self.fork_header())
+            } else {
+                let db = backend.db().at_block(parent)?;
+                let mut header = db
+                    .read_block_header(block_number)
+                    .await?
+                    .ok_or_else(|| ForkError::BackendMiss(format!("block {}", block_number)))?;
+                header.chain_id = self.fork_header().chain_id;
+                (IntraBlockState::new(db), header)
+            }
+        };
+
+        let transactions = state
+            .db()
+            .read_block_transactions(block_number)
+            .await?
+            .ok_or_else(|| {
+                ForkError::BackendMiss(format!("transactions of block {}", block_number))
+            })?;
+
+        // the slots changed on chain, the ones the replay writes are added to them
+        let mut written = BTreeSet::new();
+        for (hash, address, slot) in state.db().read_block_storage_changes(block_number).await? {
+            if only.is_none() || only == Some(hash) {
+                written.insert((address, slot));
+            }
+        }
+
+        let mut block = vec![];
+        let mut replays = vec![];
+        for (tx, expected) in transactions {
+            let pooled = PoolTransaction::new(typed_transaction(&tx), tx.hash, None);
+            let snapshot = state.take_snapshot();
+            let mismatches = match self
+                .apply_transaction(&mut state, &header, &pooled, &block)
+                .await
+            {
+                Ok((tx, actual, _)) => {
+                    let mismatches = compare_receipts(&expected, &actual);
+                    block.push((tx, actual));
+                    mismatches
+                }
+                Err(e @ ForkError::InvalidTransaction(_)) => {
+                    vec![Mismatch::Invalid {
+                        error: e.to_string(),
+                    }]
+                }
+                Err(e) => return Err(e),
+            };
+
+            if only.is_none() || only == Some(tx.hash) {
+                for delta in state.journal_since(&snapshot) {
+                    if let Delta::StorageChange { address, key, .. } = delta {
+                        written.insert((*address, *key));
+                    }
+                }
+                replays.push(TransactionReplay {
+                    hash: tx.hash,
+                    mismatches,
+                });
+            }
+        }
+
+        let mut storage = vec![];
+        for (address, slot) in written {
+            let actual = state.get_current_storage(address, slot).await?;
+            let expected = state
+                .db()
+                .read_storage_after_block(block_number, address, slot)
+                .await?;
+            if actual != expected {
+                storage.push(StorageMismatch {
+                    address,
+                    slot,
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        Ok(ReplayReport {
+            block_number,
+            transactions: replays,
+            storage,
+        })
+    }
+
+    fn check_chain_id(&self, signed: &SignedTransaction) -> Result<(), ForkError> {
+        match signed.chain_id {
+            Some(chain_id) if chain_id != self.fork_header().chain_id => Err(
+                ForkError::InvalidTransaction("invalid chain id".to_string()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// The pending and the queued transactions of the mempool.
+    async fn txpool(&self) -> Result<(Vec<PoolTransaction>, Vec<PoolTransaction>), ForkError> {
+        let mut lock = self.backend.lock().await;
+        let mut mempool = self.mempool.lock().await;
+        let account_nonces = self.account_nonces(lock.deref_mut(), &mut mempool).await?;
+        Ok(mempool.content(&account_nonces))
+    }
+
+    async fn latest_block_number(&self) -> u64 {
+        let blocks = self.blocks.lock().await;
+        self.fork_block_number() + blocks.len()
+    }
+
+    /// The header of a local block, they follow the fork block one second apart unless they
+    /// were mined with another interval. The fork block and the ones before it aren't local.
+    fn header_at(
+        &self,
+        blocks: &LocalBlocks,
+        block_number: u64,
+    ) -> Result<PartialHeader, ForkError> {
+        let fork_header = self.fork_header();
+        let offset = block_number
+            .checked_sub(fork_header.number)
+            .ok_or_else(|| ForkError::BackendMiss(format!("local block {}", block_number)))?;
+        Ok(local_header(&fork_header, blocks, offset))
+    }
+
+    /// The header of the block after the fork block.
+    pub(crate) fn fork_header(&self) -> PartialHeader {
+        self.header.read().unwrap().clone()
+    }
+
+    fn fork_block_number(&self) -> u64 {
+        self.header.read().unwrap().number - 1
+    }
+
+    /// The header of a local block or of a block before the fork, which has to be recorded
+    /// in the local database or read from the archive node.
+    async fn header_of(&self, block_number: u64) -> Result<PartialHeader, ForkError> {
+        if block_number > self.fork_block_number() {
+            return self.header_at(&*self.blocks.lock().await, block_number);
+        }
+
+        let lock = self.backend.lock().await;
+        let mut header = lock
+            .db()
+            .read_block_header(block_number)
+            .await?
+            .ok_or_else(|| ForkError::BackendMiss(format!("block {}", block_number)))?;
+        header.chain_id = self.fork_header().chain_id;
+        Ok(header)
+    }
+
+    /// The number `block` refers to, "latest" and "pending" are the same as the mempool is
+    /// only executed once it's mined. Only the hashes of the local blocks are known.
+    async fn block_number_of(&self, block: BlockId) -> Result<u64, ForkError> {
+        let latest = self.latest_block_number().await;
+        match block {
+            BlockId::Number(BlockNumber::Latest) | BlockId::Number(BlockNumber::Pending) => {
+                Ok(latest)
+            }
+            BlockId::Number(BlockNumber::Earliest) => Ok(0),
+            BlockId::Number(BlockNumber::Number(n)) => Ok(n.as_u64()),
+            BlockId::Hash(hash) => {
+                let blocks = self.blocks.lock().await;
+                (self.fork_block_number() + 1..=latest)
+                    .find(|n| {
+                        matches!(self.header_at(&blocks, *n), Ok(header) if header.hash == hash)
+                    })
+                    .ok_or_else(|| ForkError::BackendMiss(format!("block {:?}", hash)))
+            }
+        }
+    }
+
+    async fn block<TX: Default>(&self, block: BlockId) -> Result<Option<Block<TX>>, ForkError> {
+        let block_number = match self.block_number_of(block).await {
+            Ok(block_number) => block_number,
+            Err(ForkError::BackendMiss(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if block_number > self.latest_block_number().await {
+            return Ok(None);
+        }
+
+        let header = self.header_of(block_number).await?;
+        let parent_hash = match block_number.checked_sub(1) {
+            Some(parent) => match self.header_of(parent).await {
+                Ok(parent) => parent.hash,
+                // the block before the fork isn't always recorded
+                Err(ForkError::BackendMiss(_)) => H256::zero(),
+                Err(e) => return Err(e),
+            },
+            None => H256::zero(),
+        };
+
+        Ok(Some(to_block(&header, block_number, parent_hash)))
+    }
+
+    /// The header the next transaction is mined with.
+    async fn pending_header(&self) -> PartialHeader {
+        let blocks = self.blocks.lock().await;
+        local_header(&self.fork_header(), &blocks, blocks.len())
+    }
+
+    /// A copy of the state at `block`, together with the header calls on top of it run with,
+    /// or `None` if it's the latest state.
+    async fn state_at(
+        &self,
+        block: Option<BlockId>,
+    ) -> Result<Option<(IntraBlockState<StateMuxer>, PartialHeader)>, ForkError> {
+        let block_number = match block {
+            Some(block) => self.block_number_of(block).await?,
+            None => return Ok(None),
+        };
+
+        let backend = self.backend.lock().await;
+        let blocks = self.blocks.lock().await;
+        let latest = self.fork_block_number() + blocks.len();
+
+        if block_number > latest {
+            return Err(ForkError::BackendMiss(format!("block {}", block_number)));
+        }
+        if block_number == latest {
+            return Ok(None);
+        }
+
+        // the local blocks are read from their changes on top of the fork block
+        if block_number >= self.fork_block_number() {
+            let count = block_number - self.fork_block_number();
+            let state = blocks.state_after(count, backend.db().clone());
+            return Ok(Some((
+                IntraBlockState::new(backend.db().with_state(Arc::new(state))),
+                self.header_at(&blocks, block_number + 1)?,
+            )));
+        }
+
+        // the blocks before the fork are read from the archive node
+        let db = backend.db().at_block(block_number)?;
+        let mut header = db
+            .read_block_header(block_number + 1)
+            .await?
+            .ok_or_else(|| ForkError::BackendMiss(format!("block {}", block_number + 1)))?;
+        header.chain_id = self.fork_header().chain_id;
+
+        Ok(Some((IntraBlockState::new(db), header)))
+    }
+
+    /// Executes the transaction and keeps its state changes, like `transact()`, but also
+    /// returns the opcode level trace in the shape of geth's `debug_traceTransaction`.
+    pub async fn debug_trace_transaction(
+        &self,
+        tx: &TypedTransaction,
+        config: StructLoggerConfig,
+    ) -> Result<StructLogTrace, ForkError> {
+        self.trace_struct_logs(tx, config, true).await
+    }
+
+    /// The same as `debug_trace_transaction()`, but the state changes are thrown away.
+    pub async fn debug_trace_call(
+        &self,
+        tx: &TypedTransaction,
+        config: StructLoggerConfig,
+    ) -> Result<StructLogTrace, ForkError> {
+        self.trace_struct_logs(tx, config, false).await
+    }
+
+    async fn trace_struct_logs(
+        &self,
+        tx: &TypedTransaction,
+        config: StructLoggerConfig,
+        commit: bool,
+    ) -> Result<StructLogTrace, ForkError> {
+        let mut tracer = StructLogger::new(config);
+
+        let header = self.pending_header().await;
+        let mut lock = self.backend.lock().await;
+        let tx = &resolve_recipient(lock.deref_mut(), &header, Revision::London, tx).await?;
+        // like geth, the trace starts with the gas of the transaction, or of the block without it
+        let gas = match tx.gas() {
+            Some(_) => gas_limit(tx)?,
+            None => header.gas_limit as i64,
+        };
+        let snapshot = lock.take_snapshot();
+        let ret = execute_with_tracer(
+            lock.deref_mut(),
+            &header,
+            Revision::London,
+            tx,
+            gas,
+            &mut tracer,
+        )
+        .await?;
+
+        if commit {
+            self.mine(&mut lock, vec![]).await?;
+        } else {
+            lock.revert_to_snapshot(snapshot);
+        }
+
+        Ok(tracer.into_trace(
+            (gas - ret.gas_left) as u64,
+            ret.status_code != StatusCode::Success,
+            &ret.output_data,
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub struct LoopbackProvider {
+    mempool: Arc<Mutex<Mempool>>,
+    mined: Arc<Mutex<MinedTransactions>>,
+    subscriptions: Arc<StdMutex<Subscriptions>>,
+}
+
+#[async_trait]
+impl JsonRpcClient for LoopbackProvider {
+    type Error = ProviderError;
+
+    /// This is used by PendingTransaction, which polls the transaction, its receipt and the
+    /// block number, and by SubscriptionStream, nothing is sent out.
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let params = serde_json::to_value(params)?;
+        let hash = || serde_json::from_value::<H256>(params[0].clone());
+
+        let ret = match method {
+            "eth_getTransactionByHash" => {
+                let hash = hash()?;
+                let mined = self.mined.lock().await.transactions.get(&hash).cloned();
+                let tx = match mined {
+                    Some((tx, _)) => Some(tx),
+                    None => self
+                        .mempool
+                        .lock()
+                        .await
+                        .get(hash)
+                        .map(|tx| tx.transaction()),
+                };
+                serde_json::to_value(tx)?
+            }
+            "eth_getTransactionReceipt" => {
+                let mined = self.mined.lock().await;
+                serde_json::to_value(mined.transactions.get(&hash()?).map(|(_, receipt)| receipt))?
+            }
+            "eth_blockNumber" => {
+                serde_json::to_value(U64::from(self.mined.lock().await.block_number))?
+            }
+            "eth_subscribe" => {
+                let params = params.as_array().cloned().unwrap_or_default();
+                let kind = SubscriptionKind::from_params(&params)
+                    .map_err(|e| ProviderError::CustomError(e.to_string()))?;
+                serde_json::to_value(self.subscriptions.lock().unwrap().subscribe(kind))?
+            }
+            "eth_unsubscribe" => {
+                let id = serde_json::from_value::<U256>(params[0].clone())?;
+                serde_json::to_value(self.subscriptions.lock().unwrap().unsubscribe(id))?
+            }
+            _ => {
+                return Err(ProviderError::CustomError(format!(
+                    "{} isn't supported by the forked provider",
+                    method
+                )))
+            }
+        };
+
+        Ok(serde_json::from_value(ret)?)
+    }
+}
+
+impl PubsubClient for LoopbackProvider {
+    type NotificationStream = UnboundedReceiver<Box<RawValue>>;
+
+    fn subscribe<T: Into<U256>>(&self, id: T) -> Result<Self::NotificationStream, Self::Error> {
+        let id = id.into();
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .take_notifications(id)
+            .ok_or_else(|| ProviderError::CustomError(format!("subscription {} not found", id)))
+    }
+
+    fn unsubscribe<T: Into<U256>>(&self, id: T) -> Result<(), Self::Error> {
+        self.subscriptions.lock().unwrap().unsubscribe(id.into());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Middleware for ForkedEvmProvider {
+    type Error = ProviderError;
+    type Provider = LoopbackProvider;
+    type Inner = Self;
+
+    /// The default `Middleware` methods forward to the inner one, so there can't be any,
+    /// otherwise the unsupported methods would recurse forever.
+    fn inner(&self) -> &Self::Inner {
+        unreachable!("There is no inner provider here")
+    }
+
+    async fn client_version(&self) -> Result<String, Self::Error> {
+        Ok(format!(
+            "ethers-forked-evm-provider/v{}",
+            env!("CARGO_PKG_VERSION")
+        ))
+    }
+
+    /// Fills in what's missing like geth would, the sender is the first account funded by
+    /// `set_balance()`.
+    async fn fill_transaction(
+        &self,
+        tx: &mut TypedTransaction,
+        block: Option<BlockId>,
+    ) -> Result<(), Self::Error> {
+        if tx.from().is_none() {
+            if let Some(from) = self.accounts.lock().await.first() {
+                tx.set_from(*from);
+            }
+        }
+
+        if let Some(NameOrAddress::Name(name)) = tx.to().cloned() {
+            let to = self.resolve_name(&name).await?;
+            tx.set_to(to);
+        }
+
+        if tx.nonce().is_none() {
+            let from = tx.from().cloned().unwrap_or_default();
+            let block = block.unwrap_or_else(|| BlockNumber::Pending.into());
+            let nonce = self.get_transaction_count(from, Some(block)).await?;
+            tx.set_nonce(nonce);
+        }
+
+        match tx {
+            TypedTransaction::Legacy(inner) => {
+                if inner.gas_price.is_none() {
+                    inner.gas_price = Some(self.get_gas_price().await?);
+                }
+            }
+            TypedTransaction::Eip2930(inner) => {
+                if inner.tx.gas_price.is_none() {
+                    inner.tx.gas_price = Some(self.get_gas_price().await?);
+                }
+            }
+            TypedTransaction::Eip1559(inner) => {
+                if inner.max_fee_per_gas.is_none() || inner.max_priority_fee_per_gas.is_none() {
+                    let (max_fee, max_priority_fee) = self.estimate_eip1559_fees(None).await?;
+                    inner.max_fee_per_gas.get_or_insert(max_fee);
+                    inner
+                        .max_priority_fee_per_gas
+                        .get_or_insert(max_priority_fee);
+                }
+            }
+        }
+
+        if tx.gas().is_none() {
+            let gas = self.estimate_gas(tx).await?;
+            tx.set_gas(gas);
+        }
+
+        Ok(())
+    }
+
+    async fn get_block_number(&self) -> Result<U64, Self::Error> {
+        Ok(self.latest_block_number().await.into())
+    }
+
+    async fn get_block<T: Into<BlockId> + Send + Sync>(
+        &self,
+        block_hash_or_number: T,
+    ) -> Result<Option<Block<H256>>, Self::Error> {
+        Ok(self.block(block_hash_or_number.into()).await?)
+    }
+
+    async fn get_block_with_txs<T: Into<BlockId> + Send + Sync>(
+        &self,
+        block_hash_or_number: T,
+    ) -> Result<Option<Block<Transaction>>, Self::Error> {
+        Ok(self.block(block_hash_or_number.into()).await?)
+    }
+
+    async fn get_uncle_count<T: Into<BlockId> + Send + Sync>(
+        &self,
+        _block_hash_or_number: T,
+    ) -> Result<U256, Self::Error> {
+        Ok(U256::zero())
+    }
+
+    async fn get_uncle<T: Into<BlockId> + Send + Sync>(
+        &self,
+        _block_hash_or_number: T,
+        _idx: U64,
+    ) -> Result<Option<Block<H256>>, Self::Error> {
+        Ok(None)
+    }
+
+    async fn get_chainid(&self) -> Result<U256, Self::Error> {
+        Ok(self.fork_header().chain_id.into())
+    }
+
+    async fn get_net_version(&self) -> Result<String, Self::Error> {
+        Ok(self.fork_header().chain_id.to_string())
+    }
+
+    async fn get_balance<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> Result<U256, ProviderError> {
+        let from = self.address_of(from.into()).await?;
+
+        let balance = match self.state_at(block).await? {
+            Some((mut state, _)) => state.get_balance(from).await,
+            None => self.backend.lock().await.get_balance(from).await,
+        };
+        Ok(balance.map_err(ForkError::from)?)
+    }
+
+    async fn get_transaction_count<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        from: T,
+        block: Option<BlockId>,
+    ) -> Result<U256, Self::Error> {
+        let from = self.address_of(from.into()).await?;
+
+        let nonce = match self.state_at(block).await? {
+            Some((mut state, _)) => state.get_nonce(from).await,
+            None => self.backend.lock().await.get_nonce(from).await,
+        };
+        let nonce = nonce.map_err(ForkError::from)?;
+
+        // the pending nonce counts the transactions waiting in the mempool
+        if block == Some(BlockNumber::Pending.into()) {
+            let mempool = self.mempool.lock().await;
+            return Ok(mempool.next_nonce(from, nonce).into());
+        }
+        Ok(nonce.into())
+    }
+
+    async fn get_gas_price(&self) -> Result<U256, Self::Error> {
+        let header = self.pending_header().await;
+        Ok(header.base_fee_per_gas.unwrap_or_default() + DEFAULT_PRIORITY_FEE)
+    }
+
+    /// There's no competition for the local blocks, the estimator isn't needed.
+    async fn estimate_eip1559_fees(
+        &self,
+        _estimator: Option<fn(U256, Vec<Vec<U256>>) -> (U256, U256)>,
+    ) -> Result<(U256, U256), Self::Error> {
+        let header = self.pending_header().await;
+        let base_fee_per_gas = header.base_fee_per_gas.unwrap_or_default();
+        let max_priority_fee = U256::from(DEFAULT_PRIORITY_FEE);
+        Ok((base_fee_per_gas * 2 + max_priority_fee, max_priority_fee))
+    }
+
+    async fn get_accounts(&self) -> Result<Vec<Address>, Self::Error> {
+        Ok(self.accounts.lock().await.clone())
+    }
+
+    async fn send_transaction<T: Into<TypedTransaction> + Send + Sync>(
+        &self,
+        tx: T,
+        block: Option<BlockId>,
+    ) -> Result<PendingTransaction<'_, Self::Provider>, Self::Error> {
+        let mut tx = tx.into();
+        self.fill_transaction(&mut tx, block).await?;
+        let hash = self.send(&tx, None).await?;
+        Ok(PendingTransaction::new(hash, &self.dummy_provider))
+    }
+
+    async fn send_raw_transaction<'a>(
+        &'a self,
+        tx: Bytes,
+    ) -> Result<PendingTransaction<'a, Self::Provider>, Self::Error> {
+        let signed = SignedTransaction::decode(&tx.0[..])?;
+        self.check_chain_id(&signed)?;
+
+        let tx = signed.tx.clone();
+        let hash = self.send(&tx, Some(signed)).await?;
+        Ok(PendingTransaction::new(hash, &self.dummy_provider))
+    }
+
+    async fn get_transaction<T: Send + Sync + Into<TxHash>>(
+        &self,
+        transaction_hash: T,
+    ) -> Result<Option<Transaction>, Self::Error> {
+        let hash = transaction_hash.into();
+        if let Some((tx, _)) = self.mined.lock().await.transactions.get(&hash) {
+            return Ok(Some(tx.clone()));
+        }
+
+        let mempool = self.mempool.lock().await;
+        Ok(mempool.get(hash).map(|tx| tx.transaction()))
+    }
+
+    async fn get_transaction_receipt<T: Send + Sync + Into<TxHash>>(
+        &self,
+        transaction_hash: T,
+    ) -> Result<Option<TransactionReceipt>, Self::Error> {
+        let mined = self.mined.lock().await;
+        Ok(mined
+            .transactions
+            .get(&transaction_hash.into())
+            .map(|(_, receipt)| receipt.clone()))
+    }
+
+    async fn txpool_content(&self) -> Result<TxpoolContent, Self::Error> {
+        let (pending, queued) = self.txpool().await?;
+        Ok(TxpoolContent {
+            pending: by_sender_and_nonce(&pending, PoolTransaction::transaction),
+            queued: by_sender_and_nonce(&queued, PoolTransaction::transaction),
+        })
+    }
+
+    async fn txpool_inspect(&self) -> Result<TxpoolInspect, Self::Error> {
+        let summary = |tx: &PoolTransaction| TxpoolInspectSummary {
+            to: tx.transaction().to,
+            value: tx.tx.value().cloned().unwrap_or_default(),
+            gas: tx.tx.gas().cloned().unwrap_or_default(),
+            gas_price: get_max_fee_per_gas(&tx.tx),
+        };
+
+        let (pending, queued) = self.txpool().await?;
+        Ok(TxpoolInspect {
+            pending: by_sender_and_nonce(&pending, summary),
+            queued: by_sender_and_nonce(&queued, summary),
+        })
+    }
+
+    async fn txpool_status(&self) -> Result<TxpoolStatus, Self::Error> {
+        let (pending, queued) = self.txpool().await?;
+        Ok(TxpoolStatus {
+            pending: pending.len().into(),
+            queued: queued.len().into(),
+        })
+    }
+
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> Result<Bytes, Self::Error> {
+        // like geth, the call doesn't change anything
+        let ret = match self.state_at(block).await? {
+            Some((mut state, header)) => self.execute_tx(&mut state, &header, tx, i64::MAX).await?,
+            None => {
+                let header = self.pending_header().await;
+                let mut lock = self.backend.lock().await;
+                let snapshot = lock.take_snapshot();
+                let ret = self
+                    .execute_tx(lock.deref_mut(), &header, tx, i64::MAX)
+                    .await;
+                lock.revert_to_snapshot(snapshot);
+                ret?
+            }
+        };
+
+        // only return the output data if it's successful
+        if ret.status_code == StatusCode::Success {
+            Ok(ret.output_data.into())
+        } else {
+            Err(ForkError::from(self.revert_error(&ret, i64::MAX).await).into())
+        }
+    }
+
+    async fn get_code<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        at: T,
+        block: Option<BlockId>,
+    ) -> Result<Bytes, Self::Error> {
+        let at = self.address_of(at.into()).await?;
+
+        let code = match self.state_at(block).await? {
+            Some((mut state, _)) => state.get_code(at).await,
+            None => self.backend.lock().await.get_code(at).await,
+        };
+        Ok(code.map_err(ForkError::from)?.unwrap_or_default().into())
+    }
+
+    async fn get_storage_at<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        address: T,
+        location: H256,
+        block: Option<BlockId>,
+    ) -> Result<H256, Self::Error> {
+        let address = self.address_of(address.into()).await?;
+
+        let value = match self.state_at(block).await? {
+            Some((mut state, _)) => state.get_current_storage(address, location).await,
+            None => {
+                let mut lock = self.backend.lock().await;
+                lock.get_current_storage(address, location).await
+            }
+        };
+        Ok(value.map_err(ForkError::from)?)
+    }
+
+    async fn create_access_list(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> Result<AccessListWithGasUsed, Self::Error> {
+        let ret = self
+            .create_access_list_with_gas_comparison(tx, block)
+            .await?;
+        Ok(AccessListWithGasUsed {
+            access_list: ret.access_list,
+            gas_used: ret.gas_used,
+        })
+    }
+
+    async fn resolve_name(&self, ens_name: &str) -> Result<Address, Self::Error> {
+        Ok(self.address_of(ens_name.into()).await?)
+    }
+
+    async fn lookup_address(&self, address: Address) -> Result<String, Self::Error> {
+        let header = self.pending_header().await;
+        let mut lock = self.backend.lock().await;
+        Ok(lookup_address(lock.deref_mut(), &header, Revision::London, address).await?)
+    }
+
+    /// The local blocks only hold a single transaction each and the fee market isn't
+    /// simulated, so the rewards are all zero and the gas used ratios aren't tracked.
+    async fn fee_history<T: Into<U256> + Serialize + Send + Sync>(
+        &self,
+        block_count: T,
+        last_block: BlockNumber,
+        reward_percentiles: &[f64],
+    ) -> Result<FeeHistory, Self::Error> {
+        let latest = self.latest_block_number().await;
+        let last_block = self
+            .block_number_of(BlockId::Number(last_block))
+            .await?
+            .min(latest);
+        // geth caps it at 1024 blocks
+        let block_count = block_count
+            .into()
+            .min(1024.into())
+            .as_u64()
+            .min(last_block + 1);
+        let oldest_block = last_block + 1 - block_count;
+
+        // it also includes the base fee of the block after the last one
+        let mut base_fee_per_gas = vec![];
+        for block_number in oldest_block..=last_block + 1 {
+            let header = self.header_of(block_number).await?;
+            base_fee_per_gas.push(header.base_fee_per_gas.unwrap_or_default());
+        }
+
+        let reward = if reward_percentiles.is_empty() {
+            vec![]
+        } else {
+            vec![vec![U256::zero(); reward_percentiles.len()]; block_count as usize]
+        };
+
+        Ok(FeeHistory {
+            base_fee_per_gas,
+            gas_used_ratio: vec![0.0; block_count as usize],
+            oldest_block: oldest_block.into(),
+            reward,
+        })
+    }
+
+    async fn subscribe<T, R>(
+        &self,
+        params: T,
+    ) -> Result<SubscriptionStream<'_, Self::Provider, R>, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send + Sync,
+    {
+        self.dummy_provider.subscribe(params).await
+    }
+
+    async fn unsubscribe<T>(&self, id: T) -> Result<bool, Self::Error>
+    where
+        T: Into<U256> + Send + Sync,
+    {
+        self.dummy_provider.unsubscribe(id).await
+    }
+
+    /// The local blocks as they're mined, without their transactions.
+    async fn subscribe_blocks(
+        &self,
+    ) -> Result<SubscriptionStream<'_, Self::Provider, Block<TxHash>>, Self::Error> {
+        self.dummy_provider.subscribe_blocks().await
+    }
+
+    /// The transactions sent to the mempool, including the impersonated ones.
+    async fn subscribe_pending_txs(
+        &self,
+    ) -> Result<SubscriptionStream<'_, Self::Provider, TxHash>, Self::Error> {
+        self.dummy_provider.subscribe_pending_txs().await
+    }
+
+    /// The logs of the transactions mined from now on, the block range of the filter is
+    /// ignored.
+    async fn subscribe_logs<'a>(
+        &'a self,
+        filter: &Filter,
+    ) -> Result<SubscriptionStream<'a, Self::Provider, Log>, Self::Error> {
+        let filter = serde_json::to_value(filter)?;
+        self.dummy_provider
+            .subscribe([serde_json::json!("logs"), filter])
+            .await
+    }
+
+    async fn estimate_gas(&self, tx: &TypedTransaction) -> Result<U256, Self::Error> {
+        let header = self.pending_header().await;
+        let mut lock = self.backend.lock().await;
+        let tx = resolve_recipient(lock.deref_mut(), &header, Revision::London, tx).await?;
+        let decoder = self.revert_decoder.lock().await;
+        let gas = estimate_gas(lock.deref_mut(), &header, Revision::London, &tx, &decoder).await?;
+        Ok(gas.into())
+    }
+}
+
+/// The header of the local block `offset` blocks after the first one, which follows the fork
+/// block.
+fn local_header(fork_header: &PartialHeader, blocks: &LocalBlocks, offset: u64) -> PartialHeader {
+    let block_number = fork_header.number + offset;
+    let mut header = fork_header.clone();
+    header.timestamp = blocks.timestamp(offset, fork_header.timestamp);
+    header.number = block_number;
+    // there's no real block behind it, anything unique does
+    header.hash = keccak256(&[fork_header.hash.as_bytes(), &block_number.to_be_bytes()].concat());
+    header
+}
+
+/// The block as `get_block()` returns it, without its transactions.
+fn to_block<TX: Default>(
+    header: &PartialHeader,
+    block_number: u64,
+    parent_hash: H256,
+) -> Block<TX> {
+    Block {
+        hash: Some(header.hash),
+        parent_hash,
+        author: header.beneficiary,
+        number: Some(block_number.into()),
+        gas_limit: header.gas_limit.into(),
+        timestamp: header.timestamp.into(),
+        difficulty: header.difficulty,
+        base_fee_per_gas: header.base_fee_per_gas,
+        ..Default::default()
+    }
+}
+
+/// A mined local transaction as it was sent, the signature is only kept for
+/// `eth_getTransactionByHash`.
+fn resend(tx: &Transaction) -> PoolTransaction {
+    let typed = typed_transaction(tx);
+    let signed = (!tx.r.is_zero()).then(|| SignedTransaction {
+        tx: typed.clone(),
+        hash: tx.hash,
+        chain_id: None,
+        v: tx.v.as_u64(),
+        r: tx.r,
+        s: tx.s,
+    });
+    PoolTransaction::new(typed, tx.hash, signed)
+}
+
+/// The gas the transaction is sent with, which has to fit in the interpreter's `i64`.
+fn gas_limit(tx: &TypedTransaction) -> Result<i64, ForkError> {
+    let gas = tx.gas().cloned().unwrap_or_default();
+    if gas > U256::from(i64::MAX) {
+        return Err(ForkError::InvalidTransaction(format!(
+            "gas limit {} is too high",
+            gas
+        )));
+    }
+    Ok(gas.as_u64() as i64)
+}
+
+/// The shape of geth's `txpool_content` and `txpool_inspect`.
+fn by_sender_and_nonce<T>(
+    txs: &[PoolTransaction],
+    f: impl Fn(&PoolTransaction) -> T,
+) -> BTreeMap<Address, BTreeMap<String, T>> {
+    let mut ret = BTreeMap::<Address, BTreeMap<String, T>>::new();
+    for tx in txs {
+        ret.entry(tx.from())
+            .or_default()
+            .insert(tx.nonce().to_string(), f(tx));
+    }
+    ret
+}
+
+/// The checks of a transaction that don't depend on the state, returns its gas limit.
+fn check_transaction(tx: &TypedTransaction, header: &PartialHeader) -> Result<i64, ForkError> {
+    let gas = gas_limit(tx)?;
+    if tx
+        .nonce()
+        .map_or(false, |nonce| *nonce > U256::from(u64::MAX))
+    {
+        return Err(ForkError::InvalidTransaction(
+            "nonce has max value".to_string(),
+        ));
+    }
+    max_cost(tx, gas)?;
+    if (gas as u64) < intrinsic_gas(tx, true, true) {
+        return Err(ForkError::InvalidTransaction(
+            "intrinsic gas too low".to_string(),
+        ));
+    }
+    if gas as u64 > header.gas_limit {
+        return Err(ForkError::InvalidTransaction(
+            "exceeds block gas limit".to_string(),
+        ));
+    }
+    check_fee_cap(tx, header)?;
+    Ok(gas)
+}
+
+/// The most the transaction can cost the sender, its value and all of its gas at the max fee.
+/// The quantities come straight from the request, so they may not fit together.
+fn max_cost(tx: &TypedTransaction, gas: i64) -> Result<U256, ForkError> {
+    let value = tx.value().cloned().unwrap_or_default();
+    get_max_fee_per_gas(tx)
+        .checked_mul(gas.into())
+        .and_then(|fee| fee.checked_add(value))
+        .ok_or_else(|| ForkError::InvalidTransaction("gas * price + value overflows".to_string()))
+}
+
+/// Like geth, a transaction can't be included if it doesn't pay the base fee.
+fn check_fee_cap(tx: &TypedTransaction, header: &PartialHeader) -> Result<(), ForkError> {
+    if let TypedTransaction::Eip1559(tx) = tx {
+        let base_fee_per_gas = header.base_fee_per_gas.unwrap_or_default();
+        if let Some(max_fee_per_gas) = tx.max_fee_per_gas {
+            if max_fee_per_gas < base_fee_per_gas {
+                return Err(ForkError::InvalidTransaction(format!(
+                    "max fee per gas less than block base fee: maxFeePerGas: {}, baseFee: {}",
+                    max_fee_per_gas, base_fee_per_gas
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cassette::Interaction;
+    use ethers::types::TransactionRequest;
+    use serde_json::{json, Value};
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_rebase_replays_local_transactions() {
+        let dir = tempdir().unwrap();
+        let cassette_path = dir.path().join("cassette.ndjson");
+        let from = Address::from_low_u64_be(1);
+        let to = Address::from_low_u64_be(2);
+        let coinbase = Address::from_low_u64_be(3);
+
+        // the archive node has an empty block 101 on top of the fork block
+        let block = |number: u64| Block::<H256> {
+            number: Some(number.into()),
+            hash: Some(H256::from_low_u64_be(number)),
+            author: coinbase,
+            gas_limit: 30_000_000.into(),
+            timestamp: (1000 + number).into(),
+            base_fee_per_gas: Some(1_000_000_000.into()),
+            ..Default::default()
+        };
+        let at_fork = BlockNumber::Number(100.into());
+        let mut interactions = vec![
+            ("eth_chainId", Value::Null, json!("0x1")),
+            (
+                "eth_getBlockByNumber",
+                json!([at_fork, false]),
+                json!(block(100)),
+            ),
+            (
+                "eth_getBlockByNumber",
+                json!([BlockNumber::Number(101.into()), false]),
+                json!(block(101)),
+            ),
+            (
+                "trace_replayBlockTransactions",
+                json!([BlockNumber::Number(101.into()), ["stateDiff"]]),
+                json!([]),
+            ),
+            (
+                "eth_getLogs",
+                json!([Filter::new().select(101u64)]),
+                json!([]),
+            ),
+        ];
+        for (account, balance) in [
+            (from, U256::exp10(18)),
+            (to, 0.into()),
+            (coinbase, 0.into()),
+        ] {
+            interactions.push(("eth_getBalance", json!([account, at_fork]), json!(balance)));
+            interactions.push((
+                "eth_getTransactionCount",
+                json!([account, at_fork]),
+                json!("0x0"),
+            ));
+            interactions.push(("eth_getCode", json!([account, at_fork]), json!("0x")));
+        }
+        let mut file = std::fs::File::create(&cassette_path).unwrap();
+        for (method, params, result) in interactions {
+            let interaction = Interaction {
+                method: method.to_string(),
+                params,
+                result,
+            };
+            writeln!(file, "{}", serde_json::to_string(&interaction).unwrap()).unwrap();
+        }
+        drop(file);
+
+        let provider = ForkedEvmProvider::new_with_cassette(100, "", cassette_path)
+            .await
+            .unwrap();
+        let tx = TransactionRequest::new()
+            .from(from)
+            .to(to)
+            .value(1000)
+            .gas(21000)
+            .gas_price(2_000_000_000u64)
+            .nonce(0);
+        let hash = *provider.send_transaction(tx, None).await.unwrap();
+        assert_eq!(provider.get_block_number().await.unwrap(), 101.into());
+
+        // block 101 is the archive node's now, the transfer isn't on chain so it's mined again
+        provider.rebase(101, true).await.unwrap();
+        assert_eq!(provider.fork_block_number(), 101);
+        assert_eq!(provider.get_block_number().await.unwrap(), 102.into());
+        let chain_block = provider.get_block(101).await.unwrap().unwrap();
+        assert_eq!(chain_block.hash, Some(H256::from_low_u64_be(101)));
+        let receipt = provider
+            .get_transaction_receipt(hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(receipt.block_number, Some(102.into()));
+        assert_eq!(provider.get_balance(to, None).await.unwrap(), 1000.into());
+
+        // a head seen already changes nothing
+        provider.rebase(101, true).await.unwrap();
+        assert_eq!(provider.get_block_number().await.unwrap(), 102.into());
+
+        dir.close().unwrap();
     }
 }
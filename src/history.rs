@@ -0,0 +1,252 @@
+use crate::akula::interface::State;
+use crate::akula::types::{Account, Incarnation, PartialHeader};
+use crate::akula::utils::keccak256;
+use crate::akula::EMPTY_HASH;
+use crate::checkpoint::Overlay;
+use async_trait::async_trait;
+use bytes::Bytes;
+use ethers::types::{Address, H256};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// The local blocks after the fork block, as the accounts each of them changed. The journal of
+/// the state is cleared once a block is mined, the earlier blocks are read from here instead.
+#[derive(Debug, Default)]
+pub struct LocalBlocks {
+    overlays: Vec<Arc<Overlay>>,
+    timestamps: Vec<u64>,
+    // the timestamp the next block is mined with, if it isn't a second after the latest one
+    next_timestamp: Option<u64>,
+    // the code deployed by the local blocks, it's the same at every block
+    code: Arc<RwLock<HashMap<H256, Bytes>>>,
+}
+
+impl LocalBlocks {
+    /// The number of local blocks.
+    pub fn len(&self) -> u64 {
+        self.overlays.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.overlays.is_empty()
+    }
+
+    pub fn push(&mut self, overlay: Overlay, timestamp: u64) {
+        let mut code = self.code.write().unwrap();
+        for account in overlay
+            .values()
+            .filter_map(|account| account.state.as_ref())
+        {
+            if !account.code.0.is_empty() {
+                code.insert(keccak256(&account.code), account.code.0.clone());
+            }
+        }
+        drop(code);
+        self.overlays.push(Arc::new(overlay));
+        self.timestamps.push(timestamp);
+        self.next_timestamp = None;
+    }
+
+    /// The timestamp of the block `offset` blocks after the first one, which is `first` unless
+    /// it's been mined with another one. The blocks after the next one follow it a second apart.
+    pub fn timestamp(&self, offset: u64, first: u64) -> u64 {
+        if let Some(timestamp) = self.timestamps.get(offset as usize) {
+            return *timestamp;
+        }
+
+        let next = match (self.next_timestamp, self.timestamps.last()) {
+            (Some(next), _) => next,
+            (None, Some(latest)) => latest.saturating_add(1),
+            (None, None) => first,
+        };
+        next.saturating_add(offset - self.len())
+    }
+
+    pub fn set_next_timestamp(&mut self, timestamp: u64) {
+        self.next_timestamp = Some(timestamp);
+    }
+
+    pub fn overlays(&self) -> impl Iterator<Item = &Overlay> {
+        self.overlays.iter().map(|overlay| overlay.as_ref())
+    }
+
+    /// The state after the first `count` local blocks, on top of `db` which is the state of
+    /// the fork block.
+    pub fn state_after<S: State>(&self, count: u64, db: S) -> OverlayState<S> {
+        OverlayState {
+            db,
+            overlays: self.overlays[..count as usize].to_vec(),
+            code: self.code.clone(),
+        }
+    }
+}
+
+/// The overlays of the local blocks on top of the state of the fork block, the latest one
+/// first.
+#[derive(Debug)]
+pub struct OverlayState<S> {
+    db: S,
+    overlays: Vec<Arc<Overlay>>,
+    code: Arc<RwLock<HashMap<H256, Bytes>>>,
+}
+
+#[async_trait]
+impl<S: State> State for OverlayState<S> {
+    async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        match self.overlays.iter().rev().find_map(|o| o.get(&address)) {
+            Some(account) => Ok(account.state.as_ref().map(|state| Account {
+                nonce: state.nonce,
+                balance: state.balance,
+                code_hash: if state.code.0.is_empty() {
+                    EMPTY_HASH
+                } else {
+                    keccak256(&state.code)
+                },
+                incarnation: Default::default(),
+            })),
+            None => self.db.read_account(address).await,
+        }
+    }
+
+    async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
+        if let Some(code) = self.code.read().unwrap().get(&code_hash) {
+            return Ok(code.clone());
+        }
+        self.db.read_code(code_hash).await
+    }
+
+    async fn read_storage(
+        &self,
+        address: Address,
+        incarnation: Incarnation,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        for overlay in self.overlays.iter().rev() {
+            let account = match overlay.get(&address) {
+                Some(account) => account,
+                None => continue,
+            };
+            match &account.state {
+                Some(state) => {
+                    if let Some(value) = state.storage.get(&location) {
+                        return Ok(*value);
+                    }
+                    if account.wiped {
+                        return Ok(H256::zero());
+                    }
+                }
+                None => return Ok(H256::zero()),
+            }
+        }
+        self.db.read_storage(address, incarnation, location).await
+    }
+
+    async fn previous_incarnation(&self, address: Address) -> anyhow::Result<Incarnation> {
+        self.db.previous_incarnation(address).await
+    }
+
+    async fn read_block_header(&self, block_number: u64) -> anyhow::Result<Option<PartialHeader>> {
+        self.db.read_block_header(block_number).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::OverlayAccount;
+    use crate::memory_state::{Genesis, MemoryState};
+    use crate::state_dump::GenesisAccount;
+    use crate::tracers::state_diff::AccountState;
+    use address_literal::addr;
+
+    #[tokio::test]
+    async fn test_state_after() {
+        let weth = addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2");
+        let slot = |n| H256::from_low_u64_be(n);
+        let mut genesis = Genesis {
+            dev_accounts: vec![],
+            ..Default::default()
+        };
+        genesis.alloc.insert(
+            weth,
+            GenesisAccount {
+                balance: 100.into(),
+                storage: vec![(slot(1), slot(1)), (slot(2), slot(2))]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+        );
+        let db = Arc::new(MemoryState::new(&genesis));
+
+        let mut blocks = LocalBlocks::default();
+        let changed = |balance: u64, wiped, storage: Vec<(H256, H256)>| {
+            let account = OverlayAccount {
+                wiped,
+                state: Some(AccountState {
+                    balance: balance.into(),
+                    code: vec![0x60, 0x00].into(),
+                    storage: storage.into_iter().collect(),
+                    ..Default::default()
+                }),
+            };
+            vec![(weth, account)].into_iter().collect()
+        };
+        blocks.push(changed(200, false, vec![(slot(1), slot(3))]), 1000);
+        blocks.push(changed(300, true, vec![]), 1001);
+
+        let fork = blocks.state_after(0, db.clone());
+        assert_eq!(
+            fork.read_account(weth).await.unwrap().unwrap().balance,
+            100.into()
+        );
+
+        let first = blocks.state_after(1, db.clone());
+        let account = first.read_account(weth).await.unwrap().unwrap();
+        assert_eq!(account.balance, 200.into());
+        assert_eq!(
+            first.read_code(account.code_hash).await.unwrap(),
+            Bytes::from(vec![0x60, 0x00])
+        );
+        assert_eq!(
+            first
+                .read_storage(weth, Incarnation(0), slot(1))
+                .await
+                .unwrap(),
+            slot(3)
+        );
+        assert_eq!(
+            first
+                .read_storage(weth, Incarnation(0), slot(2))
+                .await
+                .unwrap(),
+            slot(2)
+        );
+
+        // created again, the slots of the fork block are gone
+        let second = blocks.state_after(2, db);
+        assert_eq!(
+            second
+                .read_storage(weth, Incarnation(0), slot(2))
+                .await
+                .unwrap(),
+            H256::zero()
+        );
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let mut blocks = LocalBlocks::default();
+        assert_eq!(blocks.timestamp(0, 1000), 1000);
+        assert_eq!(blocks.timestamp(2, 1000), 1002);
+
+        blocks.push(Overlay::default(), 1000);
+        blocks.set_next_timestamp(1012);
+        assert_eq!(blocks.timestamp(1, 1000), 1012);
+        assert_eq!(blocks.timestamp(2, 1000), 1013);
+
+        blocks.push(Overlay::default(), 1012);
+        assert_eq!(blocks.timestamp(0, 1000), 1000);
+        assert_eq!(blocks.timestamp(2, 1000), 1013);
+    }
+}
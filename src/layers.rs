@@ -0,0 +1,597 @@
+//! Layers implementing `State`, which compose into the backend of
+//! `ForkedEvmProvider::from_state()`. Each one wraps the layer below it, e.g. a `MemoryCache`
+//! over a `Recording` over a `Remote` keeps the hot accounts in memory and records the others
+//! into a database as they're read from the archive node.
+
+use crate::akula::interface::State;
+use crate::akula::types::{Account, Incarnation, PartialHeader};
+use crate::error::ForkError;
+use crate::forked_backend::Web3RemoteState;
+use crate::sqlite_backend::{SqliteBackend, SqliteDumper};
+use async_trait::async_trait;
+use bytes::Bytes;
+use ethers::types::{Address, H256};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::Mutex;
+
+/// A layer shared with the code keeping it, e.g. to close a `ReadOnlyGuard`.
+#[async_trait]
+impl<S: State + ?Sized> State for Arc<S> {
+    async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        (**self).read_account(address).await
+    }
+
+    async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
+        (**self).read_code(code_hash).await
+    }
+
+    async fn read_storage(
+        &self,
+        address: Address,
+        incarnation: Incarnation,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        (**self).read_storage(address, incarnation, location).await
+    }
+
+    async fn previous_incarnation(&self, address: Address) -> anyhow::Result<Incarnation> {
+        (**self).previous_incarnation(address).await
+    }
+
+    async fn read_block_header(&self, block_number: u64) -> anyhow::Result<Option<PartialHeader>> {
+        (**self).read_block_header(block_number).await
+    }
+}
+
+/// The state of the archive node at a block, read over its websocket.
+#[derive(Clone, Debug)]
+pub struct Remote(Web3RemoteState);
+
+impl Remote {
+    pub async fn connect(block_number: u64, wss_url: &str) -> anyhow::Result<Self> {
+        Ok(Self(Web3RemoteState::new(block_number, wss_url).await?))
+    }
+
+    /// Records the requests and responses into a cassette of JSON lines, see
+    /// `ForkedEvmProvider::new_with_cassette()`.
+    pub async fn record<P: AsRef<Path>>(
+        block_number: u64,
+        wss_url: &str,
+        cassette_path: P,
+    ) -> anyhow::Result<Self> {
+        Ok(Self(
+            Web3RemoteState::record(block_number, wss_url, cassette_path).await?,
+        ))
+    }
+
+    /// Moves the cassette of `record()` into place, until then it's a `.partial` file.
+    pub fn finish_recording(&self) -> anyhow::Result<()> {
+        self.0.finish_recording()
+    }
+
+    /// Replays a cassette written by `record()`, nothing is sent out.
+    pub async fn replay<P: AsRef<Path>>(
+        block_number: u64,
+        cassette_path: P,
+    ) -> anyhow::Result<Self> {
+        Ok(Self(
+            Web3RemoteState::replay(block_number, cassette_path).await?,
+        ))
+    }
+}
+
+#[async_trait]
+impl State for Remote {
+    async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        self.0.read_account(address).await
+    }
+
+    async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
+        self.0.read_code(code_hash).await
+    }
+
+    async fn read_storage(
+        &self,
+        address: Address,
+        incarnation: Incarnation,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        self.0.read_storage(address, incarnation, location).await
+    }
+
+    async fn read_block_header(&self, block_number: u64) -> anyhow::Result<Option<PartialHeader>> {
+        self.0.read_block_header(block_number).await
+    }
+}
+
+/// The state recorded into a database by `Recording`, or by the tee mode of
+/// `ForkedEvmProvider::new()`. What it hasn't recorded is a `ForkError::BackendMiss`.
+#[derive(Debug)]
+pub struct SqliteCache(Mutex<SqliteBackend>);
+
+impl SqliteCache {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Ok(Self(Mutex::new(SqliteBackend::new(path)?)))
+    }
+}
+
+#[async_trait]
+impl State for SqliteCache {
+    async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        self.0.lock().await.read_account(address)
+    }
+
+    async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
+        self.0.lock().await.read_code(code_hash)
+    }
+
+    async fn read_storage(
+        &self,
+        address: Address,
+        incarnation: Incarnation,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        self.0
+            .lock()
+            .await
+            .read_storage(address, incarnation, location)
+    }
+
+    async fn read_block_header(&self, block_number: u64) -> anyhow::Result<Option<PartialHeader>> {
+        self.0.lock().await.read_block_header(block_number)
+    }
+}
+
+/// Records what's read from the layer below into a database, which `SqliteCache` reads
+/// back. The database is created anew.
+#[derive(Debug)]
+pub struct Recording<S> {
+    inner: S,
+    dumper: Mutex<SqliteDumper>,
+}
+
+impl<S: State> Recording<S> {
+    pub fn new<P: AsRef<Path>>(inner: S, path: P) -> anyhow::Result<Self> {
+        Ok(Self {
+            inner,
+            dumper: Mutex::new(SqliteDumper::new(path)?),
+        })
+    }
+}
+
+#[async_trait]
+impl<S: State> State for Recording<S> {
+    async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        let ret = self.inner.read_account(address).await?;
+
+        if let Some(account) = &ret {
+            let code = self.inner.read_code(account.code_hash).await?;
+            self.dumper.lock().await.dump_address(
+                address,
+                account.balance,
+                account.nonce.into(),
+                code.to_vec(),
+            )?;
+        }
+
+        Ok(ret)
+    }
+
+    async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
+        // already recorded by read_account()
+        self.inner.read_code(code_hash).await
+    }
+
+    async fn read_storage(
+        &self,
+        address: Address,
+        incarnation: Incarnation,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        let ret = self
+            .inner
+            .read_storage(address, incarnation, location)
+            .await?;
+        self.dumper
+            .lock()
+            .await
+            .dump_storage(address, location, ret)?;
+        Ok(ret)
+    }
+
+    async fn previous_incarnation(&self, address: Address) -> anyhow::Result<Incarnation> {
+        self.inner.previous_incarnation(address).await
+    }
+
+    async fn read_block_header(&self, block_number: u64) -> anyhow::Result<Option<PartialHeader>> {
+        let ret = self.inner.read_block_header(block_number).await?;
+
+        if let Some(header) = &ret {
+            self.dumper.lock().await.dump_block_header(
+                block_number,
+                header.hash,
+                header.base_fee_per_gas.unwrap_or_default(),
+                header.timestamp,
+                header.gas_limit,
+                header.difficulty,
+                header.beneficiary,
+            )?;
+        }
+
+        Ok(ret)
+    }
+}
+
+/// The least recently used entries are evicted first.
+#[derive(Debug)]
+struct Lru<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    // the entries by the tick they were last used at
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.order.remove(&used);
+        } else if self.entries.len() > self.capacity {
+            if let Some(oldest) = self.order.keys().next().copied() {
+                let key = self.order.remove(&oldest).unwrap();
+                self.entries.remove(&key);
+            }
+        }
+        self.order.insert(self.tick, key);
+    }
+}
+
+/// Keeps up to `capacity` accounts, codes and storage slots each of the layer below in memory.
+/// Only what was read successfully is kept.
+#[derive(Debug)]
+pub struct MemoryCache<S> {
+    inner: S,
+    accounts: StdMutex<Lru<Address, Option<Account>>>,
+    code: StdMutex<Lru<H256, Bytes>>,
+    storage: StdMutex<Lru<(Address, H256), H256>>,
+}
+
+impl<S: State> MemoryCache<S> {
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            accounts: StdMutex::new(Lru::new(capacity)),
+            code: StdMutex::new(Lru::new(capacity)),
+            storage: StdMutex::new(Lru::new(capacity)),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: State> State for MemoryCache<S> {
+    async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        if let Some(account) = self.accounts.lock().unwrap().get(&address) {
+            return Ok(account);
+        }
+        let account = self.inner.read_account(address).await?;
+        self.accounts
+            .lock()
+            .unwrap()
+            .insert(address, account.clone());
+        Ok(account)
+    }
+
+    async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
+        if let Some(code) = self.code.lock().unwrap().get(&code_hash) {
+            return Ok(code);
+        }
+        let code = self.inner.read_code(code_hash).await?;
+        self.code.lock().unwrap().insert(code_hash, code.clone());
+        Ok(code)
+    }
+
+    async fn read_storage(
+        &self,
+        address: Address,
+        incarnation: Incarnation,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        if let Some(value) = self.storage.lock().unwrap().get(&(address, location)) {
+            return Ok(value);
+        }
+        let value = self
+            .inner
+            .read_storage(address, incarnation, location)
+            .await?;
+        self.storage
+            .lock()
+            .unwrap()
+            .insert((address, location), value);
+        Ok(value)
+    }
+
+    async fn previous_incarnation(&self, address: Address) -> anyhow::Result<Incarnation> {
+        self.inner.previous_incarnation(address).await
+    }
+
+    async fn read_block_header(&self, block_number: u64) -> anyhow::Result<Option<PartialHeader>> {
+        self.inner.read_block_header(block_number).await
+    }
+}
+
+/// Reads from `first`, and from `second` what `first` misses, e.g. a `SqliteCache` in front
+/// of a `Remote`. Other errors aren't retried.
+#[derive(Debug)]
+pub struct Fallback<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: State, B: State> Fallback<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+fn is_miss<T>(result: &anyhow::Result<T>) -> bool {
+    matches!(result, Err(e) if matches!(e.downcast_ref::<ForkError>(), Some(ForkError::BackendMiss(_))))
+}
+
+#[async_trait]
+impl<A: State, B: State> State for Fallback<A, B> {
+    async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        match self.first.read_account(address).await {
+            ret if is_miss(&ret) => self.second.read_account(address).await,
+            ret => ret,
+        }
+    }
+
+    async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
+        match self.first.read_code(code_hash).await {
+            ret if is_miss(&ret) => self.second.read_code(code_hash).await,
+            ret => ret,
+        }
+    }
+
+    async fn read_storage(
+        &self,
+        address: Address,
+        incarnation: Incarnation,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        match self
+            .first
+            .read_storage(address, incarnation, location)
+            .await
+        {
+            ret if is_miss(&ret) => {
+                self.second
+                    .read_storage(address, incarnation, location)
+                    .await
+            }
+            ret => ret,
+        }
+    }
+
+    async fn read_block_header(&self, block_number: u64) -> anyhow::Result<Option<PartialHeader>> {
+        match self.first.read_block_header(block_number).await {
+            ret if is_miss(&ret) => self.second.read_block_header(block_number).await,
+            ret => ret,
+        }
+    }
+}
+
+/// Lets reads through to the layer below until it's closed, then they're all
+/// `ForkError::BackendMiss`. Tests close it to make sure nothing more reaches the archive node,
+/// or gets recorded.
+#[derive(Debug)]
+pub struct ReadOnlyGuard<S> {
+    inner: S,
+    closed: AtomicBool,
+}
+
+impl<S: State> ReadOnlyGuard<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn open(&self) {
+        self.closed.store(false, Ordering::SeqCst);
+    }
+
+    fn check(&self, what: impl FnOnce() -> String) -> anyhow::Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ForkError::BackendMiss(what()).into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S: State> State for ReadOnlyGuard<S> {
+    async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        self.check(|| format!("account {:?}", address))?;
+        self.inner.read_account(address).await
+    }
+
+    async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
+        self.check(|| format!("code of hash {:?}", code_hash))?;
+        self.inner.read_code(code_hash).await
+    }
+
+    async fn read_storage(
+        &self,
+        address: Address,
+        incarnation: Incarnation,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        self.check(|| format!("storage {:?} of {:?}", location, address))?;
+        self.inner
+            .read_storage(address, incarnation, location)
+            .await
+    }
+
+    async fn previous_incarnation(&self, address: Address) -> anyhow::Result<Incarnation> {
+        self.inner.previous_incarnation(address).await
+    }
+
+    async fn read_block_header(&self, block_number: u64) -> anyhow::Result<Option<PartialHeader>> {
+        self.check(|| format!("block {}", block_number))?;
+        self.inner.read_block_header(block_number).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_state::{Genesis, MemoryState};
+    use crate::state_dump::GenesisAccount;
+    use crate::ForkedEvmProvider;
+    use address_literal::addr;
+    use ethers::providers::Middleware;
+    use tempfile::tempdir;
+
+    fn genesis_state() -> MemoryState {
+        let mut genesis = Genesis {
+            dev_accounts: vec![],
+            ..Default::default()
+        };
+        genesis.alloc.insert(
+            addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+            GenesisAccount {
+                balance: 100.into(),
+                storage: vec![(H256::from_low_u64_be(1), H256::from_low_u64_be(2))]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+        );
+        MemoryState::new(&genesis)
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(2);
+        lru.insert(1, "a");
+        lru.insert(2, "b");
+        assert_eq!(lru.get(&1), Some("a"));
+        // 2 is the least recently used
+        lru.insert(3, "c");
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.get(&1), Some("a"));
+        assert_eq!(lru.get(&3), Some("c"));
+    }
+
+    #[tokio::test]
+    async fn test_record_and_read_back() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("recording.db");
+        let weth = addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2");
+        let slot = H256::from_low_u64_be(1);
+
+        let guard = Arc::new(ReadOnlyGuard::new(
+            Recording::new(genesis_state(), &file_path).unwrap(),
+        ));
+        let cache = MemoryCache::new(guard.clone(), 16);
+        assert!(cache.read_account(weth).await.unwrap().is_some());
+        assert_eq!(
+            cache
+                .read_storage(weth, Incarnation(0), slot)
+                .await
+                .unwrap(),
+            H256::from_low_u64_be(2)
+        );
+
+        // nothing reaches the recording once closed, but what's cached is still there
+        guard.close();
+        assert!(cache.read_account(weth).await.is_ok());
+        assert!(is_miss(&cache.read_account(Address::zero()).await));
+
+        // what was recorded, and only that, is read back
+        let state = Fallback::new(SqliteCache::open(&file_path).unwrap(), genesis_state());
+        let account = state.read_account(weth).await.unwrap().unwrap();
+        assert_eq!(account.balance, 100.into());
+        assert!(is_miss(
+            &SqliteCache::open(&file_path)
+                .unwrap()
+                .read_account(Address::zero())
+                .await
+        ));
+        assert!(state.read_account(Address::zero()).await.unwrap().is_none());
+
+        dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_provider_reads_through_layers() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("shadow.db");
+        let weth = addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2");
+        let slot = H256::from_low_u64_be(1);
+
+        // the database only has the account, its slots and the headers come from the genesis
+        let mut dumper = SqliteDumper::new(&file_path).unwrap();
+        dumper
+            .dump_address(weth, 7.into(), 0.into(), vec![])
+            .unwrap();
+        drop(dumper);
+
+        let state = MemoryCache::new(
+            Fallback::new(SqliteCache::open(&file_path).unwrap(), genesis_state()),
+            16,
+        );
+        let provider = ForkedEvmProvider::from_state(0, state).await.unwrap();
+        assert_eq!(provider.get_block_number().await.unwrap(), 0.into());
+        assert_eq!(provider.get_balance(weth, None).await.unwrap(), 7.into());
+        assert_eq!(
+            provider.get_storage_at(weth, slot, None).await.unwrap(),
+            H256::from_low_u64_be(2)
+        );
+        assert_eq!(
+            provider.get_balance(Address::zero(), None).await.unwrap(),
+            0.into()
+        );
+
+        // the local changes stay above the layers
+        provider.set_balance(weth, 9).await.unwrap();
+        assert_eq!(provider.get_balance(weth, None).await.unwrap(), 9.into());
+        let recorded = SqliteCache::open(&file_path).unwrap();
+        let account = recorded.read_account(weth).await.unwrap().unwrap();
+        assert_eq!(account.balance, 7.into());
+
+        dir.close().unwrap();
+    }
+}
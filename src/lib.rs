@@ -1,7 +1,35 @@
+mod access_list;
 pub mod akula;
+mod bundle;
+mod cassette;
+mod checkpoint;
+mod ens;
+mod error;
+mod estimate_gas;
+mod fork_manager;
 mod forked_backend;
 mod forked_evm_provider;
+mod history;
+pub mod layers;
+mod memory_state;
+mod mempool;
+mod replay;
+mod revert;
+mod rpc;
+mod signed_transaction;
 mod sqlite_backend;
+mod state_dump;
 mod state_muxer;
+mod subscriptions;
+pub mod tracers;
 
+pub use access_list::AccessListWithGasComparison;
+pub use bundle::{BundleSimulation, BundleTransactionResult};
+pub use error::ForkError;
+pub use fork_manager::ForkManager;
 pub use forked_evm_provider::ForkedEvmProvider;
+pub use memory_state::{dev_accounts, Genesis, DEV_PRIVATE_KEYS};
+pub use replay::{Mismatch, ReplayReport, StorageMismatch, TransactionReplay};
+pub use revert::{RevertDecoder, RevertError, RevertReason};
+pub use rpc::RpcHandler;
+pub use state_dump::{parse_alloc, GenesisAccount, GenesisAlloc};
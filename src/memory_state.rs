@@ -0,0 +1,290 @@
+use crate::akula::interface::State;
+use crate::akula::types::{Account, Incarnation, PartialHeader};
+use crate::akula::utils::keccak256;
+use crate::akula::EMPTY_HASH;
+use crate::state_dump::{parse_alloc, parse_quantity, parse_u64, GenesisAlloc};
+use async_trait::async_trait;
+use bytes::Bytes;
+use ethers::types::{Address, H256, U256};
+use secp256k1::{PublicKey, SecretKey, SECP256K1};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The keys of anvil's and hardhat's dev accounts, derived from the "test test ... junk"
+/// mnemonic.
+pub const DEV_PRIVATE_KEYS: [&str; 10] = [
+    "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
+    "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d",
+    "5de4111afa1a4b94908f83103eb1f1706367c2e68ca870fc3fb9a804cdab365a",
+    "7c852118294e51e653712a81e05800f419141751be58f605c371e15141b007a6",
+    "47e179ec197488593b187f80a00eb0da91f1b9d0b13f8733639f19c30a34926a",
+    "8b3a350cf5c34c9194ca85829a2df0ec3153be0318b5e2d3348e872092edffba",
+    "92db14e403b83dfe3df233f83dfa3a0d7096f21ca9b0d6d6b8d88b2b4ec1564e",
+    "4bbbf85ce3377467afe5d46f804f221813b2bb87f24d81f60f1fcdbf7cbf4356",
+    "dbda1821b80551c9d65939329250298aa3472ba22feea921c0cf5d620ea67b97",
+    "2a871d0798f97d79848a013d4936a73bf4cc922c825d33c1cf7073dff6d409c6",
+];
+
+/// The addresses of `DEV_PRIVATE_KEYS`.
+pub fn dev_accounts() -> Vec<Address> {
+    DEV_PRIVATE_KEYS
+        .iter()
+        .map(|key| {
+            let key = SecretKey::from_slice(&hex::decode(key).unwrap()).unwrap();
+            let public = PublicKey::from_secret_key(SECP256K1, &key);
+            Address::from_slice(&keccak256(&public.serialize_uncompressed()[1..]).as_bytes()[12..])
+        })
+        .collect()
+}
+
+/// The chain `ForkedEvmProvider::new_in_memory()` starts. The default one is an empty London
+/// chain with anvil's chain id and dev accounts, its timestamp is 0 like geth's.
+#[derive(Clone, Debug)]
+pub struct Genesis {
+    pub alloc: GenesisAlloc,
+    pub chain_id: u64,
+    pub timestamp: u64,
+    pub gas_limit: u64,
+    pub base_fee_per_gas: U256,
+    pub beneficiary: Address,
+    /// Funded with `dev_balance` unless they're in `alloc`, they're also what
+    /// `get_accounts()` returns.
+    pub dev_accounts: Vec<Address>,
+    pub dev_balance: U256,
+}
+
+impl Default for Genesis {
+    fn default() -> Self {
+        Self {
+            alloc: GenesisAlloc::new(),
+            chain_id: 31337,
+            timestamp: 0,
+            gas_limit: 30_000_000,
+            base_fee_per_gas: 1_000_000_000u64.into(),
+            beneficiary: Address::zero(),
+            dev_accounts: dev_accounts(),
+            // 10000 ether
+            dev_balance: U256::exp10(22),
+        }
+    }
+}
+
+impl Genesis {
+    /// Reads geth's genesis file, or only its `alloc`. There are no dev accounts then, the
+    /// accounts of the file are the only ones.
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let value: Value = serde_json::from_str(json)?;
+        let mut genesis = Self {
+            alloc: parse_alloc(json)?,
+            dev_accounts: vec![],
+            ..Default::default()
+        };
+
+        if let Some(chain_id) = value["config"].get("chainId") {
+            genesis.chain_id = parse_u64(chain_id)?;
+        }
+        if let Some(timestamp) = value.get("timestamp") {
+            genesis.timestamp = parse_u64(timestamp)?;
+        }
+        if let Some(gas_limit) = value.get("gasLimit") {
+            genesis.gas_limit = parse_u64(gas_limit)?;
+        }
+        if let Some(base_fee_per_gas) = value.get("baseFeePerGas") {
+            genesis.base_fee_per_gas = parse_quantity(base_fee_per_gas)?;
+        }
+        if let Some(coinbase) = value.get("coinbase") {
+            genesis.beneficiary = serde_json::from_value(coinbase.clone())?;
+        }
+        Ok(genesis)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// Starts the chain now rather than at its timestamp, like anvil does.
+    pub fn with_current_time(mut self) -> Self {
+        self.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        self
+    }
+}
+
+/// A chain which only lives in memory, there's no archive node or database behind it. It's
+/// the genesis block, the local blocks are built on top of it like on a fork block.
+#[derive(Debug, Default)]
+pub struct MemoryState {
+    accounts: HashMap<Address, Account>,
+    code: HashMap<H256, Bytes>,
+    storage: HashMap<(Address, H256), H256>,
+    header: PartialHeader,
+}
+
+impl MemoryState {
+    pub fn new(genesis: &Genesis) -> Self {
+        let mut state = Self {
+            header: PartialHeader {
+                number: 0,
+                gas_limit: genesis.gas_limit,
+                timestamp: genesis.timestamp,
+                base_fee_per_gas: Some(genesis.base_fee_per_gas),
+                // there's no real block behind it, anything unique to the chain does
+                hash: keccak256(
+                    &[
+                        genesis.chain_id.to_be_bytes(),
+                        genesis.timestamp.to_be_bytes(),
+                    ]
+                    .concat(),
+                ),
+                beneficiary: genesis.beneficiary,
+                chain_id: genesis.chain_id,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        for address in genesis.dev_accounts.iter() {
+            state.accounts.insert(
+                *address,
+                Account {
+                    balance: genesis.dev_balance,
+                    code_hash: EMPTY_HASH,
+                    ..Default::default()
+                },
+            );
+        }
+
+        for (address, account) in genesis.alloc.iter() {
+            let code_hash = keccak256(&account.code);
+            state.code.insert(code_hash, account.code.0.clone());
+            state.accounts.insert(
+                *address,
+                Account {
+                    nonce: account.nonce,
+                    balance: account.balance,
+                    code_hash,
+                    incarnation: Default::default(),
+                },
+            );
+            for (key, value) in account.storage.iter() {
+                state.storage.insert((*address, *key), *value);
+            }
+        }
+
+        state
+    }
+}
+
+#[async_trait]
+impl State for MemoryState {
+    async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        Ok(self.accounts.get(&address).cloned())
+    }
+
+    async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
+        Ok(self.code.get(&code_hash).cloned().unwrap_or_default())
+    }
+
+    async fn read_storage(
+        &self,
+        address: Address,
+        _incarnation: Incarnation,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        Ok(self
+            .storage
+            .get(&(address, location))
+            .copied()
+            .unwrap_or_default())
+    }
+
+    /// The genesis block and the first block after it, which the local blocks follow.
+    async fn read_block_header(&self, block_number: u64) -> anyhow::Result<Option<PartialHeader>> {
+        match block_number {
+            0 => Ok(Some(self.header.clone())),
+            1 => {
+                let mut header = self.header.clone();
+                header.number = 1;
+                header.timestamp += 1;
+                header.hash = keccak256(&[self.header.hash.as_bytes(), &[1]].concat());
+                Ok(Some(header))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use address_literal::addr;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_memory_state() {
+        let genesis = json!({
+            "config": { "chainId": 5 },
+            "timestamp": "0x10",
+            "alloc": {
+                "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2": {
+                    "balance": "0x64",
+                    "code": "0x6000",
+                    "storage": {
+                        "0x0000000000000000000000000000000000000000000000000000000000000001": "0x0000000000000000000000000000000000000000000000000000000000000002"
+                    }
+                }
+            }
+        });
+        let genesis = Genesis::from_json(&genesis.to_string()).unwrap();
+        assert_eq!(genesis.chain_id, 5);
+        assert!(genesis.dev_accounts.is_empty());
+        assert!(
+            Genesis::from_json(r#"{ "alloc": {}, "timestamp": "0x10000000000000000" }"#).is_err()
+        );
+
+        let state = MemoryState::new(&genesis);
+        let weth = addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2");
+        let account = state.read_account(weth).await.unwrap().unwrap();
+        assert_eq!(account.balance, 100.into());
+        assert_eq!(
+            state.read_code(account.code_hash).await.unwrap(),
+            Bytes::from(vec![0x60, 0x00])
+        );
+        assert_eq!(
+            state
+                .read_storage(weth, Incarnation(0), H256::from_low_u64_be(1))
+                .await
+                .unwrap(),
+            H256::from_low_u64_be(2)
+        );
+        assert!(state.read_account(Address::zero()).await.unwrap().is_none());
+
+        let header = state.read_block_header(1).await.unwrap().unwrap();
+        assert_eq!(header.timestamp, 17);
+        assert_eq!(header.chain_id, 5);
+        assert!(state.read_block_header(2).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dev_accounts() {
+        let accounts = dev_accounts();
+        assert_eq!(
+            accounts[0],
+            addr!("0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266")
+        );
+        assert_eq!(
+            accounts[9],
+            addr!("0xa0ee7a142d267c1f36714e4a8f75612f20a79720")
+        );
+
+        let genesis = Genesis::default();
+        assert_eq!(genesis.timestamp, 0);
+        let state = MemoryState::new(&genesis);
+        let account = state.read_account(accounts[0]).await.unwrap().unwrap();
+        assert_eq!(account.balance, genesis.dev_balance);
+    }
+}
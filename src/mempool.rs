@@ -0,0 +1,288 @@
+use crate::akula::utils::{
+    get_effective_gas_price, get_max_fee_per_gas, get_max_priority_fee_per_gas, get_sender,
+};
+use crate::error::ForkError;
+use crate::signed_transaction::SignedTransaction;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{Address, NameOrAddress, Transaction, H256, U256};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
+
+/// The fee increase a replacement needs, in percent, geth's default `--txpool.pricebump`.
+const PRICE_BUMP: u64 = 10;
+
+/// A transaction waiting to be mined, its sender and nonce are always set.
+#[derive(Clone, Debug)]
+pub struct PoolTransaction {
+    pub tx: TypedTransaction,
+    pub hash: H256,
+    pub signed: Option<SignedTransaction>,
+    // the order of arrival, which breaks the ties between equal tips
+    seq: u64,
+}
+
+impl PoolTransaction {
+    pub fn new(tx: TypedTransaction, hash: H256, signed: Option<SignedTransaction>) -> Self {
+        Self {
+            tx,
+            hash,
+            signed,
+            seq: 0,
+        }
+    }
+
+    pub fn from(&self) -> Address {
+        get_sender(&self.tx)
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.tx.nonce().map(|n| n.as_u64()).unwrap_or_default()
+    }
+
+    /// The transaction as `eth_getTransactionByHash` returns it before it's mined.
+    pub fn transaction(&self) -> Transaction {
+        let (v, r, s) = match &self.signed {
+            Some(signed) => (signed.v, signed.r, signed.s),
+            None => (0, U256::zero(), U256::zero()),
+        };
+
+        Transaction {
+            hash: self.hash,
+            nonce: self.nonce().into(),
+            from: self.from(),
+            to: match self.tx.to() {
+                Some(NameOrAddress::Address(to)) => Some(*to),
+                _ => None,
+            },
+            value: self.tx.value().cloned().unwrap_or_default(),
+            gas: self.tx.gas().cloned().unwrap_or_default(),
+            input: self.tx.data().cloned().unwrap_or_default(),
+            v: v.into(),
+            r,
+            s,
+            ..Default::default()
+        }
+    }
+}
+
+/// The transactions sent but not mined yet, by sender and nonce. Like geth, the ones whose
+/// nonces follow the account nonce without a gap are pending, the others are queued.
+#[derive(Debug, Default)]
+pub struct Mempool {
+    transactions: BTreeMap<Address, BTreeMap<u64, PoolTransaction>>,
+    seq: u64,
+}
+
+impl Mempool {
+    /// Adds the transaction, a transaction of the same sender and nonce is only replaced if
+    /// both of its fees are bumped by 10%. Returns the replaced one.
+    pub fn insert(
+        &mut self,
+        tx: TypedTransaction,
+        hash: H256,
+        signed: Option<SignedTransaction>,
+    ) -> Result<Option<PoolTransaction>, ForkError> {
+        let tx = PoolTransaction {
+            seq: self.seq,
+            ..PoolTransaction::new(tx, hash, signed)
+        };
+
+        let by_nonce = self.transactions.entry(tx.from()).or_default();
+        if let Some(old) = by_nonce.get(&tx.nonce()) {
+            if !is_bumped(&old.tx, &tx.tx) {
+                return Err(ForkError::InvalidTransaction(
+                    "replacement transaction underpriced".to_string(),
+                ));
+            }
+        }
+
+        self.seq += 1;
+        Ok(by_nonce.insert(tx.nonce(), tx))
+    }
+
+    pub fn remove(&mut self, from: Address, nonce: u64) -> Option<PoolTransaction> {
+        let by_nonce = self.transactions.get_mut(&from)?;
+        let tx = by_nonce.remove(&nonce);
+        if by_nonce.is_empty() {
+            self.transactions.remove(&from);
+        }
+        tx
+    }
+
+    pub fn get(&self, hash: H256) -> Option<&PoolTransaction> {
+        self.transactions
+            .values()
+            .flat_map(|by_nonce| by_nonce.values())
+            .find(|tx| tx.hash == hash)
+    }
+
+    pub fn senders(&self) -> Vec<Address> {
+        self.transactions.keys().cloned().collect()
+    }
+
+    /// The nonce of the next transaction of the sender, after its pending ones.
+    pub fn next_nonce(&self, from: Address, account_nonce: u64) -> u64 {
+        let mut nonce = account_nonce;
+        if let Some(by_nonce) = self.transactions.get(&from) {
+            while by_nonce.contains_key(&nonce) {
+                nonce += 1;
+            }
+        }
+        nonce
+    }
+
+    /// Drops the transactions whose nonces have been used in the meantime.
+    pub fn prune(&mut self, account_nonces: &HashMap<Address, u64>) {
+        for (from, by_nonce) in self.transactions.iter_mut() {
+            let account_nonce = account_nonces.get(from).cloned().unwrap_or_default();
+            *by_nonce = by_nonce.split_off(&account_nonce);
+        }
+        self.transactions.retain(|_, by_nonce| !by_nonce.is_empty());
+    }
+
+    /// The pending and the queued transactions, given the account nonces of their senders.
+    pub fn content(
+        &self,
+        account_nonces: &HashMap<Address, u64>,
+    ) -> (Vec<PoolTransaction>, Vec<PoolTransaction>) {
+        let mut pending = vec![];
+        let mut queued = vec![];
+
+        for (from, by_nonce) in self.transactions.iter() {
+            let account_nonce = account_nonces.get(from).cloned().unwrap_or_default();
+            let next_nonce = self.next_nonce(*from, account_nonce);
+            for (nonce, tx) in by_nonce.iter() {
+                if *nonce < next_nonce {
+                    pending.push(tx.clone());
+                } else {
+                    queued.push(tx.clone());
+                }
+            }
+        }
+
+        (pending, queued)
+    }
+
+    /// The pending transactions in the order a block includes them, like geth's miner: the
+    /// highest effective tip goes first, but the transactions of a sender stay in nonce order.
+    pub fn best(
+        &self,
+        account_nonces: &HashMap<Address, u64>,
+        base_fee_per_gas: U256,
+    ) -> Vec<PoolTransaction> {
+        let (pending, _) = self.content(account_nonces);
+
+        let mut by_sender = HashMap::<Address, VecDeque<PoolTransaction>>::new();
+        for tx in pending {
+            by_sender.entry(tx.from()).or_default().push_back(tx);
+        }
+
+        let tip = |tx: &TypedTransaction| {
+            get_effective_gas_price(tx, base_fee_per_gas).saturating_sub(base_fee_per_gas)
+        };
+        // the first transaction of every sender, by tip then by arrival
+        let mut heads = BinaryHeap::new();
+        for (from, txs) in by_sender.iter() {
+            let tx = &txs[0];
+            heads.push((tip(&tx.tx), Reverse(tx.seq), *from));
+        }
+
+        let mut ordered = vec![];
+        while let Some((_, _, from)) = heads.pop() {
+            let txs = by_sender.get_mut(&from).unwrap();
+            ordered.extend(txs.pop_front());
+            if let Some(next) = txs.front() {
+                heads.push((tip(&next.tx), Reverse(next.seq), from));
+            }
+        }
+        ordered
+    }
+}
+
+fn is_bumped(old: &TypedTransaction, new: &TypedTransaction) -> bool {
+    // nothing is higher than a fee which overflows once bumped
+    let bumped = |new: U256, old: U256| {
+        old.checked_mul((100 + PRICE_BUMP).into())
+            .map_or(false, |old| new >= old / 100)
+    };
+
+    bumped(get_max_fee_per_gas(new), get_max_fee_per_gas(old))
+        && bumped(
+            get_max_priority_fee_per_gas(new),
+            get_max_priority_fee_per_gas(old),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::TransactionRequest;
+
+    fn tx(from: u64, nonce: u64, gas_price: u64) -> TypedTransaction {
+        TransactionRequest::new()
+            .from(Address::from_low_u64_be(from))
+            .nonce(nonce)
+            .gas_price(gas_price)
+            .into()
+    }
+
+    fn insert(mempool: &mut Mempool, tx: TypedTransaction) -> Result<H256, ForkError> {
+        let hash = H256::from_low_u64_be(mempool.seq);
+        mempool.insert(tx, hash, None).map(|_| hash)
+    }
+
+    #[test]
+    fn test_best_order() {
+        let mut mempool = Mempool::default();
+        let victim = insert(&mut mempool, tx(1, 0, 20)).unwrap();
+        let victim_next = insert(&mut mempool, tx(1, 1, 50)).unwrap();
+        let bot = insert(&mut mempool, tx(2, 0, 30)).unwrap();
+        let bot_next = insert(&mut mempool, tx(2, 1, 10)).unwrap();
+        // same tip as the victim, but later
+        let other = insert(&mut mempool, tx(3, 0, 20)).unwrap();
+        // there's a gap in the nonces
+        let queued = insert(&mut mempool, tx(3, 2, 100)).unwrap();
+
+        let nonces = mempool.senders().into_iter().map(|a| (a, 0)).collect();
+        let order = mempool
+            .best(&nonces, 5.into())
+            .iter()
+            .map(|tx| tx.hash)
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![bot, victim, victim_next, other, bot_next]);
+
+        let (pending, queued_txs) = mempool.content(&nonces);
+        assert_eq!(pending.len(), 5);
+        assert_eq!(queued_txs[0].hash, queued);
+        assert_eq!(mempool.next_nonce(Address::from_low_u64_be(3), 0), 1);
+    }
+
+    #[test]
+    fn test_replacement() {
+        let mut mempool = Mempool::default();
+        insert(&mut mempool, tx(1, 0, 100)).unwrap();
+
+        assert!(matches!(
+            insert(&mut mempool, tx(1, 0, 109)),
+            Err(ForkError::InvalidTransaction(_))
+        ));
+        let replacement = insert(&mut mempool, tx(1, 0, 110)).unwrap();
+
+        let nonces = vec![(Address::from_low_u64_be(1), 0)].into_iter().collect();
+        let best = mempool.best(&nonces, U256::zero());
+        assert_eq!(best.len(), 1);
+        assert_eq!(best[0].hash, replacement);
+
+        // a fee this high can't be bumped, and doesn't overflow either
+        let max: TypedTransaction = TransactionRequest::new()
+            .from(Address::from_low_u64_be(2))
+            .nonce(0)
+            .gas_price(U256::max_value())
+            .into();
+        insert(&mut mempool, max.clone()).unwrap();
+        assert!(matches!(
+            insert(&mut mempool, max),
+            Err(ForkError::InvalidTransaction(_))
+        ));
+    }
+}
@@ -0,0 +1,284 @@
+use ethers::types::transaction::eip1559::Eip1559TransactionRequest;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::transaction::eip2930::Eip2930TransactionRequest;
+use ethers::types::{
+    Address, Log, Transaction, TransactionReceipt, TransactionRequest, H256, U256, U64,
+};
+use serde::Serialize;
+
+/// A difference between the replayed transaction and its receipt on chain.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Mismatch {
+    /// The replay rejected the transaction, so nothing else could be compared.
+    Invalid { error: String },
+    Status {
+        expected: Option<U64>,
+        actual: Option<U64>,
+    },
+    GasUsed {
+        expected: Option<U256>,
+        actual: Option<U256>,
+    },
+    /// Only the addresses, topics and data are compared.
+    Logs {
+        expected: Vec<Log>,
+        actual: Vec<Log>,
+    },
+}
+
+/// A storage slot written by the replay or by the block on chain which doesn't hold the same
+/// value as on chain after the block.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageMismatch {
+    pub address: Address,
+    pub slot: H256,
+    pub expected: H256,
+    pub actual: H256,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionReplay {
+    pub hash: H256,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// The outcome of `replay_block()` and `replay_transaction()`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayReport {
+    pub block_number: u64,
+    pub transactions: Vec<TransactionReplay>,
+    pub storage: Vec<StorageMismatch>,
+}
+
+impl ReplayReport {
+    /// Whether the replay matches the chain.
+    pub fn is_match(&self) -> bool {
+        self.storage.is_empty() && self.transactions.iter().all(|tx| tx.mismatches.is_empty())
+    }
+}
+
+/// Compares the receipt of the replay with the one on chain.
+pub fn compare_receipts(
+    expected: &TransactionReceipt,
+    actual: &TransactionReceipt,
+) -> Vec<Mismatch> {
+    let mut mismatches = vec![];
+
+    if expected.status != actual.status {
+        mismatches.push(Mismatch::Status {
+            expected: expected.status,
+            actual: actual.status,
+        });
+    }
+    if expected.gas_used != actual.gas_used {
+        mismatches.push(Mismatch::GasUsed {
+            expected: expected.gas_used,
+            actual: actual.gas_used,
+        });
+    }
+
+    let same_log =
+        |a: &Log, b: &Log| a.address == b.address && a.topics == b.topics && a.data == b.data;
+    if expected.logs.len() != actual.logs.len()
+        || !expected
+            .logs
+            .iter()
+            .zip(actual.logs.iter())
+            .all(|(a, b)| same_log(a, b))
+    {
+        mismatches.push(Mismatch::Logs {
+            expected: expected.logs.clone(),
+            actual: actual.logs.clone(),
+        });
+    }
+
+    mismatches
+}
+
+/// The transaction as it was sent, from the archive node's response.
+pub fn typed_transaction(tx: &Transaction) -> TypedTransaction {
+    let mut request = TransactionRequest::new()
+        .from(tx.from)
+        .nonce(tx.nonce)
+        .value(tx.value)
+        .gas(tx.gas)
+        .data(tx.input.clone());
+    request.to = tx.to.map(Into::into);
+    request.gas_price = tx.gas_price;
+
+    match tx.transaction_type.map(|t| t.as_u64()) {
+        Some(1) => TypedTransaction::Eip2930(Eip2930TransactionRequest {
+            tx: request,
+            access_list: tx.access_list.clone().unwrap_or_default(),
+        }),
+        Some(2) => {
+            let mut request = Eip1559TransactionRequest::new()
+                .from(tx.from)
+                .nonce(tx.nonce)
+                .value(tx.value)
+                .gas(tx.gas)
+                .data(tx.input.clone())
+                .access_list(tx.access_list.clone().unwrap_or_default());
+            request.to = tx.to.map(Into::into);
+            request.max_fee_per_gas = tx.max_fee_per_gas;
+            request.max_priority_fee_per_gas = tx.max_priority_fee_per_gas;
+            TypedTransaction::Eip1559(request)
+        }
+        _ => TypedTransaction::Legacy(request),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite_backend::SqliteDumper;
+    use crate::ForkedEvmProvider;
+
+    #[test]
+    fn test_compare_receipts() {
+        let log = Log {
+            address: Address::from_low_u64_be(1),
+            topics: vec![H256::from_low_u64_be(2)],
+            ..Default::default()
+        };
+        let expected = TransactionReceipt {
+            status: Some(1.into()),
+            gas_used: Some(21000.into()),
+            logs: vec![log.clone()],
+            ..Default::default()
+        };
+
+        // only the content of the logs counts, not where they are
+        let mut actual = expected.clone();
+        actual.logs[0].log_index = Some(7.into());
+        assert!(compare_receipts(&expected, &actual).is_empty());
+
+        actual.gas_used = Some(21001.into());
+        actual.logs.push(log);
+        assert_eq!(
+            compare_receipts(&expected, &actual),
+            vec![
+                Mismatch::GasUsed {
+                    expected: Some(21000.into()),
+                    actual: Some(21001.into()),
+                },
+                Mismatch::Logs {
+                    expected: expected.logs.clone(),
+                    actual: actual.logs.clone(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_recorded_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("replay.db");
+        let sender = Address::from_low_u64_be(0x1000);
+        let recipient = Address::from_low_u64_be(0x2000);
+        // PUSH1 1 PUSH1 0 SSTORE STOP
+        let store = Address::from_low_u64_be(0x3000);
+
+        let tx = |nonce: u64, to| Transaction {
+            hash: H256::from_low_u64_be(nonce + 1),
+            nonce: nonce.into(),
+            from: sender,
+            to: Some(to),
+            gas: 100_000.into(),
+            gas_price: Some(2_000_000_000u64.into()),
+            ..Default::default()
+        };
+        let receipt = |gas_used: u64| TransactionReceipt {
+            status: Some(1.into()),
+            gas_used: Some(gas_used.into()),
+            ..Default::default()
+        };
+
+        // block 101 as the archive node would have recorded it, except for the value stored
+        {
+            let mut dumper = SqliteDumper::new(&db_path).unwrap();
+            let code = hex::decode("600160005500").unwrap();
+            dumper
+                .dump_address(sender, U256::exp10(18), U256::zero(), vec![])
+                .unwrap();
+            // the recipient, then the coinbase
+            for address in [recipient, Address::zero()] {
+                dumper
+                    .dump_address(address, U256::zero(), U256::zero(), vec![])
+                    .unwrap();
+            }
+            dumper
+                .dump_address(store, U256::zero(), U256::zero(), code)
+                .unwrap();
+            dumper
+                .dump_storage(store, H256::zero(), H256::zero())
+                .unwrap();
+            dumper
+                .dump_block_header(
+                    101,
+                    H256::zero(),
+                    1_000_000_000u64.into(),
+                    0,
+                    30_000_000,
+                    U256::zero(),
+                    Address::zero(),
+                )
+                .unwrap();
+            dumper
+                .dump_block_transactions(
+                    101,
+                    &[
+                        (tx(0, recipient), receipt(21000)),
+                        (tx(1, store), receipt(43106)),
+                    ],
+                )
+                .unwrap();
+            dumper
+                .dump_block_storage_changes(101, &[(H256::from_low_u64_be(2), store, H256::zero())])
+                .unwrap();
+            dumper
+                .dump_storage_after_block(101, store, H256::zero(), H256::from_low_u64_be(2))
+                .unwrap();
+        }
+
+        let provider = ForkedEvmProvider::new(100, "wss://unused", db_path)
+            .await
+            .unwrap();
+        let report = provider.replay_block(101).await.unwrap();
+        assert!(!report.is_match());
+        assert_eq!(
+            report.transactions,
+            vec![
+                TransactionReplay {
+                    hash: H256::from_low_u64_be(1),
+                    mismatches: vec![],
+                },
+                TransactionReplay {
+                    hash: H256::from_low_u64_be(2),
+                    mismatches: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            report.storage,
+            vec![StorageMismatch {
+                address: store,
+                slot: H256::zero(),
+                expected: H256::from_low_u64_be(2),
+                actual: H256::from_low_u64_be(1),
+            }]
+        );
+
+        // only the transaction is reported, nothing has been changed by the block replay
+        let report = provider
+            .replay_transaction(H256::from_low_u64_be(1))
+            .await
+            .unwrap();
+        assert!(report.is_match());
+        assert_eq!(report.transactions.len(), 1);
+    }
+}
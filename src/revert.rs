@@ -0,0 +1,234 @@
+use crate::akula::evm::CallResult;
+use ethers::abi::{self, Abi, ParamType, Token};
+use ethers::providers::ProviderError;
+use ethers::types::{Bytes, U256};
+use ethers::utils::id;
+use evmodin::StatusCode;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+// Error(string)
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+// Panic(uint256)
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RevertReason {
+    /// `revert("...")` or `require(..., "...")`.
+    Error(String),
+    /// A failed `assert()` or a runtime check inserted by the Solidity compiler.
+    Panic(U256),
+    /// A custom error declared in one of the registered ABIs.
+    Custom { name: String, args: Vec<Token> },
+    /// No data, or data that can't be decoded.
+    Unknown,
+}
+
+impl RevertReason {
+    /// The description of the Solidity panic code, if it's a panic.
+    pub fn panic_name(&self) -> Option<&'static str> {
+        let code = match self {
+            RevertReason::Panic(code) => code,
+            _ => return None,
+        };
+
+        // https://docs.soliditylang.org/en/latest/control-structures.html#panic-via-assert-and-error-via-require
+        let name = match code.low_u64() {
+            _ if code.bits() > 64 => "unknown panic code",
+            0x00 => "generic compiler panic",
+            0x01 => "assertion failed",
+            0x11 => "arithmetic underflow or overflow",
+            0x12 => "division or modulo by zero",
+            0x21 => "invalid enum value",
+            0x22 => "invalid storage byte array encoding",
+            0x31 => "pop on an empty array",
+            0x32 => "array index out of bounds",
+            0x41 => "out of memory",
+            0x51 => "call to a zero-initialized function",
+            _ => "unknown panic code",
+        };
+        Some(name)
+    }
+}
+
+impl Display for RevertReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RevertReason::Error(reason) => write!(f, "{}", reason),
+            RevertReason::Panic(code) => {
+                write!(f, "panic: {} ({:#x})", self.panic_name().unwrap(), code)
+            }
+            RevertReason::Custom { name, args } => {
+                let args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            RevertReason::Unknown => write!(f, "unknown reason"),
+        }
+    }
+}
+
+/// Decodes the revert data, custom errors are recognized after their ABIs are registered.
+#[derive(Clone, Debug, Default)]
+pub struct RevertDecoder {
+    custom_errors: HashMap<[u8; 4], (String, Vec<ParamType>)>,
+}
+
+impl RevertDecoder {
+    pub fn register_abi(&mut self, abi: &Abi) {
+        for error in abi.errors.values().flatten() {
+            let types = error
+                .inputs
+                .iter()
+                .map(|p| p.kind.clone())
+                .collect::<Vec<_>>();
+            let signature = format!(
+                "{}({})",
+                error.name,
+                types
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+            self.custom_errors
+                .insert(id(signature), (error.name.clone(), types));
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> RevertReason {
+        if data.len() < 4 {
+            return RevertReason::Unknown;
+        }
+
+        let mut selector = [0u8; 4];
+        selector.copy_from_slice(&data[..4]);
+        let payload = &data[4..];
+
+        let decoded = match selector {
+            ERROR_SELECTOR => match abi::decode(&[ParamType::String], payload) {
+                Ok(mut tokens) => match tokens.pop() {
+                    Some(Token::String(reason)) => Some(RevertReason::Error(reason)),
+                    _ => None,
+                },
+                Err(_) => None,
+            },
+            PANIC_SELECTOR => match abi::decode(&[ParamType::Uint(256)], payload) {
+                Ok(mut tokens) => match tokens.pop() {
+                    Some(Token::Uint(code)) => Some(RevertReason::Panic(code)),
+                    _ => None,
+                },
+                Err(_) => None,
+            },
+            _ => self.custom_errors.get(&selector).and_then(|(name, types)| {
+                abi::decode(types, payload)
+                    .ok()
+                    .map(|args| RevertReason::Custom {
+                        name: name.clone(),
+                        args,
+                    })
+            }),
+        };
+
+        decoded.unwrap_or(RevertReason::Unknown)
+    }
+}
+
+/// A transaction or call that didn't succeed.
+#[derive(Clone, Debug)]
+pub struct RevertError {
+    pub reason: RevertReason,
+    /// The raw data returned by `REVERT`, it's empty for other failures.
+    pub data: Bytes,
+    pub gas_used: u64,
+    pub status_code: StatusCode,
+}
+
+impl RevertError {
+    pub fn new(ret: &CallResult, gas_used: u64, decoder: &RevertDecoder) -> Self {
+        let reason = if ret.status_code == StatusCode::Revert {
+            decoder.decode(&ret.output_data)
+        } else {
+            RevertReason::Unknown
+        };
+
+        Self {
+            reason,
+            data: ret.output_data.clone().into(),
+            gas_used,
+            status_code: ret.status_code.clone(),
+        }
+    }
+}
+
+impl Display for RevertError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.status_code, &self.reason) {
+            (StatusCode::Revert, RevertReason::Unknown) if self.data.0.is_empty() => {
+                write!(f, "execution reverted")
+            }
+            (StatusCode::Revert, RevertReason::Unknown) => {
+                write!(f, "execution reverted: 0x{}", hex::encode(&self.data.0))
+            }
+            (StatusCode::Revert, reason) => write!(f, "execution reverted: {}", reason),
+            (status_code, _) => write!(f, "execution failed: {:?}", status_code),
+        }
+    }
+}
+
+impl std::error::Error for RevertError {}
+
+impl From<RevertError> for ProviderError {
+    fn from(e: RevertError) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_revert_reasons() {
+        let decoder = RevertDecoder::default();
+
+        let mut data = ERROR_SELECTOR.to_vec();
+        data.extend(abi::encode(&[Token::String("UniswapV2: K".to_string())]));
+        assert_eq!(
+            decoder.decode(&data),
+            RevertReason::Error("UniswapV2: K".to_string())
+        );
+
+        let mut data = PANIC_SELECTOR.to_vec();
+        data.extend(abi::encode(&[Token::Uint(0x11.into())]));
+        let reason = decoder.decode(&data);
+        assert_eq!(reason, RevertReason::Panic(0x11.into()));
+        assert_eq!(
+            reason.to_string(),
+            "panic: arithmetic underflow or overflow (0x11)"
+        );
+
+        assert_eq!(decoder.decode(&[]), RevertReason::Unknown);
+    }
+
+    #[test]
+    fn test_decode_custom_error() {
+        let abi: Abi = serde_json::from_str(
+            r#"[{"type":"error","name":"InsufficientOutput","inputs":[{"name":"amount","type":"uint256"}]}]"#,
+        )
+        .unwrap();
+
+        let mut decoder = RevertDecoder::default();
+        let mut data = id("InsufficientOutput(uint256)").to_vec();
+        data.extend(abi::encode(&[Token::Uint(42.into())]));
+        assert_eq!(decoder.decode(&data), RevertReason::Unknown);
+
+        decoder.register_abi(&abi);
+        assert_eq!(
+            decoder.decode(&data),
+            RevertReason::Custom {
+                name: "InsufficientOutput".to_string(),
+                args: vec![Token::Uint(42.into())],
+            }
+        );
+    }
+}
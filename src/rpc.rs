@@ -0,0 +1,550 @@
+use crate::akula::utils::keccak256;
+use crate::bundle::bundle_hash;
+use crate::error::ForkError;
+use crate::forked_evm_provider::ForkedEvmProvider;
+use crate::revert::RevertError;
+use crate::subscriptions::SubscriptionKind;
+use ethers::providers::{Middleware, ProviderError};
+use ethers::types::transaction::eip1559::Eip1559TransactionRequest;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::transaction::eip2930::{AccessList, Eip2930TransactionRequest};
+use ethers::types::{
+    Address, BigEndianHash, BlockId, BlockNumber, Bytes, TransactionRequest, H256, U256, U64,
+};
+use futures::channel::mpsc::UnboundedSender;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// A JSON-RPC 2.0 request.
+#[derive(Clone, Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    fn invalid_params(message: impl ToString) -> Self {
+        Self::new(-32602, message)
+    }
+}
+
+impl From<ForkError> for RpcError {
+    fn from(e: ForkError) -> Self {
+        match e {
+            ForkError::Reverted(e) => e.into(),
+            e => Self::new(-32000, e),
+        }
+    }
+}
+
+impl From<RevertError> for RpcError {
+    /// Like geth, the revert data goes along with the message.
+    fn from(e: RevertError) -> Self {
+        Self {
+            code: 3,
+            message: e.to_string(),
+            data: Some(json!(e.data)),
+        }
+    }
+}
+
+impl From<ProviderError> for RpcError {
+    /// The provider boxes its own errors, they're recovered to keep the revert data.
+    fn from(e: ProviderError) -> Self {
+        match e {
+            ProviderError::JsonRpcClientError(e) => match e.downcast::<ForkError>() {
+                Ok(e) => (*e).into(),
+                Err(e) => match e.downcast::<RevertError>() {
+                    Ok(e) => (*e).into(),
+                    Err(e) => Self::new(-32000, e),
+                },
+            },
+            e => Self::new(-32000, e),
+        }
+    }
+}
+
+/// Answers JSON-RPC requests from the fork: the `eth_*`, `net_*`, `web3_*` and `txpool_*`
+/// methods, flashbots' bundles and the cheat codes of anvil and hardhat.
+#[derive(Clone, Debug)]
+pub struct RpcHandler {
+    provider: Arc<ForkedEvmProvider>,
+    // where the `eth_subscription` messages go, only websocket connections have one
+    notifications: Option<UnboundedSender<String>>,
+}
+
+impl RpcHandler {
+    pub fn new(provider: Arc<ForkedEvmProvider>) -> Self {
+        Self {
+            provider,
+            notifications: None,
+        }
+    }
+
+    /// The handler of a websocket connection, which supports `eth_subscribe`. The
+    /// notifications are sent as JSON-RPC messages to `notifications`.
+    pub fn with_notifications(&self, notifications: UnboundedSender<String>) -> Self {
+        Self {
+            provider: self.provider.clone(),
+            notifications: Some(notifications),
+        }
+    }
+
+    /// Handles a request or a batch of requests, the body of an HTTP request or a websocket
+    /// message.
+    pub async fn handle_text(&self, text: &str) -> String {
+        let body = match serde_json::from_str::<Value>(text) {
+            Ok(Value::Array(requests)) => {
+                let mut responses = vec![];
+                for request in requests {
+                    responses.push(self.handle_value(request).await);
+                }
+                json!(responses)
+            }
+            Ok(request) => json!(self.handle_value(request).await),
+            Err(e) => json!(error_response(
+                Value::Null,
+                RpcError::new(-32700, format!("parse error: {}", e)),
+            )),
+        };
+        body.to_string()
+    }
+
+    async fn handle_value(&self, request: Value) -> Response {
+        match serde_json::from_value::<Request>(request) {
+            Ok(request) => self.handle(request).await,
+            Err(e) => error_response(
+                Value::Null,
+                RpcError::new(-32600, format!("invalid request: {}", e)),
+            ),
+        }
+    }
+
+    async fn handle(&self, request: Request) -> Response {
+        let params = match request.params {
+            Value::Array(params) => params,
+            Value::Null => vec![],
+            params => vec![params],
+        };
+
+        match self.call(&request.method, &params).await {
+            Ok(result) => Response {
+                jsonrpc: "2.0",
+                id: request.id,
+                result: Some(result),
+                error: None,
+            },
+            Err(e) => error_response(request.id, e),
+        }
+    }
+
+    async fn call(&self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        let provider = &self.provider;
+
+        let result = match method {
+            "web3_clientVersion" => json!(provider.client_version().await?),
+            "web3_sha3" => json!(keccak256(param::<Bytes>(params, 0)?.as_ref())),
+            "net_version" => json!(provider.get_net_version().await?),
+            "net_listening" => json!(true),
+            "net_peerCount" => json!(U64::zero()),
+
+            "eth_chainId" => json!(U64::from(provider.get_chainid().await?.as_u64())),
+            "eth_blockNumber" => json!(provider.get_block_number().await?),
+            "eth_syncing" | "eth_mining" => json!(false),
+            "eth_accounts" => json!(provider.get_accounts().await?),
+            "eth_gasPrice" => json!(provider.get_gas_price().await?),
+            "eth_maxPriorityFeePerGas" => {
+                let (max_fee, _) = provider.estimate_eip1559_fees(None).await?;
+                let base_fee = provider.get_gas_price().await?;
+                json!(max_fee.saturating_sub(base_fee))
+            }
+            "eth_feeHistory" => {
+                let block_count = param::<U256>(params, 0)?;
+                let last_block = block_number(params, 1)?;
+                let percentiles = param::<Option<Vec<f64>>>(params, 2)?.unwrap_or_default();
+                json!(
+                    provider
+                        .fee_history(block_count, last_block, &percentiles)
+                        .await?
+                )
+            }
+            "eth_getBalance" => json!(
+                provider
+                    .get_balance(param::<Address>(params, 0)?, block_id(params, 1)?)
+                    .await?
+            ),
+            "eth_getTransactionCount" => json!(
+                provider
+                    .get_transaction_count(param::<Address>(params, 0)?, block_id(params, 1)?)
+                    .await?
+            ),
+            "eth_getCode" => json!(
+                provider
+                    .get_code(param::<Address>(params, 0)?, block_id(params, 1)?)
+                    .await?
+            ),
+            "eth_getStorageAt" => {
+                let slot = H256::from_uint(&param::<U256>(params, 1)?);
+                json!(
+                    provider
+                        .get_storage_at(param::<Address>(params, 0)?, slot, block_id(params, 2)?)
+                        .await?
+                )
+            }
+            "eth_getBlockByNumber" | "eth_getBlockByHash" => {
+                let block = match method {
+                    "eth_getBlockByHash" => BlockId::Hash(param::<H256>(params, 0)?),
+                    _ => block_number(params, 0)?.into(),
+                };
+                if param::<Option<bool>>(params, 1)?.unwrap_or_default() {
+                    json!(provider.get_block_with_txs(block).await?)
+                } else {
+                    json!(provider.get_block(block).await?)
+                }
+            }
+            "eth_getTransactionByHash" => {
+                json!(provider.get_transaction(param::<H256>(params, 0)?).await?)
+            }
+            "eth_getTransactionReceipt" => json!(
+                provider
+                    .get_transaction_receipt(param::<H256>(params, 0)?)
+                    .await?
+            ),
+            "eth_call" => json!(
+                provider
+                    .call(&transaction(params, 0)?, block_id(params, 1)?)
+                    .await?
+            ),
+            "eth_estimateGas" => json!(provider.estimate_gas(&transaction(params, 0)?).await?),
+            "eth_createAccessList" => json!(
+                provider
+                    .create_access_list(&transaction(params, 0)?, block_id(params, 1)?)
+                    .await?
+            ),
+            "eth_sendTransaction" => {
+                let mut tx = transaction(params, 0)?;
+                provider.fill_transaction(&mut tx, None).await?;
+                json!(*provider.send_transaction(tx, None).await?)
+            }
+            "eth_sendRawTransaction" => json!(
+                *provider
+                    .send_raw_transaction(param::<Bytes>(params, 0)?)
+                    .await?
+            ),
+
+            "txpool_content" => json!(provider.txpool_content().await?),
+            "txpool_inspect" => json!(provider.txpool_inspect().await?),
+            "txpool_status" => json!(provider.txpool_status().await?),
+
+            "eth_callBundle" => {
+                let bundle = param::<CallBundle>(params, 0)?;
+                let block_number =
+                    u64::from_str_radix(bundle.block_number.trim_start_matches("0x"), 16)
+                        .map_err(RpcError::invalid_params)?;
+                json!(
+                    provider
+                        .simulate_bundle(&bundle.txs, block_number, bundle.timestamp)
+                        .await?
+                )
+            }
+            "eth_sendBundle" => {
+                let bundle = param::<CallBundle>(params, 0)?;
+                provider.send_bundle(&bundle.txs).await?;
+                let hashes = bundle.txs.iter().map(|tx| keccak256(tx.as_ref()));
+                json!({ "bundleHash": bundle_hash(hashes) })
+            }
+
+            "anvil_setBalance" | "hardhat_setBalance" => {
+                provider
+                    .set_balance(param::<Address>(params, 0)?, param::<U256>(params, 1)?)
+                    .await?;
+                json!(true)
+            }
+            "evm_mine" => {
+                provider.mine_block().await?;
+                json!("0x0")
+            }
+            "anvil_mine" | "hardhat_mine" => {
+                // the number of blocks and the seconds between them, both quantities
+                let count = param::<Option<U64>>(params, 0)?.map_or(1, |n| n.as_u64());
+                let interval = param::<Option<U64>>(params, 1)?.map(|n| n.as_u64());
+                provider.mine_blocks(count, interval).await?;
+                json!(null)
+            }
+            "evm_setAutomine" | "anvil_setAutomine" => {
+                provider.set_automine(param::<bool>(params, 0)?).await;
+                json!(true)
+            }
+            "anvil_dumpState" => json!(provider.dump_state().await?),
+            "anvil_loadState" => {
+                provider.load_state(&param::<Bytes>(params, 0)?).await?;
+                json!(true)
+            }
+            "anvil_reset" | "hardhat_reset" => {
+                // the archive node can't be changed, only the fork block
+                let reset = param::<Option<Reset>>(params, 0)?.unwrap_or_default();
+                let block_number = match reset.forking.and_then(|forking| forking.block_number) {
+                    // hardhat takes a number, anvil a quantity
+                    Some(Value::Number(n)) => n.as_u64(),
+                    Some(Value::String(n)) => Some(
+                        u64::from_str_radix(n.trim_start_matches("0x"), 16)
+                            .map_err(RpcError::invalid_params)?,
+                    ),
+                    _ => None,
+                };
+                provider.reset(block_number).await?;
+                json!(true)
+            }
+
+            "eth_subscribe" => json!(self.subscribe(params)?),
+            "eth_unsubscribe" => {
+                let id = param::<U256>(params, 0)?;
+                json!(provider.subscriptions().lock().unwrap().unsubscribe(id))
+            }
+
+            _ => {
+                return Err(RpcError::new(
+                    -32601,
+                    format!("the method {} does not exist/is not available", method),
+                ))
+            }
+        };
+
+        Ok(result)
+    }
+
+    /// Starts the subscription and forwards its notifications to the connection, until either
+    /// of them ends.
+    fn subscribe(&self, params: &[Value]) -> Result<U256, RpcError> {
+        let sink = match &self.notifications {
+            Some(sink) => sink.clone(),
+            None => return Err(RpcError::new(-32601, "notifications not supported")),
+        };
+        let kind = SubscriptionKind::from_params(params).map_err(RpcError::invalid_params)?;
+
+        let mut subscriptions = self.provider.subscriptions().lock().unwrap();
+        let id = subscriptions.subscribe(kind);
+        let mut stream = subscriptions.take_notifications(id).unwrap();
+        drop(subscriptions);
+
+        let provider = self.provider.clone();
+        tokio::spawn(async move {
+            while let Some(result) = stream.next().await {
+                let notification = json!({
+                    "jsonrpc": "2.0",
+                    "method": "eth_subscription",
+                    "params": { "subscription": id, "result": result },
+                });
+                if sink.unbounded_send(notification.to_string()).is_err() {
+                    provider.subscriptions().lock().unwrap().unsubscribe(id);
+                    break;
+                }
+            }
+        });
+        Ok(id)
+    }
+}
+
+/// The parameters of `eth_callBundle` and `eth_sendBundle`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CallBundle {
+    txs: Vec<Bytes>,
+    block_number: String,
+    #[serde(default)]
+    timestamp: Option<u64>,
+}
+
+/// The parameters of `anvil_reset` and `hardhat_reset`.
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Reset {
+    #[serde(default)]
+    forking: Option<Forking>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Forking {
+    #[serde(default)]
+    block_number: Option<Value>,
+}
+
+fn error_response(id: Value, error: RpcError) -> Response {
+    Response {
+        jsonrpc: "2.0",
+        id,
+        result: None,
+        error: Some(error),
+    }
+}
+
+/// A missing parameter is the same as `null`, which is how the optional ones are omitted.
+fn param<T: DeserializeOwned>(params: &[Value], index: usize) -> Result<T, RpcError> {
+    let value = params.get(index).cloned().unwrap_or(Value::Null);
+    serde_json::from_value(value)
+        .map_err(|e| RpcError::invalid_params(format!("invalid parameter {}: {}", index, e)))
+}
+
+fn block_number(params: &[Value], index: usize) -> Result<BlockNumber, RpcError> {
+    match param::<Option<String>>(params, index)?.as_deref() {
+        None | Some("latest") => Ok(BlockNumber::Latest),
+        Some("pending") => Ok(BlockNumber::Pending),
+        Some("earliest") => Ok(BlockNumber::Earliest),
+        Some(number) => u64::from_str_radix(number.trim_start_matches("0x"), 16)
+            .map(|n| BlockNumber::Number(n.into()))
+            .map_err(|_| RpcError::invalid_params(format!("invalid block {}", number))),
+    }
+}
+
+/// A block number or tag, or an EIP-1898 object with the number or the hash of the block.
+fn block_id(params: &[Value], index: usize) -> Result<Option<BlockId>, RpcError> {
+    match params.get(index) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Object(block)) => match (block.get("blockHash"), block.get("blockNumber")) {
+            (Some(hash), _) => Ok(Some(BlockId::Hash(param::<H256>(&[hash.clone()], 0)?))),
+            (None, Some(number)) => Ok(Some(block_number(&[number.clone()], 0)?.into())),
+            (None, None) => Err(RpcError::invalid_params("invalid block")),
+        },
+        Some(_) => Ok(Some(block_number(params, index)?.into())),
+    }
+}
+
+/// Transaction objects don't always have a type, it's guessed from their fields like geth.
+fn transaction(params: &[Value], index: usize) -> Result<TypedTransaction, RpcError> {
+    let mut tx = param::<serde_json::Map<String, Value>>(params, index)?;
+    // `input` is the newer name of `data`
+    if let Some(input) = tx.remove("input") {
+        tx.entry("data").or_insert(input);
+    }
+    let access_list = tx.remove("accessList");
+
+    let typ = match tx.remove("type").as_ref().and_then(Value::as_str) {
+        Some(typ) => u64::from_str_radix(typ.trim_start_matches("0x"), 16)
+            .map_err(|_| RpcError::invalid_params(format!("invalid type {}", typ)))?,
+        None if tx.contains_key("maxFeePerGas") || tx.contains_key("maxPriorityFeePerGas") => 2,
+        None if access_list.is_some() => 1,
+        None => 0,
+    };
+    let access_list = match access_list {
+        Some(access_list) => param::<AccessList>(&[access_list], 0)?,
+        None => AccessList::default(),
+    };
+
+    let tx = Value::Object(tx);
+    Ok(match typ {
+        0 => TypedTransaction::Legacy(param::<TransactionRequest>(&[tx], 0)?),
+        1 => TypedTransaction::Eip2930(Eip2930TransactionRequest {
+            tx: param::<TransactionRequest>(&[tx], 0)?,
+            access_list,
+        }),
+        2 => TypedTransaction::Eip1559(Eip1559TransactionRequest {
+            access_list,
+            ..param::<Eip1559TransactionRequest>(&[tx], 0)?
+        }),
+        typ => {
+            return Err(RpcError::invalid_params(format!(
+                "transaction type {} not supported",
+                typ
+            )))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dev_accounts, Genesis};
+
+    #[test]
+    fn test_transaction_params() {
+        let params = vec![json!({
+            "from": "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f",
+            "to": "0x3535353535353535353535353535353535353535",
+            "input": "0x1234",
+            "maxFeePerGas": "0x10",
+        })];
+        let tx = transaction(&params, 0).unwrap();
+
+        assert!(matches!(tx, TypedTransaction::Eip1559(_)));
+        assert_eq!(tx.data(), Some(&Bytes::from(vec![0x12, 0x34])));
+        assert!(matches!(
+            block_id(&[json!("0x10")], 0).unwrap(),
+            Some(BlockId::Number(BlockNumber::Number(n))) if n == 16.into()
+        ));
+        assert_eq!(block_id(&[], 0).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_batch_round_trip() {
+        let genesis = Genesis {
+            chain_id: 10,
+            ..Default::default()
+        };
+        let provider = ForkedEvmProvider::new_in_memory(genesis).await.unwrap();
+        let handler = RpcHandler::new(Arc::new(provider));
+        let from = dev_accounts()[0];
+        let to = Address::from_low_u64_be(0x2000);
+
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_chainId"},
+            {
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "eth_sendTransaction",
+                "params": [{"from": from, "to": to, "value": "0x5"}],
+            },
+            {"jsonrpc": "2.0", "id": 3, "method": "eth_getBalance", "params": [to, "latest"]},
+            // PUSH1 0 PUSH1 0 REVERT as init code
+            {
+                "jsonrpc": "2.0",
+                "id": 4,
+                "method": "eth_call",
+                "params": [{"from": from, "input": "0x60006000fd"}],
+            },
+            {"jsonrpc": "2.0", "id": 5, "method": "eth_foo"},
+        ]);
+        let responses: Value =
+            serde_json::from_str(&handler.handle_text(&batch.to_string()).await).unwrap();
+
+        assert_eq!(responses[0]["result"], json!("0xa"));
+        assert!(responses[1]["result"].is_string());
+        assert_eq!(responses[2]["result"], json!("0x5"));
+        // the revert code of geth
+        assert_eq!(responses[3]["error"]["code"], json!(3));
+        assert_eq!(responses[3]["error"]["data"], json!("0x"));
+        assert_eq!(responses[4]["id"], json!(5));
+        assert_eq!(responses[4]["error"]["code"], json!(-32601));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::akula::evm::u256_to_h256;
+use crate::akula::is_valid_signature;
+use crate::akula::utils::keccak256;
+use crate::error::ForkError;
+use ethers::types::transaction::eip1559::Eip1559TransactionRequest;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::transaction::eip2930::{AccessList, AccessListItem, Eip2930TransactionRequest};
+use ethers::types::{Address, TransactionRequest, H256, U256};
+use rlp::{DecoderError, Rlp, RlpStream};
+use secp256k1::{
+    recovery::{RecoverableSignature, RecoveryId},
+    Message, SECP256K1,
+};
+
+/// A transaction decoded from its signed RLP envelope, `tx.from()` is the recovered sender.
+#[derive(Clone, Debug)]
+pub struct SignedTransaction {
+    pub tx: TypedTransaction,
+    pub hash: H256,
+    /// `None` for legacy transactions signed without EIP-155 replay protection.
+    pub chain_id: Option<u64>,
+    pub v: u64,
+    pub r: U256,
+    pub s: U256,
+}
+
+impl SignedTransaction {
+    /// Decodes legacy, EIP-2930 and EIP-1559 transactions, and recovers the sender.
+    pub fn decode(raw: &[u8]) -> Result<Self, ForkError> {
+        match raw.first() {
+            Some(0x01) => Self::decode_eip2930(raw),
+            Some(0x02) => Self::decode_eip1559(raw),
+            Some(x) if *x >= 0xc0 => Self::decode_legacy(raw),
+            _ => Err(ForkError::InvalidTransaction(
+                "transaction type not supported".to_string(),
+            )),
+        }
+    }
+
+    // rlp([nonce, gasPrice, gasLimit, to, value, data, v, r, s])
+    fn decode_legacy(raw: &[u8]) -> Result<Self, ForkError> {
+        let rlp = Rlp::new(raw);
+        if rlp.item_count()? != 9 {
+            return Err(DecoderError::RlpIncorrectListLen.into());
+        }
+
+        let v: u64 = rlp.val_at(6)?;
+        // https://eips.ethereum.org/EIPS/eip-155
+        let (chain_id, odd) = match v {
+            27 | 28 => (None, v == 28),
+            v if v >= 35 => (Some((v - 35) / 2), (v - 35) % 2 == 1),
+            _ => return Err(invalid_signature()),
+        };
+
+        let mut tx = TransactionRequest::new();
+        tx.nonce = Some(rlp.val_at(0)?);
+        tx.gas_price = Some(rlp.val_at(1)?);
+        tx.gas = Some(rlp.val_at(2)?);
+        tx.to = decode_to(&rlp.at(3)?)?.map(Into::into);
+        tx.value = Some(rlp.val_at(4)?);
+        tx.data = Some(rlp.val_at::<Vec<u8>>(5)?.into());
+        tx.chain_id = chain_id.map(Into::into);
+
+        let sighash = signing_hash(None, &rlp, 6, chain_id)?;
+        Self::recover(
+            TypedTransaction::Legacy(tx),
+            raw,
+            sighash,
+            chain_id,
+            v,
+            odd,
+            (rlp.val_at(7)?, rlp.val_at(8)?),
+        )
+    }
+
+    // 0x01 || rlp([chainId, nonce, gasPrice, gasLimit, to, value, data, accessList, yParity, r, s])
+    fn decode_eip2930(raw: &[u8]) -> Result<Self, ForkError> {
+        let rlp = Rlp::new(&raw[1..]);
+        if rlp.item_count()? != 11 {
+            return Err(DecoderError::RlpIncorrectListLen.into());
+        }
+
+        let chain_id: u64 = rlp.val_at(0)?;
+        let mut tx = TransactionRequest::new();
+        tx.chain_id = Some(chain_id.into());
+        tx.nonce = Some(rlp.val_at(1)?);
+        tx.gas_price = Some(rlp.val_at(2)?);
+        tx.gas = Some(rlp.val_at(3)?);
+        tx.to = decode_to(&rlp.at(4)?)?.map(Into::into);
+        tx.value = Some(rlp.val_at(5)?);
+        tx.data = Some(rlp.val_at::<Vec<u8>>(6)?.into());
+        let access_list = decode_access_list(&rlp.at(7)?)?;
+
+        let v: u64 = rlp.val_at(8)?;
+        let sighash = signing_hash(Some(0x01), &rlp, 8, None)?;
+        Self::recover(
+            TypedTransaction::Eip2930(Eip2930TransactionRequest { tx, access_list }),
+            raw,
+            sighash,
+            Some(chain_id),
+            v,
+            y_parity(v)?,
+            (rlp.val_at(9)?, rlp.val_at(10)?),
+        )
+    }
+
+    // 0x02 || rlp([chainId, nonce, maxPriorityFeePerGas, maxFeePerGas, gasLimit, to, value, data,
+    //              accessList, yParity, r, s])
+    fn decode_eip1559(raw: &[u8]) -> Result<Self, ForkError> {
+        let rlp = Rlp::new(&raw[1..]);
+        if rlp.item_count()? != 12 {
+            return Err(DecoderError::RlpIncorrectListLen.into());
+        }
+
+        let chain_id: u64 = rlp.val_at(0)?;
+        let mut tx = Eip1559TransactionRequest::new();
+        tx.chain_id = Some(chain_id.into());
+        tx.nonce = Some(rlp.val_at(1)?);
+        tx.max_priority_fee_per_gas = Some(rlp.val_at(2)?);
+        tx.max_fee_per_gas = Some(rlp.val_at(3)?);
+        tx.gas = Some(rlp.val_at(4)?);
+        tx.to = decode_to(&rlp.at(5)?)?.map(Into::into);
+        tx.value = Some(rlp.val_at(6)?);
+        tx.data = Some(rlp.val_at::<Vec<u8>>(7)?.into());
+        tx.access_list = decode_access_list(&rlp.at(8)?)?;
+
+        let v: u64 = rlp.val_at(9)?;
+        let sighash = signing_hash(Some(0x02), &rlp, 9, None)?;
+        Self::recover(
+            TypedTransaction::Eip1559(tx),
+            raw,
+            sighash,
+            Some(chain_id),
+            v,
+            y_parity(v)?,
+            (rlp.val_at(10)?, rlp.val_at(11)?),
+        )
+    }
+
+    fn recover(
+        mut tx: TypedTransaction,
+        raw: &[u8],
+        sighash: H256,
+        chain_id: Option<u64>,
+        v: u64,
+        odd: bool,
+        (r, s): (U256, U256),
+    ) -> Result<Self, ForkError> {
+        let from = recover_sender(sighash, u256_to_h256(r), u256_to_h256(s), odd)
+            .ok_or_else(invalid_signature)?;
+        tx.set_from(from);
+
+        Ok(Self {
+            tx,
+            hash: keccak256(raw),
+            chain_id,
+            v,
+            r,
+            s,
+        })
+    }
+}
+
+fn invalid_signature() -> ForkError {
+    ForkError::InvalidTransaction("invalid signature".to_string())
+}
+
+fn y_parity(v: u64) -> Result<bool, DecoderError> {
+    match v {
+        0 | 1 => Ok(v == 1),
+        _ => Err(DecoderError::Custom("yParity isn't 0 or 1")),
+    }
+}
+
+fn decode_to(rlp: &Rlp) -> Result<Option<Address>, DecoderError> {
+    if rlp.is_empty() {
+        Ok(None)
+    } else {
+        rlp.as_val().map(Some)
+    }
+}
+
+fn decode_access_list(rlp: &Rlp) -> Result<AccessList, DecoderError> {
+    let mut items = vec![];
+    for item in rlp.iter() {
+        items.push(AccessListItem {
+            address: item.val_at(0)?,
+            storage_keys: item.list_at(1)?,
+        });
+    }
+    Ok(AccessList(items))
+}
+
+/// The hash the sender signed, which is the envelope without the signature, with the chain id
+/// in place of it for EIP-155 legacy transactions.
+fn signing_hash(
+    prefix: Option<u8>,
+    rlp: &Rlp,
+    fields: usize,
+    eip155_chain_id: Option<u64>,
+) -> Result<H256, DecoderError> {
+    let mut stream = RlpStream::new_list(fields + if eip155_chain_id.is_some() { 3 } else { 0 });
+    for i in 0..fields {
+        stream.append_raw(rlp.at(i)?.as_raw(), 1);
+    }
+    if let Some(chain_id) = eip155_chain_id {
+        stream.append(&chain_id);
+        stream.append(&0u8);
+        stream.append(&0u8);
+    }
+
+    let mut payload = prefix.into_iter().collect::<Vec<u8>>();
+    payload.extend_from_slice(&stream.out());
+    Ok(keccak256(payload))
+}
+
+fn recover_sender(sighash: H256, r: H256, s: H256, odd: bool) -> Option<Address> {
+    if !is_valid_signature(r, s, true) {
+        return None;
+    }
+
+    let mut sig = [0; 64];
+    sig[..32].copy_from_slice(&r.0);
+    sig[32..].copy_from_slice(&s.0);
+
+    let sig =
+        RecoverableSignature::from_compact(&sig, RecoveryId::from_i32(odd.into()).ok()?).ok()?;
+    let public = SECP256K1
+        .recover(&Message::from_slice(sighash.as_bytes()).ok()?, &sig)
+        .ok()?;
+
+    Some(Address::from_slice(
+        &keccak256(&public.serialize_uncompressed()[1..]).as_bytes()[12..],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use address_literal::addr;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_decode_eip155_transaction() {
+        // the example of https://eips.ethereum.org/EIPS/eip-155
+        let raw = hex::decode("f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83").unwrap();
+        let signed = SignedTransaction::decode(&raw).unwrap();
+
+        assert_eq!(signed.chain_id, Some(1));
+        assert_eq!(signed.tx.nonce(), Some(&9.into()));
+        assert_eq!(
+            signed.tx.from(),
+            Some(&addr!("0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f"))
+        );
+        assert_eq!(
+            signed.hash,
+            H256::from_str("33469b22e9f636356c4160a87eb19df52b7412e8eac32a4a55ffe88ea8350788")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decode_typed_transactions() {
+        let sender = addr!("0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f");
+
+        // an EIP-2930 transaction with a yParity of 0
+        let raw = hex::decode("01f8ab01098504a817c80082c350943535353535353535353535353535353535353535880de0b6b3a764000084d0e30db0f838f794c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2e1a0000000000000000000000000000000000000000000000000000000000000000180a0d47644539acec3da5e3ecf5fe8863c628a9c97e8b71e9ea9167a6f4f83c03c32a02b5e9d41c59f7fa7c86dd88be837226cd9acf5762aa7b3e71976b82e1f93bbca").unwrap();
+        let signed = SignedTransaction::decode(&raw).unwrap();
+        assert!(matches!(signed.tx, TypedTransaction::Eip2930(_)));
+        assert_eq!(signed.chain_id, Some(1));
+        assert_eq!(signed.tx.nonce(), Some(&9.into()));
+        assert_eq!(signed.tx.from(), Some(&sender));
+        assert_eq!(
+            signed.hash,
+            H256::from_str("df5cd7708b51903ab52a19faa2de44a7b961d54dff44f46a6f8148ba9c86a872")
+                .unwrap()
+        );
+
+        // an EIP-1559 transaction with a yParity of 1
+        let raw = hex::decode("02f8af010a847735940085174876e80082c350943535353535353535353535353535353535353535880de0b6b3a764000084d0e30db0f838f794c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2e1a0000000000000000000000000000000000000000000000000000000000000000101a0f30e4bd8094e53a679ddb8f55b5216b03c44623fc4279ef0791f9aa1f6930d499ff4f80bf6fb1864b578662b451a32575b46f71a713dd330c262b9b4285d104c").unwrap();
+        let signed = SignedTransaction::decode(&raw).unwrap();
+        match &signed.tx {
+            TypedTransaction::Eip1559(tx) => {
+                assert_eq!(tx.chain_id, Some(1.into()));
+                assert_eq!(tx.max_fee_per_gas, Some(100_000_000_000u64.into()));
+                assert_eq!(tx.access_list.0.len(), 1);
+            }
+            tx => panic!("not an EIP-1559 transaction: {:?}", tx),
+        }
+        assert_eq!(signed.tx.from(), Some(&sender));
+        assert_eq!(
+            signed.hash,
+            H256::from_str("0f6f11ca4284744a6bb5bbaa97ceb1fe9adb99400a97e3e07901ba85eeda591c")
+                .unwrap()
+        );
+
+        // the same one with a yParity of 2
+        let raw = hex::decode("02f8af010a847735940085174876e80082c350943535353535353535353535353535353535353535880de0b6b3a764000084d0e30db0f838f794c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2e1a0000000000000000000000000000000000000000000000000000000000000000102a0f30e4bd8094e53a679ddb8f55b5216b03c44623fc4279ef0791f9aa1f6930d499ff4f80bf6fb1864b578662b451a32575b46f71a713dd330c262b9b4285d104c").unwrap();
+        assert!(matches!(
+            SignedTransaction::decode(&raw),
+            Err(ForkError::InvalidTransaction(_))
+        ));
+    }
+}
@@ -1,9 +1,10 @@
 use crate::akula::types::{Account, Incarnation, PartialHeader};
 use crate::akula::utils::keccak256;
+use crate::error::ForkError;
 use bytes::Bytes;
 use ethers::types::U256;
-use ethers::types::{Address, H256};
-use rusqlite::{params, Connection, OpenFlags};
+use ethers::types::{Address, Transaction, TransactionReceipt, H256};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
 use std::path::Path;
 use std::str::FromStr;
 
@@ -14,10 +15,9 @@ pub struct SqliteBackend {
 
 impl SqliteBackend {
     /// Open the sqlite database in read only mode.
-    pub fn new<P: AsRef<Path>>(path: P) -> Self {
-        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
-            .expect("failed to open sqlite dumper");
-        Self { db }
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(Self { db })
     }
 
     pub fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
@@ -29,7 +29,7 @@ impl SqliteBackend {
                 params![address_text.as_str()],
                 |row| row.get(0),
             )
-            .map_err(|_| anyhow::anyhow!("failed to get balance"))?;
+            .map_err(|e| query_error(e, format!("balance of {:?}", address)))?;
         let nonce_text: String = self
             .db
             .query_row(
@@ -37,7 +37,7 @@ impl SqliteBackend {
                 params![address_text.as_str()],
                 |row| row.get(0),
             )
-            .map_err(|_| anyhow::anyhow!("failed to get nonce"))?;
+            .map_err(|e| query_error(e, format!("nonce of {:?}", address)))?;
         let code_hash_text: String = self
             .db
             .query_row(
@@ -45,7 +45,7 @@ impl SqliteBackend {
                 params![address_text.as_str()],
                 |row| row.get(0),
             )
-            .map_err(|_| anyhow::anyhow!("failed to get code_hash"))?;
+            .map_err(|e| query_error(e, format!("code hash of {:?}", address)))?;
 
         let balance = U256::from_dec_str(balance_text.as_str())?;
         let nonce = U256::from_dec_str(nonce_text.as_str())?;
@@ -68,7 +68,7 @@ impl SqliteBackend {
                 params![code_hash_text],
                 |row| row.get(0),
             )
-            .map_err(|_| anyhow::anyhow!("failed to get code hash"))?;
+            .map_err(|e| query_error(e, format!("code of hash {:?}", code_hash)))?;
         let code = hex::decode(code_text)?;
         Ok(code.into())
     }
@@ -89,8 +89,8 @@ impl SqliteBackend {
                 params![address_text.as_str(), location_text.as_str()],
                 |row| row.get(0),
             )
-            .map_err(|_| anyhow::anyhow!("failed to get storage"))?;
-        let value = H256::from_str(value_text.as_str()).expect("failed to parse storage");
+            .map_err(|e| query_error(e, format!("storage {:?} of {:?}", location, address)))?;
+        let value = H256::from_str(value_text.as_str())?;
         Ok(value)
     }
 
@@ -101,20 +101,21 @@ impl SqliteBackend {
                 params![block_number],
                 |row| {
                     Ok((
-                        row.get(0).unwrap(),
-                        row.get(1).unwrap(),
-                        row.get(2).unwrap(),
-                        row.get(3).unwrap(),
-                        row.get(4).unwrap(),
-                        row.get(5).unwrap(),
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
                     ))
                 },
             )
-            .map_err(|_| anyhow::anyhow!("failed to get block info"))?;
-        let hash = H256::from_str(hash_text.as_str()).unwrap();
-        let base_fee_per_gas = U256::from_dec_str(base_fee_per_gas_text.as_str()).unwrap();
-        let difficulty = U256::from_dec_str(difficulty_text.as_str()).unwrap();
-        let beneficiary = Address::from_str(beneficiary_text.as_str()).unwrap();
+            .map_err(|e| query_error(e, format!("block {}", block_number)))?;
+        let hash = H256::from_str(hash_text.as_str())?;
+        let base_fee_per_gas = U256::from_dec_str(base_fee_per_gas_text.as_str())?;
+        let difficulty = U256::from_dec_str(difficulty_text.as_str())?;
+        let beneficiary = Address::from_str(beneficiary_text.as_str())?;
+        let chain_id = self.read_chain_id()?;
 
         Ok(Some(PartialHeader {
             difficulty,
@@ -124,8 +125,88 @@ impl SqliteBackend {
             base_fee_per_gas: Some(base_fee_per_gas.into()),
             hash,
             beneficiary,
+            chain_id,
         }))
     }
+
+    /// The chain id of the archive node the database was recorded from. The databases
+    /// recorded before it was kept are mainnet's.
+    pub fn read_chain_id(&self) -> anyhow::Result<u64> {
+        let recorded: bool = self.db.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type == 'table' AND name == 'chain')",
+            [],
+            |row| row.get(0),
+        )?;
+        if !recorded {
+            return Ok(1);
+        }
+
+        let chain_id = self
+            .db
+            .query_row("SELECT chain_id FROM chain", [], |row| row.get(0))
+            .optional()?;
+        Ok(chain_id.unwrap_or(1))
+    }
+
+    pub fn read_block_transactions(
+        &self,
+        block_number: u64,
+    ) -> anyhow::Result<Option<Vec<(Transaction, TransactionReceipt)>>> {
+        let transactions_text: String = self
+            .db
+            .query_row(
+                "SELECT transactions FROM block_transactions WHERE number == ?1",
+                params![block_number],
+                |row| row.get(0),
+            )
+            .map_err(|e| query_error(e, format!("transactions of block {}", block_number)))?;
+        Ok(Some(serde_json::from_str(transactions_text.as_str())?))
+    }
+
+    pub fn read_block_storage_changes(
+        &self,
+        block_number: u64,
+    ) -> anyhow::Result<Vec<(H256, Address, H256)>> {
+        let changes_text: String = self
+            .db
+            .query_row(
+                "SELECT changes FROM block_storage_changes WHERE number == ?1",
+                params![block_number],
+                |row| row.get(0),
+            )
+            .map_err(|e| query_error(e, format!("storage changes of block {}", block_number)))?;
+        Ok(serde_json::from_str(changes_text.as_str())?)
+    }
+
+    /// The value of the storage slot at the end of the block.
+    pub fn read_storage_after_block(
+        &self,
+        block_number: u64,
+        address: Address,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        let address_text = hex::encode(address.as_bytes());
+        let location_text = hex::encode(location.as_bytes());
+
+        let value_text: String = self
+            .db
+            .query_row(
+                "SELECT value FROM block_storage WHERE number == ?1 AND address == ?2 AND slot == ?3",
+                params![block_number, address_text.as_str(), location_text.as_str()],
+                |row| row.get(0),
+            )
+            .map_err(|e| {
+                query_error(
+                    e,
+                    format!(
+                        "storage {:?} of {:?} after block {}",
+                        location, address, block_number
+                    ),
+                )
+            })?;
+        let value = H256::from_str(value_text.as_str())?;
+        Ok(value)
+    }
 }
 
 #[derive(Debug)]
@@ -134,8 +215,8 @@ pub struct SqliteDumper {
 }
 
 impl SqliteDumper {
-    pub fn new<P: AsRef<Path>>(path: P) -> Self {
-        let db = Connection::open(path).expect("failed to open sqlite dumper");
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let db = Connection::open(path)?;
 
         db.execute_batch(r"
             BEGIN;
@@ -145,20 +226,34 @@ impl SqliteDumper {
             DROP TABLE IF EXISTS code;
             DROP TABLE IF EXISTS storage;
             DROP TABLE IF EXISTS block;
+            DROP TABLE IF EXISTS block_transactions;
+            DROP TABLE IF EXISTS block_storage;
+            DROP TABLE IF EXISTS block_storage_changes;
+            DROP TABLE IF EXISTS chain;
 
             CREATE TABLE balance(address TEXT NOT NULL, balance TEXT NOT NULL);
             CREATE TABLE nonce(address TEXT NOT NULL, nonce TEXT NOT NULL);
             CREATE TABLE code(address TEXT NOT NULL, hash TEXT NOT NULL, code TEXT NOT NULL);
             CREATE TABLE storage(address TEXT NOT NULL, slot TEXT NOT NULL, value TEXT NOT NULL);
             CREATE TABLE block(number INTEGER, hash TEXT NOT NULL, base_fee_per_gas TEXT NOT NULL, timestamp INTEGER, gas_limit INTEGER, difficulty TEXT NOT NULL, beneficiary TEXT NOT NULL);
+            CREATE TABLE block_transactions(number INTEGER, transactions TEXT NOT NULL);
+            CREATE TABLE block_storage(number INTEGER, address TEXT NOT NULL, slot TEXT NOT NULL, value TEXT NOT NULL);
+            CREATE TABLE block_storage_changes(number INTEGER, changes TEXT NOT NULL);
+            CREATE TABLE chain(chain_id INTEGER NOT NULL);
 
             COMMIT;
-        ").expect("failed to initialize database");
+        ")?;
 
-        Self { db }
+        Ok(Self { db })
     }
 
-    pub fn dump_address(&mut self, address: Address, balance: U256, nonce: U256, code: Vec<u8>) {
+    pub fn dump_address(
+        &mut self,
+        address: Address,
+        balance: U256,
+        nonce: U256,
+        code: Vec<u8>,
+    ) -> anyhow::Result<()> {
         let address_text = hex::encode(address.as_bytes());
         let balance_text = format!("{}", balance);
         let nonce_text = format!("{}", nonce);
@@ -167,37 +262,31 @@ impl SqliteDumper {
         let code_hash_text = hex::encode(code_hash.as_bytes());
         let code_text = hex::encode(code.as_slice());
 
-        self.db
-            .execute(
-                "INSERT INTO balance (address, balance) VALUES (?1, ?2)",
-                params![address_text.as_str(), balance_text],
-            )
-            .expect("failed to insert to balance");
-        self.db
-            .execute(
-                "INSERT INTO nonce (address, nonce) VALUES (?1, ?2)",
-                params![address_text.as_str(), nonce_text],
-            )
-            .expect("failed to insert to nonce");
-        self.db
-            .execute(
-                "INSERT INTO code(address, hash, code) VALUES(?1, ?2, ?3)",
-                params![address_text.as_str(), code_hash_text, code_text],
-            )
-            .expect("failed to insert to code");
+        self.db.execute(
+            "INSERT INTO balance (address, balance) VALUES (?1, ?2)",
+            params![address_text.as_str(), balance_text],
+        )?;
+        self.db.execute(
+            "INSERT INTO nonce (address, nonce) VALUES (?1, ?2)",
+            params![address_text.as_str(), nonce_text],
+        )?;
+        self.db.execute(
+            "INSERT INTO code(address, hash, code) VALUES(?1, ?2, ?3)",
+            params![address_text.as_str(), code_hash_text, code_text],
+        )?;
+        Ok(())
     }
 
-    pub fn dump_storage(&mut self, address: Address, key: H256, value: H256) {
+    pub fn dump_storage(&mut self, address: Address, key: H256, value: H256) -> anyhow::Result<()> {
         let address_text = hex::encode(address.as_bytes());
         let key_text = hex::encode(key.as_bytes());
         let value_text = hex::encode(value.as_bytes());
 
-        self.db
-            .execute(
-                "INSERT INTO storage(address, slot, value) VALUES(?1, ?2, ?3)",
-                params![address_text, key_text, value_text],
-            )
-            .expect("failed to insert to storage");
+        self.db.execute(
+            "INSERT INTO storage(address, slot, value) VALUES(?1, ?2, ?3)",
+            params![address_text, key_text, value_text],
+        )?;
+        Ok(())
     }
 
     pub fn dump_block_header(
@@ -209,19 +298,85 @@ impl SqliteDumper {
         gas_limit: u64,
         difficulty: U256,
         beneficiary: Address,
-    ) {
+    ) -> anyhow::Result<()> {
         let hash_text = hex::encode(hash.as_bytes());
         let base_fee_per_gas_text = format!("{:?}", base_fee_per_gas);
         let difficulty_text = format!("{:?}", difficulty);
         let beneficiary_text = hex::encode(beneficiary.as_bytes());
 
-        self.db.execute("INSERT INTO block(number, hash, base_fee_per_gas, timestamp, gas_limit, difficulty, beneficiary) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)", params![block_number, hash_text, base_fee_per_gas_text, timestamp, gas_limit, difficulty_text, beneficiary_text]).expect("failed to insert to block header");
+        self.db.execute("INSERT INTO block(number, hash, base_fee_per_gas, timestamp, gas_limit, difficulty, beneficiary) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)", params![block_number, hash_text, base_fee_per_gas_text, timestamp, gas_limit, difficulty_text, beneficiary_text])?;
+        Ok(())
+    }
+
+    /// The transactions and receipts are kept as the archive node's json.
+    pub fn dump_block_transactions(
+        &mut self,
+        block_number: u64,
+        transactions: &[(Transaction, TransactionReceipt)],
+    ) -> anyhow::Result<()> {
+        let transactions_text = serde_json::to_string(transactions)?;
+
+        self.db.execute(
+            "INSERT INTO block_transactions(number, transactions) VALUES(?1, ?2)",
+            params![block_number, transactions_text],
+        )?;
+        Ok(())
+    }
+
+    /// The changes are kept as json, like the transactions.
+    pub fn dump_block_storage_changes(
+        &mut self,
+        block_number: u64,
+        changes: &[(H256, Address, H256)],
+    ) -> anyhow::Result<()> {
+        let changes_text = serde_json::to_string(changes)?;
+
+        self.db.execute(
+            "INSERT INTO block_storage_changes(number, changes) VALUES(?1, ?2)",
+            params![block_number, changes_text],
+        )?;
+        Ok(())
+    }
+
+    /// There's a single chain id, the one recorded last.
+    pub fn dump_chain_id(&mut self, chain_id: u64) -> anyhow::Result<()> {
+        self.db.execute("DELETE FROM chain", [])?;
+        self.db
+            .execute("INSERT INTO chain(chain_id) VALUES(?1)", params![chain_id])?;
+        Ok(())
+    }
+
+    pub fn dump_storage_after_block(
+        &mut self,
+        block_number: u64,
+        address: Address,
+        key: H256,
+        value: H256,
+    ) -> anyhow::Result<()> {
+        let address_text = hex::encode(address.as_bytes());
+        let key_text = hex::encode(key.as_bytes());
+        let value_text = hex::encode(value.as_bytes());
+
+        self.db.execute(
+            "INSERT INTO block_storage(number, address, slot, value) VALUES(?1, ?2, ?3, ?4)",
+            params![block_number, address_text, key_text, value_text],
+        )?;
+        Ok(())
+    }
+}
+
+/// A missing row means the data was never recorded, anything else is a real database error.
+fn query_error(e: rusqlite::Error, what: String) -> anyhow::Error {
+    match e {
+        rusqlite::Error::QueryReturnedNoRows => ForkError::BackendMiss(what).into(),
+        e => anyhow::Error::new(e).context(what),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::akula::types::Incarnation;
+    use crate::error::ForkError;
     use crate::sqlite_backend::{SqliteBackend, SqliteDumper};
     use address_literal::addr;
     use ethers::types::H256;
@@ -240,33 +395,60 @@ mod tests {
 
         // save to the file
         {
-            let mut dumper = SqliteDumper::new(file_path.clone());
+            let mut dumper = SqliteDumper::new(file_path.clone()).unwrap();
 
-            dumper.dump_address(
-                addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
-                u256!(1234),
-                u256!(5678),
-                vec![8, 9, 10],
-            );
-            dumper.dump_storage(
-                addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
-                rand_hash_1,
-                rand_hash_2,
-            );
-            dumper.dump_block_header(
-                13330,
-                rand_hash_3,
-                u256!(6666),
-                1239,
-                9999,
-                u256!(11111122222233333),
-                addr!("0x2260fac5e5542a773aa44fbcfedf7c193bc2c599"),
-            );
+            dumper
+                .dump_address(
+                    addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+                    u256!(1234),
+                    u256!(5678),
+                    vec![8, 9, 10],
+                )
+                .unwrap();
+            dumper
+                .dump_storage(
+                    addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+                    rand_hash_1,
+                    rand_hash_2,
+                )
+                .unwrap();
+            dumper
+                .dump_block_header(
+                    13330,
+                    rand_hash_3,
+                    u256!(6666),
+                    1239,
+                    9999,
+                    u256!(11111122222233333),
+                    addr!("0x2260fac5e5542a773aa44fbcfedf7c193bc2c599"),
+                )
+                .unwrap();
+            dumper.dump_block_transactions(13330, &[]).unwrap();
+            dumper
+                .dump_block_storage_changes(
+                    13330,
+                    &[(
+                        rand_hash_2,
+                        addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+                        rand_hash_1,
+                    )],
+                )
+                .unwrap();
+            dumper.dump_chain_id(1).unwrap();
+            dumper.dump_chain_id(5).unwrap();
+            dumper
+                .dump_storage_after_block(
+                    13330,
+                    addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+                    rand_hash_1,
+                    rand_hash_3,
+                )
+                .unwrap();
         }
 
         // load it again
         {
-            let backend = SqliteBackend::new(file_path.clone());
+            let backend = SqliteBackend::new(file_path.clone()).unwrap();
 
             let account = backend
                 .read_account(addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"))
@@ -302,8 +484,59 @@ mod tests {
                 header.beneficiary,
                 addr!("0x2260fac5e5542a773aa44fbcfedf7c193bc2c599")
             );
+            assert_eq!(header.chain_id, 5);
+
+            assert_eq!(backend.read_chain_id().unwrap(), 5);
+            assert_eq!(
+                backend.read_block_storage_changes(13330).unwrap(),
+                vec![(
+                    rand_hash_2,
+                    addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+                    rand_hash_1
+                )]
+            );
+            assert_eq!(
+                backend.read_block_transactions(13330).unwrap(),
+                Some(vec![])
+            );
+            assert_eq!(
+                backend
+                    .read_storage_after_block(
+                        13330,
+                        addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+                        rand_hash_1,
+                    )
+                    .unwrap(),
+                rand_hash_3
+            );
+
+            // anything not recorded is a backend miss
+            let err = backend.read_block_header(13331).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<ForkError>(),
+                Some(ForkError::BackendMiss(_))
+            ));
         }
 
         dir.close().unwrap();
     }
+
+    #[test]
+    fn test_database_without_chain_id() {
+        // recorded before the chain id was kept
+        let backend = SqliteBackend::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/simple-public-view.db"
+        ))
+        .unwrap();
+        assert_eq!(backend.read_chain_id().unwrap(), 1);
+        assert_eq!(
+            backend
+                .read_block_header(13458689)
+                .unwrap()
+                .unwrap()
+                .chain_id,
+            1
+        );
+    }
 }
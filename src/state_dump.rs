@@ -0,0 +1,278 @@
+use crate::tracers::state_diff::AccountState;
+use ethers::types::{Address, Bytes, H256, U256, U64};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// An account of geth's genesis `alloc`, which anvil and hardhat take too. The quantities can
+/// be hex or decimal, like geth reads them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenesisAccount {
+    #[serde(default, deserialize_with = "deserialize_quantity")]
+    pub balance: U256,
+    #[serde(
+        default,
+        serialize_with = "serialize_hex_u64",
+        deserialize_with = "deserialize_u64"
+    )]
+    pub nonce: u64,
+    #[serde(default, skip_serializing_if = "is_empty")]
+    pub code: Bytes,
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        deserialize_with = "deserialize_storage"
+    )]
+    pub storage: BTreeMap<H256, H256>,
+}
+
+/// The accounts of geth's genesis file, by address.
+pub type GenesisAlloc = BTreeMap<Address, GenesisAccount>;
+
+/// Reads a whole genesis file, its `alloc` alone, or a state file written by anvil's
+/// `--dump-state`.
+pub fn parse_alloc(json: &str) -> anyhow::Result<GenesisAlloc> {
+    let value: Value = serde_json::from_str(json)?;
+    let accounts = ["alloc", "accounts"]
+        .iter()
+        .find_map(|key| value.get(*key).cloned())
+        .unwrap_or(value);
+    Ok(serde_json::from_value(accounts)?)
+}
+
+impl From<AccountState> for GenesisAccount {
+    fn from(account: AccountState) -> Self {
+        Self {
+            balance: account.balance,
+            nonce: account.nonce,
+            code: account.code,
+            storage: account.storage,
+        }
+    }
+}
+
+impl From<GenesisAccount> for AccountState {
+    fn from(account: GenesisAccount) -> Self {
+        Self {
+            balance: account.balance,
+            nonce: account.nonce,
+            code: account.code,
+            storage: account.storage,
+        }
+    }
+}
+
+/// The state of anvil's `anvil_dumpState` and `anvil_loadState`, which is hex encoded JSON.
+/// Unlike the genesis `alloc`, the nonce is a number and the storage slots are quantities.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AnvilState {
+    pub accounts: BTreeMap<Address, AnvilAccount>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AnvilAccount {
+    #[serde(default, deserialize_with = "deserialize_u64")]
+    pub nonce: u64,
+    #[serde(default, deserialize_with = "deserialize_quantity")]
+    pub balance: U256,
+    #[serde(default)]
+    pub code: Bytes,
+    #[serde(
+        default,
+        serialize_with = "serialize_storage_quantities",
+        deserialize_with = "deserialize_storage"
+    )]
+    pub storage: BTreeMap<H256, H256>,
+}
+
+impl From<GenesisAlloc> for AnvilState {
+    fn from(alloc: GenesisAlloc) -> Self {
+        let accounts = alloc
+            .into_iter()
+            .map(|(address, account)| {
+                let account = AnvilAccount {
+                    nonce: account.nonce,
+                    balance: account.balance,
+                    code: account.code,
+                    storage: account.storage,
+                };
+                (address, account)
+            })
+            .collect();
+        Self { accounts }
+    }
+}
+
+impl From<AnvilState> for GenesisAlloc {
+    fn from(state: AnvilState) -> Self {
+        state
+            .accounts
+            .into_iter()
+            .map(|(address, account)| {
+                let account = GenesisAccount {
+                    balance: account.balance,
+                    nonce: account.nonce,
+                    code: account.code,
+                    storage: account.storage,
+                };
+                (address, account)
+            })
+            .collect()
+    }
+}
+
+fn is_empty(code: &Bytes) -> bool {
+    code.as_ref().is_empty()
+}
+
+/// A JSON number, or a hex or decimal string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Quantity {
+    Number(u64),
+    Text(String),
+}
+
+impl Quantity {
+    fn parse(self) -> Result<U256, String> {
+        match self {
+            Quantity::Number(n) => Ok(n.into()),
+            Quantity::Text(text) => match text.strip_prefix("0x") {
+                Some("") => Ok(U256::zero()),
+                Some(hex) => U256::from_str_radix(hex, 16).map_err(|e| e.to_string()),
+                None => U256::from_dec_str(&text).map_err(|e| e.to_string()),
+            },
+        }
+    }
+}
+
+/// A quantity of geth's genesis file, e.g. its `timestamp`.
+pub(crate) fn parse_quantity(value: &Value) -> anyhow::Result<U256> {
+    let quantity = Quantity::deserialize(value).map_err(anyhow::Error::from)?;
+    quantity.parse().map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Like `parse_quantity()`, for the fields which are a `u64`.
+pub(crate) fn parse_u64(value: &Value) -> anyhow::Result<u64> {
+    to_u64(parse_quantity(value)?).map_err(|e| anyhow::anyhow!(e))
+}
+
+fn to_u64(value: U256) -> Result<u64, String> {
+    if value > U256::from(u64::MAX) {
+        return Err(format!("{} overflows u64", value));
+    }
+    Ok(value.as_u64())
+}
+
+fn deserialize_quantity<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+    Quantity::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    to_u64(deserialize_quantity(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn serialize_hex_u64<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    U64::from(*value).serialize(serializer)
+}
+
+/// The slots and values can be short quantities, like anvil writes them, or full words.
+fn deserialize_storage<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<H256, H256>, D::Error> {
+    let storage = BTreeMap::<String, Quantity>::deserialize(deserializer)?;
+    let word = |quantity: Quantity| -> Result<H256, D::Error> {
+        let value = quantity.parse().map_err(serde::de::Error::custom)?;
+        let mut word = H256::zero();
+        value.to_big_endian(word.as_bytes_mut());
+        Ok(word)
+    };
+
+    storage
+        .into_iter()
+        .map(|(key, value)| Ok((word(Quantity::Text(key))?, word(value)?)))
+        .collect()
+}
+
+fn serialize_storage_quantities<S: Serializer>(
+    storage: &BTreeMap<H256, H256>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let quantity = |word: &H256| U256::from_big_endian(word.as_bytes());
+    serializer.collect_map(
+        storage
+            .iter()
+            .map(|(key, value)| (quantity(key), quantity(value))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use address_literal::addr;
+    use serde_json::json;
+    use u256_literal::u256;
+
+    #[test]
+    fn test_parse_alloc() {
+        let genesis = json!({
+            "config": { "chainId": 1 },
+            "alloc": {
+                "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2": {
+                    "balance": "1000000000000000000",
+                    "nonce": "0x2",
+                    "code": "0x6000",
+                    "storage": {
+                        "0x0000000000000000000000000000000000000000000000000000000000000001": "0x02"
+                    }
+                },
+                "0x2260fac5e5542a773aa44fbcfedf7c193bc2c599": { "balance": "0x10" }
+            }
+        });
+        let alloc = parse_alloc(&genesis.to_string()).unwrap();
+
+        let weth = &alloc[&addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2")];
+        assert_eq!(weth.balance, u256!(1000000000000000000));
+        assert_eq!(weth.nonce, 2);
+        assert_eq!(weth.code, Bytes::from(vec![0x60, 0x00]));
+        assert_eq!(
+            weth.storage[&H256::from_low_u64_be(1)],
+            H256::from_low_u64_be(2)
+        );
+        let wbtc = &alloc[&addr!("0x2260fac5e5542a773aa44fbcfedf7c193bc2c599")];
+        assert_eq!(wbtc.balance, u256!(16));
+        assert!(wbtc.storage.is_empty());
+
+        // the alloc alone, as it's exported
+        let exported = serde_json::to_string(&alloc).unwrap();
+        assert_eq!(parse_alloc(&exported).unwrap(), alloc);
+    }
+
+    #[test]
+    fn test_anvil_state() {
+        let dump = json!({
+            "accounts": {
+                "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2": {
+                    "nonce": 1,
+                    "balance": "0x64",
+                    "code": "0x",
+                    "storage": { "0x1": "0x2" }
+                }
+            }
+        });
+        let state: AnvilState = serde_json::from_value(dump.clone()).unwrap();
+        let account = &state.accounts[&addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2")];
+        assert_eq!(account.nonce, 1);
+        assert_eq!(account.balance, u256!(100));
+        assert_eq!(
+            account.storage[&H256::from_low_u64_be(1)],
+            H256::from_low_u64_be(2)
+        );
+
+        // anvil reads it back the way it wrote it
+        assert_eq!(serde_json::to_value(&state).unwrap(), dump);
+        assert_eq!(AnvilState::from(GenesisAlloc::from(state.clone())), state);
+    }
+}
@@ -1,25 +1,53 @@
 use crate::akula::interface::State;
 use crate::akula::types::{Account, Incarnation, PartialHeader};
+use crate::cassette::RemoteClient;
+use crate::error::ForkError;
 use crate::forked_backend::Web3RemoteState;
+use crate::memory_state::{Genesis, MemoryState};
 use crate::sqlite_backend::{SqliteBackend, SqliteDumper};
 use async_trait::async_trait;
 use bytes::Bytes;
 use ethers::abi::ethereum_types::{Address, H256};
+use ethers::providers::SubscriptionStream;
+use ethers::types::{Block, Log, Transaction, TransactionReceipt};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 pub enum BackendConfig {
-    AllViaWeb3 { wss_url: String },
-    TeeWeb3ToLocal { wss_url: String, db_path: PathBuf },
-    LocalOnly { db_path: PathBuf },
+    AllViaWeb3 {
+        wss_url: String,
+    },
+    TeeWeb3ToLocal {
+        wss_url: String,
+        db_path: PathBuf,
+    },
+    LocalOnly {
+        db_path: PathBuf,
+    },
+    InMemory {
+        genesis: Genesis,
+    },
+    Custom {
+        state: Arc<dyn State>,
+    },
+    RecordWeb3 {
+        wss_url: String,
+        cassette_path: PathBuf,
+    },
+    ReplayWeb3 {
+        cassette_path: PathBuf,
+    },
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct StateMuxer {
     web3: Option<Web3RemoteState>,
     dumper: Option<Arc<Mutex<SqliteDumper>>>,
     db: Option<Arc<Mutex<SqliteBackend>>>,
+    // a backend of its own, e.g. a chain started from a genesis, there's no archive node
+    // or database behind it
+    state: Option<Arc<dyn State>>,
 }
 
 impl StateMuxer {
@@ -29,33 +57,283 @@ impl StateMuxer {
                 web3: Some(Web3RemoteState::new(state_block_number, wss_url.as_str()).await?),
                 dumper: None,
                 db: None,
+                state: None,
             },
             BackendConfig::TeeWeb3ToLocal { wss_url, db_path } => Self {
                 web3: Some(Web3RemoteState::new(state_block_number, wss_url.as_str()).await?),
-                dumper: Some(Arc::new(Mutex::new(SqliteDumper::new(db_path)))),
+                dumper: Some(Arc::new(Mutex::new(SqliteDumper::new(db_path)?))),
                 db: None,
+                state: None,
             },
             BackendConfig::LocalOnly { db_path } => Self {
                 web3: None,
                 dumper: None,
-                db: Some(Arc::new(Mutex::new(SqliteBackend::new(db_path)))),
+                db: Some(Arc::new(Mutex::new(SqliteBackend::new(db_path)?))),
+                state: None,
+            },
+            BackendConfig::InMemory { genesis } => Self {
+                web3: None,
+                dumper: None,
+                db: None,
+                state: Some(Arc::new(MemoryState::new(&genesis))),
+            },
+            BackendConfig::RecordWeb3 {
+                wss_url,
+                cassette_path,
+            } => Self {
+                web3: Some(
+                    Web3RemoteState::record(state_block_number, wss_url.as_str(), cassette_path)
+                        .await?,
+                ),
+                dumper: None,
+                db: None,
+                state: None,
+            },
+            BackendConfig::ReplayWeb3 { cassette_path } => Self {
+                web3: Some(Web3RemoteState::replay(state_block_number, cassette_path).await?),
+                dumper: None,
+                db: None,
+                state: None,
+            },
+            BackendConfig::Custom { state } => Self {
+                web3: None,
+                dumper: None,
+                db: None,
+                state: Some(state),
             },
         };
 
+        // the database is replayed without the archive node, it has to know the chain too
+        if this.dumper.is_some() {
+            this.read_chain_id().await?;
+        }
+
         Ok(this)
     }
+
+    /// The state at an earlier block, only the archive node has it, the local database only
+    /// records the fork block.
+    pub fn at_block(&self, block_number: u64) -> Result<Self, ForkError> {
+        let web3 = self
+            .web3
+            .as_ref()
+            .ok_or_else(|| ForkError::BackendMiss(format!("state at block {}", block_number)))?;
+
+        Ok(Self {
+            web3: Some(web3.at_block(block_number)),
+            dumper: None,
+            db: None,
+            state: None,
+        })
+    }
+
+    /// The state at a later block of the chain, the archive node's cache is carried over. Like
+    /// `at_block()`, nothing is recorded.
+    pub async fn advance(&self, block_number: u64) -> anyhow::Result<Self> {
+        Ok(Self {
+            web3: Some(self.web3()?.advance(block_number).await),
+            dumper: None,
+            db: None,
+            state: None,
+        })
+    }
+
+    /// The new blocks of the archive node, as they're mined.
+    pub async fn subscribe_new_heads(
+        &self,
+    ) -> anyhow::Result<SubscriptionStream<'_, RemoteClient, Block<H256>>> {
+        self.web3()?.subscribe_new_heads().await
+    }
+
+    /// The header the block after `block_number` will likely have, only the archive node
+    /// knows the latest blocks.
+    pub async fn read_pending_header(
+        &self,
+        block_number: u64,
+    ) -> anyhow::Result<Option<PartialHeader>> {
+        self.web3()?.read_pending_header(block_number).await
+    }
+
+    pub async fn read_block_logs(&self, block_number: u64) -> anyhow::Result<Vec<Log>> {
+        self.web3()?.read_block_logs(block_number).await
+    }
+
+    /// Another fork block of the same chain, the connection to the archive node and its code
+    /// cache are shared. Like `new()`, the state is read from the database if it exists,
+    /// otherwise it's recorded into it.
+    pub fn fork_at(&self, block_number: u64, db_path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let web3 = self.web3.as_ref().map(|web3| web3.at_block(block_number));
+        match db_path {
+            Some(db_path) if db_path.exists() => Ok(Self {
+                web3,
+                dumper: None,
+                db: Some(Arc::new(Mutex::new(SqliteBackend::new(db_path)?))),
+                state: None,
+            }),
+            db_path => {
+                let web3 = web3.ok_or_else(no_archive_node)?;
+                let dumper = match db_path {
+                    Some(db_path) => Some(Arc::new(Mutex::new(SqliteDumper::new(db_path)?))),
+                    None => None,
+                };
+                Ok(Self {
+                    web3: Some(web3),
+                    dumper,
+                    db: None,
+                    state: None,
+                })
+            }
+        }
+    }
+
+    /// The same backend with `state` read first, e.g. the changes of the local blocks on top
+    /// of this one. Nothing is recorded.
+    pub fn with_state(&self, state: Arc<dyn State>) -> Self {
+        Self {
+            web3: self.web3.clone(),
+            dumper: None,
+            db: self.db.clone(),
+            state: Some(state),
+        }
+    }
+
+    /// Moves the cassette of `BackendConfig::RecordWeb3` into place once everything it needs
+    /// is recorded.
+    pub fn finish_recording(&self) -> anyhow::Result<()> {
+        match &self.web3 {
+            Some(web3) => web3.finish_recording(),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether there's an archive node behind, rather than only the local database.
+    pub fn is_connected(&self) -> bool {
+        self.web3.is_some()
+    }
+
+    /// The archive node's chain id, recorded like the state. Without an archive node, it's read
+    /// from the database, see `SqliteBackend::read_chain_id()`.
+    pub async fn read_chain_id(&self) -> anyhow::Result<u64> {
+        if let (Some(db), None) = (&self.db, &self.web3) {
+            let lock = db.lock().await;
+            return lock.read_chain_id();
+        }
+
+        let ret = self.web3()?.read_chain_id().await?;
+
+        if let Some(dumper) = &self.dumper {
+            let mut lock = dumper.lock().await;
+            lock.dump_chain_id(ret)?;
+        }
+
+        Ok(ret)
+    }
+
+    /// The transactions of the block with their receipts, recorded like the state.
+    pub async fn read_block_transactions(
+        &self,
+        block_number: u64,
+    ) -> anyhow::Result<Option<Vec<(Transaction, TransactionReceipt)>>> {
+        if let Some(db) = &self.db {
+            let lock = db.lock().await;
+            return lock.read_block_transactions(block_number);
+        }
+
+        let ret = self.web3()?.read_block_transactions(block_number).await?;
+
+        if let Some(dumper) = &self.dumper {
+            let mut lock = dumper.lock().await;
+
+            if let Some(transactions) = &ret {
+                lock.dump_block_transactions(block_number, transactions)?;
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// The storage slots the transactions of the block changed on chain, by the hash of the
+    /// transaction, recorded like the state.
+    pub async fn read_block_storage_changes(
+        &self,
+        block_number: u64,
+    ) -> anyhow::Result<Vec<(H256, Address, H256)>> {
+        if let Some(db) = &self.db {
+            let lock = db.lock().await;
+            return lock.read_block_storage_changes(block_number);
+        }
+
+        let ret = self
+            .web3()?
+            .read_block_storage_changes(block_number)
+            .await?;
+
+        if let Some(dumper) = &self.dumper {
+            let mut lock = dumper.lock().await;
+            lock.dump_block_storage_changes(block_number, &ret)?;
+        }
+
+        Ok(ret)
+    }
+
+    /// The value of the storage slot at the end of the block, recorded like the state.
+    pub async fn read_storage_after_block(
+        &self,
+        block_number: u64,
+        address: Address,
+        location: H256,
+    ) -> anyhow::Result<H256> {
+        if let Some(db) = &self.db {
+            let lock = db.lock().await;
+            return lock.read_storage_after_block(block_number, address, location);
+        }
+
+        let ret = self
+            .web3()?
+            .at_block(block_number)
+            .read_storage(address, Default::default(), location)
+            .await?;
+
+        if let Some(dumper) = &self.dumper {
+            let mut lock = dumper.lock().await;
+            lock.dump_storage_after_block(block_number, address, location, ret)?;
+        }
+
+        Ok(ret)
+    }
+
+    /// Only the archive node can find a transaction.
+    pub async fn read_transaction_block_number(&self, hash: H256) -> anyhow::Result<Option<u64>> {
+        match &self.web3 {
+            Some(web3) => web3.read_transaction_block_number(hash).await,
+            None => Err(ForkError::BackendMiss(format!("transaction {:?}", hash)).into()),
+        }
+    }
+
+    fn web3(&self) -> anyhow::Result<&Web3RemoteState> {
+        self.web3.as_ref().ok_or_else(no_archive_node)
+    }
+}
+
+/// Only the archive node has what's been asked for.
+fn no_archive_node() -> anyhow::Error {
+    ForkError::BackendMiss("no archive node is configured".to_string()).into()
 }
 
 #[async_trait]
 impl State for StateMuxer {
     async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        if let Some(state) = &self.state {
+            return state.read_account(address).await;
+        }
+
         // if we have db locally, get it!
         if let Some(db) = &self.db {
             let lock = db.lock().await;
             return lock.read_account(address);
         }
 
-        let web3 = self.web3.as_ref().unwrap();
+        let web3 = self.web3()?;
         let ret = web3.read_account(address).await?;
 
         // write back
@@ -63,13 +341,13 @@ impl State for StateMuxer {
             let mut lock = dumper.lock().await;
 
             if let Some(account) = &ret {
-                let code: Bytes = web3.read_code(account.code_hash).await.unwrap();
+                let code: Bytes = web3.read_code(account.code_hash).await?;
                 lock.dump_address(
                     address,
                     account.balance,
                     account.nonce.into(),
                     code.to_vec(),
-                );
+                )?;
             }
         }
 
@@ -77,12 +355,16 @@ impl State for StateMuxer {
     }
 
     async fn read_code(&self, code_hash: H256) -> anyhow::Result<Bytes> {
+        if let Some(state) = &self.state {
+            return state.read_code(code_hash).await;
+        }
+
         if let Some(db) = &self.db {
             let lock = db.lock().await;
             return lock.read_code(code_hash);
         }
 
-        let web3 = self.web3.as_ref().unwrap();
+        let web3 = self.web3()?;
         // for this one, we don't need to write it back to database, it's already done in 'read_account()'
         web3.read_code(code_hash).await
     }
@@ -93,29 +375,44 @@ impl State for StateMuxer {
         incarnation: Incarnation,
         location: H256,
     ) -> anyhow::Result<H256> {
+        if let Some(state) = &self.state {
+            return state.read_storage(address, incarnation, location).await;
+        }
+
         if let Some(db) = &self.db {
             let lock = db.lock().await;
             return lock.read_storage(address, incarnation, location);
         }
 
-        let web3 = self.web3.as_ref().unwrap();
+        let web3 = self.web3()?;
         let ret = web3.read_storage(address, incarnation, location).await?;
 
         if let Some(dumper) = &self.dumper {
             let mut lock = dumper.lock().await;
-            lock.dump_storage(address, location, ret);
+            lock.dump_storage(address, location, ret)?;
         }
 
         Ok(ret)
     }
 
+    async fn previous_incarnation(&self, address: Address) -> anyhow::Result<Incarnation> {
+        match &self.state {
+            Some(state) => state.previous_incarnation(address).await,
+            None => Ok(Incarnation(0)),
+        }
+    }
+
     async fn read_block_header(&self, block_number: u64) -> anyhow::Result<Option<PartialHeader>> {
+        if let Some(state) = &self.state {
+            return state.read_block_header(block_number).await;
+        }
+
         if let Some(db) = &self.db {
             let lock = db.lock().await;
             return lock.read_block_header(block_number);
         }
 
-        let web3 = self.web3.as_ref().unwrap();
+        let web3 = self.web3()?;
         let ret = web3.read_block_header(block_number).await?;
 
         if let Some(dumper) = &self.dumper {
@@ -130,7 +427,7 @@ impl State for StateMuxer {
                     header.gas_limit,
                     header.difficulty,
                     header.beneficiary,
-                );
+                )?;
             }
         }
 
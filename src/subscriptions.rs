@@ -0,0 +1,252 @@
+use anyhow::bail;
+use ethers::types::{Address, Block, Log, H256, U256};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// What an `eth_subscribe` subscription is notified of.
+#[derive(Clone, Debug)]
+pub enum SubscriptionKind {
+    /// The local blocks, as they're mined.
+    NewHeads,
+    /// The logs of the mined transactions which match the filter.
+    Logs(LogFilter),
+    /// The hashes of the transactions as they're added to the mempool.
+    NewPendingTransactions,
+}
+
+impl SubscriptionKind {
+    /// Parses the parameters of `eth_subscribe`, e.g. `["logs", {"address": ...}]`.
+    pub fn from_params(params: &[Value]) -> anyhow::Result<Self> {
+        match params.get(0).and_then(Value::as_str) {
+            Some("newHeads") => Ok(SubscriptionKind::NewHeads),
+            Some("newPendingTransactions") => Ok(SubscriptionKind::NewPendingTransactions),
+            Some("logs") => {
+                let filter = match params.get(1) {
+                    Some(filter) => serde_json::from_value(filter.clone())?,
+                    None => LogFilter::default(),
+                };
+                Ok(SubscriptionKind::Logs(filter))
+            }
+            Some(kind) => bail!("unsupported subscription: {}", kind),
+            None => bail!("missing subscription kind"),
+        }
+    }
+}
+
+/// The addresses and topics of a `logs` subscription, the block range doesn't apply to them.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct LogFilter {
+    #[serde(default)]
+    address: Option<OneOrMany<Address>>,
+    #[serde(default)]
+    topics: Vec<Option<OneOrMany<Option<H256>>>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl LogFilter {
+    /// Like geth, `null` and empty lists match anything.
+    pub fn matches(&self, log: &Log) -> bool {
+        let address_matches = match &self.address {
+            None => true,
+            Some(OneOrMany::One(address)) => *address == log.address,
+            Some(OneOrMany::Many(addresses)) => {
+                addresses.is_empty() || addresses.contains(&log.address)
+            }
+        };
+        if !address_matches || self.topics.len() > log.topics.len() {
+            return false;
+        }
+
+        self.topics
+            .iter()
+            .zip(log.topics.iter())
+            .all(|(expected, topic)| match expected {
+                None | Some(OneOrMany::One(None)) => true,
+                Some(OneOrMany::One(Some(expected))) => expected == topic,
+                Some(OneOrMany::Many(expected)) => {
+                    expected.is_empty()
+                        || expected
+                            .iter()
+                            .any(|expected| expected.map_or(true, |e| e == *topic))
+                }
+            })
+    }
+}
+
+#[derive(Debug)]
+struct Subscription {
+    kind: SubscriptionKind,
+    sender: UnboundedSender<Box<RawValue>>,
+    // until the subscriber takes it
+    receiver: Option<UnboundedReceiver<Box<RawValue>>>,
+}
+
+/// The live subscriptions, they're fed by the provider as blocks are mined and transactions
+/// are sent. The notifications are the `result` of geth's `eth_subscription` messages.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    subscriptions: HashMap<U256, Subscription>,
+    next_id: u64,
+}
+
+impl Subscriptions {
+    /// Starts a subscription, its notifications are queued until `take_notifications()`.
+    pub fn subscribe(&mut self, kind: SubscriptionKind) -> U256 {
+        self.next_id += 1;
+        let id = U256::from(self.next_id);
+
+        let (sender, receiver) = unbounded();
+        self.subscriptions.insert(
+            id,
+            Subscription {
+                kind,
+                sender,
+                receiver: Some(receiver),
+            },
+        );
+        id
+    }
+
+    pub fn take_notifications(&mut self, id: U256) -> Option<UnboundedReceiver<Box<RawValue>>> {
+        self.subscriptions.get_mut(&id)?.receiver.take()
+    }
+
+    /// Ends the subscription, returns whether it existed.
+    pub fn unsubscribe(&mut self, id: U256) -> bool {
+        self.subscriptions.remove(&id).is_some()
+    }
+
+    /// Notifies of a mined block and of the logs of its transactions.
+    pub fn notify_block(&mut self, block: &Block<H256>, logs: &[Log]) {
+        self.notify(|kind| match kind {
+            SubscriptionKind::NewHeads => vec![to_raw_value(block)],
+            SubscriptionKind::Logs(filter) => logs
+                .iter()
+                .filter(|log| filter.matches(log))
+                .map(to_raw_value)
+                .collect(),
+            SubscriptionKind::NewPendingTransactions => vec![],
+        });
+    }
+
+    pub fn notify_pending_transaction(&mut self, hash: H256) {
+        self.notify(|kind| match kind {
+            SubscriptionKind::NewPendingTransactions => vec![to_raw_value(&hash)],
+            _ => vec![],
+        });
+    }
+
+    fn notify(&mut self, notifications: impl Fn(&SubscriptionKind) -> Vec<Box<RawValue>>) {
+        // the subscriptions whose stream has been dropped are closed
+        self.subscriptions.retain(|_, subscription| {
+            notifications(&subscription.kind)
+                .into_iter()
+                .all(|notification| subscription.sender.unbounded_send(notification).is_ok())
+        });
+    }
+}
+
+fn to_raw_value<T: Serialize>(value: &T) -> Box<RawValue> {
+    serde_json::value::to_raw_value(value).expect("blocks, logs and hashes are always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dev_accounts, ForkedEvmProvider, Genesis};
+    use ethers::providers::Middleware;
+    use ethers::types::{Filter, TransactionRequest};
+    use futures::StreamExt;
+    use serde_json::json;
+
+    #[test]
+    fn test_log_filter() {
+        let log = Log {
+            address: Address::from_low_u64_be(1),
+            topics: vec![H256::from_low_u64_be(2), H256::from_low_u64_be(3)],
+            ..Default::default()
+        };
+        let matches = |filter: Value| {
+            SubscriptionKind::from_params(&[json!("logs"), filter]).map(|kind| match kind {
+                SubscriptionKind::Logs(filter) => filter.matches(&log),
+                _ => unreachable!(),
+            })
+        };
+
+        assert!(matches(json!({})).unwrap());
+        assert!(matches(json!({ "address": [log.address, Address::zero()] })).unwrap());
+        assert!(matches(json!({ "topics": [null, log.topics[1]] })).unwrap());
+        assert!(matches(json!({ "topics": [[log.topics[1], log.topics[0]]] })).unwrap());
+        assert!(!matches(json!({ "address": Address::zero() })).unwrap());
+        assert!(!matches(json!({ "topics": [log.topics[1]] })).unwrap());
+        // there are more topics than the log has
+        assert!(!matches(json!({ "topics": [null, null, null] })).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_notify() {
+        let mut subscriptions = Subscriptions::default();
+        let pending = subscriptions.subscribe(SubscriptionKind::NewPendingTransactions);
+        let heads = subscriptions.subscribe(SubscriptionKind::NewHeads);
+        let mut notifications = subscriptions.take_notifications(pending).unwrap();
+        assert!(subscriptions.take_notifications(pending).is_none());
+
+        let hash = H256::from_low_u64_be(1);
+        subscriptions.notify_pending_transaction(hash);
+        let notification = notifications.next().await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<H256>(notification.get()).unwrap(),
+            hash
+        );
+
+        // its stream is gone, so the subscription is dropped on the next notification
+        drop(subscriptions.take_notifications(heads));
+        subscriptions.notify_block(&Block::default(), &[]);
+        assert!(!subscriptions.unsubscribe(heads));
+        assert!(subscriptions.unsubscribe(pending));
+    }
+
+    #[tokio::test]
+    async fn test_mined_block_notifications() {
+        let provider = ForkedEvmProvider::new_in_memory(Genesis::default())
+            .await
+            .unwrap();
+        let from = dev_accounts()[0];
+        // returns LOG1(0, 0, 0x42) STOP as its code
+        let initcode = hex::decode("6008600c60003960086000f3604260006000a100").unwrap();
+        let deployment = TransactionRequest::new()
+            .from(from)
+            .data(initcode)
+            .gas(100_000)
+            .into();
+        let emitter = provider.deploy(&deployment).await.unwrap();
+
+        provider.set_automine(false).await;
+        let mut heads = provider.subscribe_blocks().await.unwrap();
+        let mut logs = provider
+            .subscribe_logs(&Filter::new().address(emitter))
+            .await
+            .unwrap();
+
+        let tx = TransactionRequest::new().from(from).to(emitter);
+        provider.send_transaction(tx, None).await.unwrap();
+        let hashes = provider.mine_block().await.unwrap();
+
+        // the deployment was mined before the subscriptions
+        let head = heads.next().await.unwrap();
+        assert_eq!(head.number, Some(2.into()));
+        let log = logs.next().await.unwrap();
+        assert_eq!(log.topics, vec![H256::from_low_u64_be(0x42)]);
+        assert_eq!(log.transaction_hash, Some(hashes[0]));
+        assert_eq!(log.block_hash, head.hash);
+    }
+}
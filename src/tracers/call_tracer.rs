@@ -0,0 +1,146 @@
+use crate::akula::tracer::Tracer;
+use crate::revert::{RevertDecoder, RevertReason};
+use ethers::types::{Address, Bytes, H256, U256};
+use evmodin::{CallKind, ExecutionState, OpCode, Output, StatusCode};
+use serde::Serialize;
+
+/// A call frame in the shape of geth's `callTracer` output.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallFrame {
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub from: Address,
+    /// The created address for `CREATE`/`CREATE2`, which is empty if the creation failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<Address>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<U256>,
+    pub gas: U256,
+    pub gas_used: U256,
+    pub input: Bytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revert_reason: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub calls: Vec<CallFrame>,
+}
+
+#[derive(Debug, Default)]
+pub struct CallTracer {
+    stack: Vec<CallFrame>,
+    // the latest instruction, which is the one making the call when a frame is entered
+    opcode: Option<OpCode>,
+    root: Option<CallFrame>,
+}
+
+impl CallTracer {
+    /// The top level call frame, it's `None` if nothing has been executed.
+    pub fn into_call_frame(self) -> Option<CallFrame> {
+        self.root
+    }
+}
+
+impl Tracer for CallTracer {
+    fn trace_instructions(&self) -> bool {
+        true
+    }
+
+    fn capture_step(
+        &mut self,
+        _pc: usize,
+        opcode: OpCode,
+        _gas_cost: u64,
+        _state: &ExecutionState,
+        _storage: Option<(H256, H256)>,
+    ) {
+        self.opcode = Some(opcode);
+    }
+
+    fn capture_enter(
+        &mut self,
+        kind: CallKind,
+        _is_static: bool,
+        from: Address,
+        to: Option<Address>,
+        input: &bytes::Bytes,
+        gas: i64,
+        value: U256,
+    ) {
+        // a STATICCALL comes as a static CALL, just like a CALL made in a static context, only
+        // the instruction making it tells them apart
+        let static_call = kind == CallKind::Call && self.opcode == Some(OpCode::STATICCALL);
+        let typ = match kind {
+            CallKind::Call if static_call => "STATICCALL",
+            CallKind::Call => "CALL",
+            CallKind::DelegateCall => "DELEGATECALL",
+            CallKind::CallCode => "CALLCODE",
+            CallKind::Create => "CREATE",
+            CallKind::Create2 => "CREATE2",
+        };
+
+        // the value is inherited from the parent frame in these calls
+        let value = if static_call || kind == CallKind::DelegateCall {
+            None
+        } else {
+            Some(value)
+        };
+
+        self.stack.push(CallFrame {
+            typ: typ.to_string(),
+            from,
+            to,
+            value,
+            gas: gas.max(0).into(),
+            gas_used: U256::zero(),
+            input: input.clone().into(),
+            output: None,
+            error: None,
+            revert_reason: None,
+            calls: vec![],
+        });
+    }
+
+    fn capture_exit(&mut self, output: &Output) {
+        let mut frame = match self.stack.pop() {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        frame.gas_used = frame.gas.saturating_sub(output.gas_left.max(0).into());
+        if let Some(address) = output.create_address {
+            frame.to = Some(address);
+        }
+        if !output.output_data.is_empty() {
+            frame.output = Some(output.output_data.clone().into());
+        }
+        if output.status_code != StatusCode::Success {
+            frame.error = Some(error_message(&output.status_code));
+        }
+        if output.status_code == StatusCode::Revert {
+            frame.revert_reason = match RevertDecoder::default().decode(&output.output_data) {
+                RevertReason::Unknown => None,
+                reason => Some(reason.to_string()),
+            };
+        }
+
+        match self.stack.last_mut() {
+            Some(parent) => parent.calls.push(frame),
+            None => self.root = Some(frame),
+        }
+    }
+}
+
+// the same error strings as geth reports
+fn error_message(status_code: &StatusCode) -> String {
+    match status_code {
+        StatusCode::Revert => "execution reverted".to_string(),
+        StatusCode::OutOfGas => "out of gas".to_string(),
+        StatusCode::InvalidInstruction => "invalid opcode".to_string(),
+        StatusCode::InsufficientBalance => "insufficient balance for transfer".to_string(),
+        other => format!("{:?}", other),
+    }
+}
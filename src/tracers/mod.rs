@@ -0,0 +1,3 @@
+pub mod call_tracer;
+pub mod state_diff;
+pub mod struct_logger;
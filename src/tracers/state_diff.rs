@@ -0,0 +1,350 @@
+use crate::akula::delta::Delta;
+use crate::akula::interface::State;
+use crate::akula::intra_block_state::{IntraBlockState, Snapshot};
+use crate::akula::types::Account;
+use crate::akula::EMPTY_HASH;
+use ethers::types::{
+    AccountDiff as ParityAccountDiff, Address, Bytes, ChangedType, Diff,
+    StateDiff as ParityStateDiff, H256, U256,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// An account with the storage slots touched by the transaction.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccountState {
+    pub balance: U256,
+    pub nonce: u64,
+    pub code: Bytes,
+    pub storage: BTreeMap<H256, H256>,
+}
+
+/// `None` means the account doesn't exist, either before or after the transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountDiff {
+    pub pre: Option<AccountState>,
+    pub post: Option<AccountState>,
+}
+
+/// Every account and storage slot modified since a snapshot.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StateDiff(pub BTreeMap<Address, AccountDiff>);
+
+/// An account in geth's `prestateTracer` output, fields are omitted when they are empty.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PrestateAccount {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<Bytes>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub storage: BTreeMap<H256, H256>,
+}
+
+/// geth's `prestateTracer` output with `diffMode` on.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PrestateDiff {
+    pub pre: BTreeMap<Address, PrestateAccount>,
+    pub post: BTreeMap<Address, PrestateAccount>,
+}
+
+impl StateDiff {
+    /// Collects the changes made after `snapshot`, it must be called before the journal is
+    /// reverted or cleared.
+    pub async fn since<S: State>(
+        state: &mut IntraBlockState<S>,
+        snapshot: &Snapshot,
+    ) -> anyhow::Result<Self> {
+        // undo the journal on copies of the current values, what's left is the state at the snapshot
+        let mut pre_accounts: BTreeMap<Address, Option<Account>> = BTreeMap::new();
+        let mut pre_storage: BTreeMap<Address, BTreeMap<H256, H256>> = BTreeMap::new();
+
+        for delta in state.journal_since(snapshot).iter().rev() {
+            match delta {
+                Delta::Create { address } => {
+                    pre_accounts.insert(*address, None);
+                }
+                Delta::Update { address, previous } => {
+                    pre_accounts.insert(*address, previous.current.clone());
+                }
+                Delta::UpdateBalance { address, previous } => {
+                    let current = state.objects.get(address).and_then(|o| o.current.clone());
+                    if let Some(account) = pre_accounts.entry(*address).or_insert(current) {
+                        account.balance = *previous;
+                    }
+                }
+                Delta::StorageChange {
+                    address,
+                    key,
+                    previous,
+                } => {
+                    pre_storage
+                        .entry(*address)
+                        .or_default()
+                        .insert(*key, *previous);
+                }
+                Delta::StorageWipe { address, storage } => {
+                    let slots = pre_storage.entry(*address).or_default();
+                    for (key, value) in &storage.committed {
+                        slots.insert(*key, value.original);
+                    }
+                    for (key, value) in &storage.current {
+                        slots.insert(*key, *value);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let addresses = pre_accounts
+            .keys()
+            .chain(pre_storage.keys())
+            .copied()
+            .collect::<BTreeSet<_>>();
+
+        let mut diff = BTreeMap::new();
+        for address in addresses {
+            let post_account = state.objects.get(&address).and_then(|o| o.current.clone());
+            let pre_account = pre_accounts
+                .remove(&address)
+                .unwrap_or_else(|| post_account.clone());
+            let pre_slots = pre_storage.remove(&address).unwrap_or_default();
+
+            let mut post_slots = BTreeMap::new();
+            for key in pre_slots.keys() {
+                let value = if post_account.is_some() {
+                    state.get_current_storage(address, *key).await?
+                } else {
+                    H256::zero()
+                };
+                post_slots.insert(*key, value);
+            }
+
+            let pre = match pre_account {
+                Some(account) => Some(account_state(state, account, pre_slots).await?),
+                None => None,
+            };
+            let post = match post_account {
+                Some(account) => Some(account_state(state, account, post_slots).await?),
+                None => None,
+            };
+
+            if pre != post {
+                diff.insert(address, AccountDiff { pre, post });
+            }
+        }
+
+        Ok(Self(diff))
+    }
+
+    /// The same shape as geth's `prestateTracer` with `{"diffMode": true}`.
+    pub fn to_prestate_diff(&self) -> PrestateDiff {
+        let mut ret = PrestateDiff::default();
+
+        for (address, diff) in &self.0 {
+            let empty = AccountState::default();
+            let pre = diff.pre.as_ref().unwrap_or(&empty);
+
+            let mut pre_account = PrestateAccount {
+                balance: Some(pre.balance).filter(|b| !b.is_zero()),
+                nonce: Some(pre.nonce).filter(|n| *n != 0),
+                code: Some(pre.code.clone()).filter(|c| !c.0.is_empty()),
+                storage: BTreeMap::new(),
+            };
+
+            if let Some(post) = &diff.post {
+                let mut post_account = PrestateAccount::default();
+                if post.balance != pre.balance {
+                    post_account.balance = Some(post.balance);
+                }
+                if post.nonce != pre.nonce {
+                    post_account.nonce = Some(post.nonce);
+                }
+                if post.code != pre.code && !post.code.0.is_empty() {
+                    post_account.code = Some(post.code.clone());
+                }
+                for (key, value) in &post.storage {
+                    let previous = pre.storage.get(key).copied().unwrap_or_default();
+                    if previous == *value {
+                        continue;
+                    }
+                    if !previous.is_zero() {
+                        pre_account.storage.insert(*key, previous);
+                    }
+                    if !value.is_zero() {
+                        post_account.storage.insert(*key, *value);
+                    }
+                }
+                ret.post.insert(*address, post_account);
+            } else {
+                pre_account.storage = pre
+                    .storage
+                    .iter()
+                    .filter(|(_, v)| !v.is_zero())
+                    .map(|(k, v)| (*k, *v))
+                    .collect();
+            }
+
+            // newly created accounts have no prestate
+            if diff.pre.is_some() {
+                ret.pre.insert(*address, pre_account);
+            }
+        }
+
+        ret
+    }
+
+    /// The same shape as the `stateDiff` of parity's `trace_replayTransaction`.
+    pub fn to_parity_state_diff(&self) -> ParityStateDiff {
+        let mut ret = BTreeMap::new();
+
+        for (address, diff) in &self.0 {
+            let pre = diff.pre.as_ref();
+            let post = diff.post.as_ref();
+
+            let mut storage = BTreeMap::new();
+            let keys = pre
+                .iter()
+                .chain(post.iter())
+                .flat_map(|a| a.storage.keys())
+                .copied()
+                .collect::<BTreeSet<_>>();
+            for key in keys {
+                let d = to_diff(
+                    pre.map(|a| a.storage.get(&key).copied().unwrap_or_default()),
+                    post.map(|a| a.storage.get(&key).copied().unwrap_or_default()),
+                );
+                if d != Diff::Same {
+                    storage.insert(key, d);
+                }
+            }
+
+            ret.insert(
+                *address,
+                ParityAccountDiff {
+                    balance: to_diff(pre.map(|a| a.balance), post.map(|a| a.balance)),
+                    nonce: to_diff(pre.map(|a| a.nonce.into()), post.map(|a| a.nonce.into())),
+                    code: to_diff(pre.map(|a| a.code.clone()), post.map(|a| a.code.clone())),
+                    storage,
+                },
+            );
+        }
+
+        ParityStateDiff(ret)
+    }
+}
+
+async fn account_state<S: State>(
+    state: &mut IntraBlockState<S>,
+    account: Account,
+    storage: BTreeMap<H256, H256>,
+) -> anyhow::Result<AccountState> {
+    let code = if account.code_hash == EMPTY_HASH {
+        Default::default()
+    } else if let Some(code) = state.new_code.get(&account.code_hash) {
+        code.clone()
+    } else if let Some(code) = state.existing_code.get(&account.code_hash) {
+        code.clone()
+    } else {
+        state.db().read_code(account.code_hash).await?
+    };
+
+    Ok(AccountState {
+        balance: account.balance,
+        nonce: account.nonce,
+        code: code.into(),
+        storage,
+    })
+}
+
+fn to_diff<T: PartialEq>(pre: Option<T>, post: Option<T>) -> Diff<T> {
+    match (pre, post) {
+        (None, Some(to)) => Diff::Born(to),
+        (Some(from), None) => Diff::Died(from),
+        (Some(from), Some(to)) if from != to => Diff::Changed(ChangedType { from, to }),
+        _ => Diff::Same,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::akula::types::{Incarnation, PartialHeader};
+    use address_literal::addr;
+    use async_trait::async_trait;
+    use u256_literal::u256;
+
+    #[derive(Debug)]
+    struct EmptyState;
+
+    #[async_trait]
+    impl State for EmptyState {
+        async fn read_account(&self, _address: Address) -> anyhow::Result<Option<Account>> {
+            Ok(None)
+        }
+
+        async fn read_code(&self, _code_hash: H256) -> anyhow::Result<bytes::Bytes> {
+            Ok(Default::default())
+        }
+
+        async fn read_storage(
+            &self,
+            _address: Address,
+            _incarnation: Incarnation,
+            _location: H256,
+        ) -> anyhow::Result<H256> {
+            Ok(H256::zero())
+        }
+
+        async fn read_block_header(
+            &self,
+            _block_number: u64,
+        ) -> anyhow::Result<Option<PartialHeader>> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_diff_of_new_account() {
+        let address = addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2");
+        let key = H256::from_low_u64_be(1);
+        let value = H256::from_low_u64_be(2);
+
+        let mut state = IntraBlockState::new(EmptyState);
+        let snapshot = state.take_snapshot();
+
+        state.set_balance(address, u256!(100)).await.unwrap();
+        state.set_nonce(address, 1).await.unwrap();
+        state.set_storage(address, key, value).await.unwrap();
+
+        let diff = StateDiff::since(&mut state, &snapshot).await.unwrap();
+        let account = diff.0.get(&address).unwrap();
+        assert_eq!(account.pre, None);
+        assert_eq!(
+            account.post,
+            Some(AccountState {
+                balance: u256!(100),
+                nonce: 1,
+                code: Default::default(),
+                storage: vec![(key, value)].into_iter().collect(),
+            })
+        );
+
+        let prestate = diff.to_prestate_diff();
+        assert!(prestate.pre.is_empty());
+        assert_eq!(prestate.post[&address].balance, Some(u256!(100)));
+        assert_eq!(prestate.post[&address].storage[&key], value);
+
+        let parity = diff.to_parity_state_diff();
+        assert_eq!(parity.0[&address].balance, Diff::Born(u256!(100)));
+        assert_eq!(parity.0[&address].storage[&key], Diff::Born(value));
+
+        // nothing is left once the changes are reverted
+        state.revert_to_snapshot(snapshot);
+        let snapshot = state.take_snapshot();
+        let diff = StateDiff::since(&mut state, &snapshot).await.unwrap();
+        assert!(diff.0.is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::akula::tracer::Tracer;
+use ethers::types::{Address, H256, U256};
+use evmodin::{ExecutionState, OpCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Mirrors the logger config of geth's `debug_traceTransaction`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StructLoggerConfig {
+    pub disable_memory: bool,
+    pub disable_stack: bool,
+    pub disable_storage: bool,
+}
+
+/// A single step, in the same shape as geth's `StructLogRes`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StructLog {
+    pub pc: u64,
+    pub op: String,
+    pub gas: u64,
+    pub gas_cost: u64,
+    pub depth: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack: Option<Vec<U256>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage: Option<BTreeMap<String, String>>,
+}
+
+/// The result of geth's default struct logger, `ExecutionResult` in geth.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StructLogTrace {
+    pub gas: u64,
+    pub failed: bool,
+    pub return_value: String,
+    pub struct_logs: Vec<StructLog>,
+}
+
+#[derive(Debug, Default)]
+pub struct StructLogger {
+    config: StructLoggerConfig,
+    logs: Vec<StructLog>,
+    storage: HashMap<Address, BTreeMap<H256, H256>>,
+}
+
+impl StructLogger {
+    pub fn new(config: StructLoggerConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn into_trace(self, gas_used: u64, failed: bool, output: &[u8]) -> StructLogTrace {
+        StructLogTrace {
+            gas: gas_used,
+            failed,
+            return_value: hex::encode(output),
+            struct_logs: self.logs,
+        }
+    }
+}
+
+impl Tracer for StructLogger {
+    fn trace_instructions(&self) -> bool {
+        true
+    }
+
+    fn capture_step(
+        &mut self,
+        pc: usize,
+        opcode: OpCode,
+        gas_cost: u64,
+        state: &ExecutionState,
+        storage: Option<(H256, H256)>,
+    ) {
+        let stack = if self.config.disable_stack {
+            None
+        } else {
+            let stack = &state.stack;
+            Some((0..stack.len()).rev().map(|i| *stack.get(i)).collect())
+        };
+
+        let memory = if self.config.disable_memory {
+            None
+        } else {
+            Some(state.memory[..].chunks(32).map(hex::encode).collect())
+        };
+
+        let storage = match storage {
+            Some((key, value)) if !self.config.disable_storage => {
+                let slots = self.storage.entry(state.message.recipient).or_default();
+                slots.insert(key, value);
+                Some(
+                    slots
+                        .iter()
+                        .map(|(k, v)| (hex::encode(k), hex::encode(v)))
+                        .collect(),
+                )
+            }
+            _ => None,
+        };
+
+        self.logs.push(StructLog {
+            pc: pc as u64,
+            op: opcode.to_string(),
+            gas: state.gas_left.max(0) as u64,
+            gas_cost,
+            depth: state.message.depth as u64 + 1,
+            stack,
+            memory,
+            storage,
+        });
+    }
+}
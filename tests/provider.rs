@@ -1,5 +1,12 @@
 use address_literal::addr;
-use ethers_forked_evm_provider::ForkedEvmProvider;
+use ethers::prelude::*;
+use ethers_forked_evm_provider::tracers::state_diff::PrestateAccount;
+use ethers_forked_evm_provider::tracers::struct_logger::StructLoggerConfig;
+use ethers_forked_evm_provider::{
+    dev_accounts, parse_alloc, ForkError, ForkManager, ForkedEvmProvider, Genesis, GenesisAccount,
+    RevertReason,
+};
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -42,3 +49,820 @@ async fn test_simple_public_view_functions() {
     // WETH
     assert_eq!(token1, addr!("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"));
 }
+
+/// Copies `Error("nope")` from the end of its code and reverts with it.
+const REVERT_NOPE: &str = "6064600c60003960646000fd08c379a0\
+    0000000000000000000000000000000000000000000000000000000000000020\
+    0000000000000000000000000000000000000000000000000000000000000004\
+    6e6f706500000000000000000000000000000000000000000000000000000000";
+
+/// An in-memory provider with the contracts deployed at genesis, by address and hex code.
+async fn in_memory_with(contracts: &[(Address, &str)]) -> ForkedEvmProvider {
+    let mut genesis = Genesis::default();
+    for (address, code) in contracts {
+        genesis.alloc.insert(
+            *address,
+            GenesisAccount {
+                code: hex::decode(code).unwrap().into(),
+                ..Default::default()
+            },
+        );
+    }
+    ForkedEvmProvider::new_in_memory(genesis).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_struct_log_gas_costs() {
+    // PUSH1 1 PUSH1 0 SSTORE STOP
+    let contract = addr!("0x1000000000000000000000000000000000000001");
+    let provider = in_memory_with(&[(contract, "600160005500")]).await;
+
+    let tx = TransactionRequest::new()
+        .from(dev_accounts()[0])
+        .to(contract)
+        .into();
+    let trace = provider
+        .debug_trace_transaction(&tx, StructLoggerConfig::default())
+        .await
+        .unwrap();
+
+    assert!(!trace.failed);
+    let steps = trace
+        .struct_logs
+        .iter()
+        .map(|log| (log.op.as_str(), log.gas_cost))
+        .collect::<Vec<_>>();
+    // a cold slot set from zero
+    assert_eq!(
+        steps,
+        vec![("PUSH1", 3), ("PUSH1", 3), ("SSTORE", 22100), ("STOP", 0)]
+    );
+}
+
+#[tokio::test]
+async fn test_struct_log_call_and_return_costs() {
+    // CALL(0x1000, callee, 0, 0, 0, 0, 0) STOP
+    let caller = addr!("0x100000000000000000000000000000000000000a");
+    // RETURN(0, 32)
+    let callee = addr!("0x100000000000000000000000000000000000000b");
+    let provider = in_memory_with(&[
+        (
+            caller,
+            "6000600060006000600073100000000000000000000000000000000000000b611000f100",
+        ),
+        (callee, "60206000f3"),
+    ])
+    .await;
+
+    let tx = TransactionRequest::new()
+        .from(dev_accounts()[0])
+        .to(caller)
+        .gas(100_000)
+        .into();
+    let trace = provider
+        .debug_trace_transaction(&tx, StructLoggerConfig::default())
+        .await
+        .unwrap();
+
+    assert!(!trace.failed);
+    assert_eq!(trace.gas, 23630);
+    assert_eq!(trace.struct_logs[0].gas, 100_000 - 21000);
+    // the values geth reports: a cold account access plus the gas given to the callee, not
+    // what the callee used
+    let call = trace.struct_logs.iter().find(|l| l.op == "CALL").unwrap();
+    assert_eq!(call.gas_cost, 2600 + 0x1000);
+    let steps = trace
+        .struct_logs
+        .iter()
+        .filter(|l| l.depth == 2)
+        .map(|l| (l.op.as_str(), l.gas, l.gas_cost))
+        .collect::<Vec<_>>();
+    // the memory expansion to one word
+    assert_eq!(
+        steps,
+        vec![
+            ("PUSH1", 0x1000, 3),
+            ("PUSH1", 0x1000 - 3, 3),
+            ("RETURN", 0x1000 - 6, 3)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_call_trace_kinds() {
+    // STATICCALL(GAS, inner, 0, 0, 0, 0) STOP
+    let outer = addr!("0x100000000000000000000000000000000000000c");
+    // STATICCALL(GAS, leaf, 0, 0, 0, 0) POP CALL(GAS, leaf, 0, 0, 0, 0, 0) STOP, both of them
+    // in a static context
+    let inner = addr!("0x100000000000000000000000000000000000000d");
+    let leaf = addr!("0x100000000000000000000000000000000000000e");
+    let provider = in_memory_with(&[
+        (
+            outer,
+            "600060006000600073100000000000000000000000000000000000000d5afa00",
+        ),
+        (
+            inner,
+            "600060006000600073100000000000000000000000000000000000000e5afa50\
+             6000600060006000600073100000000000000000000000000000000000000e5af100",
+        ),
+        (leaf, "00"),
+    ])
+    .await;
+    provider.set_call_tracing(true).await;
+
+    let tx = TransactionRequest::new().from(dev_accounts()[0]).to(outer);
+    provider.call(&tx.into(), None).await.unwrap();
+
+    let traces = provider.take_call_traces().await;
+    let root = &traces[0];
+    assert_eq!(root.typ, "CALL");
+    let static_call = &root.calls[0];
+    assert_eq!(
+        (static_call.typ.as_str(), static_call.to),
+        ("STATICCALL", Some(inner))
+    );
+    assert_eq!(static_call.value, None);
+    let kinds = static_call
+        .calls
+        .iter()
+        .map(|frame| (frame.typ.as_str(), frame.value))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        vec![("STATICCALL", None), ("CALL", Some(U256::zero()))]
+    );
+}
+
+#[tokio::test]
+async fn test_intrinsic_gas_and_refund_cap() {
+    // PUSH1 0 PUSH1 0 SSTORE PUSH1 0 PUSH1 1 SSTORE STOP, which clears slots 0 and 1
+    let contract = addr!("0x1000000000000000000000000000000000000002");
+    let mut genesis = Genesis::default();
+    genesis.alloc.insert(
+        contract,
+        GenesisAccount {
+            code: hex::decode("6000600055600060015500").unwrap().into(),
+            storage: (0..2u64)
+                .map(|slot| (H256::from_low_u64_be(slot), H256::from_low_u64_be(1)))
+                .collect(),
+            ..Default::default()
+        },
+    );
+    let provider = ForkedEvmProvider::new_in_memory(genesis).await.unwrap();
+    let from = dev_accounts()[0];
+    let to = addr!("0x2000000000000000000000000000000000000002");
+
+    // 21000 + 2400 for the address + 1900 for the key + 4 and 16 for the data
+    let access_list = AccessList(vec![AccessListItem {
+        address: to,
+        storage_keys: vec![H256::zero()],
+    }]);
+    let request = TransactionRequest::new()
+        .from(from)
+        .to(to)
+        .data(vec![0u8, 1]);
+    let tx = Eip2930TransactionRequest::new(request.clone().gas(25320), access_list.clone());
+    let hash = *provider.send_transaction(tx, None).await.unwrap();
+    let receipt = provider
+        .get_transaction_receipt(hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(receipt.gas_used, Some(25320.into()));
+
+    let tx = Eip2930TransactionRequest::new(request.gas(25319), access_list);
+    assert!(provider.send_transaction(tx, None).await.is_err());
+
+    // 31012 before the refund of 2 * 4800, which is capped at a fifth of it
+    let tx = TransactionRequest::new()
+        .from(from)
+        .to(contract)
+        .gas(100_000);
+    let hash = *provider.send_transaction(tx, None).await.unwrap();
+    let receipt = provider
+        .get_transaction_receipt(hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(receipt.gas_used, Some((31012 - 31012 / 5).into()));
+}
+
+/// The provider's own error, which `Middleware` boxes.
+fn fork_error(e: ProviderError) -> ForkError {
+    match e {
+        ProviderError::JsonRpcClientError(e) => *e.downcast::<ForkError>().unwrap(),
+        e => panic!("unexpected error: {:?}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_estimate_gas() {
+    // reverts unless GAS is at least 10000: PUSH2 10000 GAS LT PUSH1 9 JUMPI STOP
+    // JUMPDEST PUSH1 0 DUP1 REVERT
+    let threshold = addr!("0x1000000000000000000000000000000000000003");
+    // reverts with Error("nope")
+    let reverting = addr!("0x1000000000000000000000000000000000000004");
+    // PUSH1 1 PUSH1 0 SSTORE STOP
+    let store = addr!("0x1000000000000000000000000000000000000005");
+    let provider = in_memory_with(&[
+        (threshold, "6127105a10600957005b600080fd"),
+        (reverting, REVERT_NOPE),
+        (store, "600160005500"),
+    ])
+    .await;
+    let from = dev_accounts()[0];
+
+    let tx = TransactionRequest::new()
+        .from(from)
+        .to(addr!("0x2000000000000000000000000000000000000003"))
+        .value(1)
+        .into();
+    assert_eq!(provider.estimate_gas(&tx).await.unwrap(), 21000.into());
+
+    // a gas above the block gas limit is capped by it, it isn't truncated
+    let tx = TransactionRequest::new()
+        .from(from)
+        .to(threshold)
+        .gas(U256::from(u64::MAX) + 31004)
+        .into();
+    assert_eq!(provider.estimate_gas(&tx).await.unwrap(), 31005.into());
+
+    // the lowest limit it succeeds with, well above the gas it uses
+    let tx = TransactionRequest::new().from(from).to(threshold).into();
+    assert_eq!(provider.estimate_gas(&tx).await.unwrap(), 31005.into());
+
+    let tx = TransactionRequest::new().from(from).to(reverting).into();
+    match fork_error(provider.estimate_gas(&tx).await.unwrap_err()) {
+        ForkError::Reverted(e) => {
+            assert_eq!(e.reason, RevertReason::Error("nope".to_string()))
+        }
+        e => panic!("unexpected error: {:?}", e),
+    }
+
+    // the gas of the transaction caps it, the SSTORE alone needs 22100
+    let tx = TransactionRequest::new()
+        .from(from)
+        .to(store)
+        .gas(30000)
+        .into();
+    match fork_error(provider.estimate_gas(&tx).await.unwrap_err()) {
+        ForkError::GasCapTooLow(cap) => assert_eq!(cap, 30000),
+        e => panic!("unexpected error: {:?}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_ens() {
+    // both the registry and the resolver: resolver() returns its own address, addr() only
+    // knows vitalik.eth and name() always returns it
+    let ens = "60003560e01c80630178b8bf1460275780633b3b57de1460315763691f343114607557600080fd\
+        5b3060005260206000f35b73d8da6bf26964af9d7eed9e03e53415d37aa960456004357fee6c4522aab0\
+        003e8d14cd40a6af439055fd2577951148c14b6cea9a53475835140260005260206000f35b6060608260\
+        003960606000f300000000000000000000000000000000000000000000000000000000000000200000\
+        00000000000000000000000000000000000000000000000000000000000b766974616c696b2e657468\
+        000000000000000000000000000000000000000000";
+    let provider = in_memory_with(&[(ethers::providers::ens::ENS_ADDRESS, ens)]).await;
+    let vitalik = addr!("0xd8da6bf26964af9d7eed9e03e53415d37aa96045");
+
+    assert_eq!(provider.resolve_name("vitalik.eth").await.unwrap(), vitalik);
+    assert!(matches!(
+        provider.resolve_name("nobody.eth").await,
+        Err(ProviderError::EnsError(name)) if name == "nobody.eth"
+    ));
+
+    assert_eq!(
+        provider.lookup_address(vitalik).await.unwrap(),
+        "vitalik.eth"
+    );
+    // the reverse record has to resolve back to the address
+    assert!(matches!(
+        provider.lookup_address(dev_accounts()[0]).await,
+        Err(ProviderError::EnsError(name)) if name == "vitalik.eth"
+    ));
+
+    // the names are resolved when they're sent to as well
+    let tx = TransactionRequest::new()
+        .from(dev_accounts()[0])
+        .to("vitalik.eth")
+        .value(1);
+    provider.send_transaction(tx, None).await.unwrap();
+    assert_eq!(provider.get_balance(vitalik, None).await.unwrap(), 1.into());
+}
+
+#[tokio::test]
+async fn test_middleware_reads() {
+    // PUSH1 1 PUSH1 0 SSTORE STOP
+    let contract = addr!("0x1000000000000000000000000000000000000006");
+    let provider = in_memory_with(&[(contract, "600160005500")]).await;
+    let from = dev_accounts()[0];
+
+    assert_eq!(provider.get_chainid().await.unwrap(), 31337.into());
+    assert_eq!(provider.get_accounts().await.unwrap(), dev_accounts());
+    // the base fee and the default tip
+    assert_eq!(
+        provider.get_gas_price().await.unwrap(),
+        2_000_000_000u64.into()
+    );
+    assert_eq!(
+        provider.get_code(contract, None).await.unwrap(),
+        hex::decode("600160005500").unwrap().into()
+    );
+    assert!(provider.get_block(1u64).await.unwrap().is_none());
+
+    let tx = TransactionRequest::new().from(from).to(contract);
+    provider.send_transaction(tx, None).await.unwrap();
+
+    assert_eq!(provider.get_block_number().await.unwrap(), 1.into());
+    let genesis = provider.get_block(0u64).await.unwrap().unwrap();
+    let block = provider.get_block(1u64).await.unwrap().unwrap();
+    assert_eq!(block.number, Some(1.into()));
+    assert_eq!(block.parent_hash, genesis.hash.unwrap());
+    let by_hash = provider
+        .get_block(block.hash.unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(by_hash.number, Some(1.into()));
+
+    // the state of both blocks is still there
+    let slot = H256::zero();
+    let before = Some(BlockId::Number(0u64.into()));
+    assert_eq!(
+        provider
+            .get_storage_at(contract, slot, before)
+            .await
+            .unwrap(),
+        H256::zero()
+    );
+    assert_eq!(
+        provider.get_storage_at(contract, slot, None).await.unwrap(),
+        H256::from_low_u64_be(1)
+    );
+    assert_eq!(
+        provider.get_transaction_count(from, before).await.unwrap(),
+        0.into()
+    );
+    assert_eq!(
+        provider.get_transaction_count(from, None).await.unwrap(),
+        1.into()
+    );
+    assert_eq!(
+        provider.get_balance(from, before).await.unwrap(),
+        U256::exp10(22)
+    );
+    assert!(provider.get_balance(from, None).await.unwrap() < U256::exp10(22));
+
+    let history = provider
+        .fee_history(2, BlockNumber::Latest, &[50.0])
+        .await
+        .unwrap();
+    assert_eq!(history.oldest_block, 0.into());
+    assert_eq!(
+        history.base_fee_per_gas,
+        vec![U256::from(1_000_000_000u64); 3]
+    );
+    assert_eq!(history.reward, vec![vec![U256::zero()]; 2]);
+}
+
+#[tokio::test]
+async fn test_simulate_bundle() {
+    // reverts with Error("nope")
+    let reverting = addr!("0x1000000000000000000000000000000000000007");
+    let provider = in_memory_with(&[(reverting, REVERT_NOPE)]).await;
+    let from = dev_accounts()[0];
+    let to = addr!("0x2000000000000000000000000000000000000007");
+
+    // signed by the first dev account at a gas price of 2 gwei: 1 ether to `to` with nonce 0,
+    // then a call to `reverting` with nonce 1
+    let txs = [
+        "f86d808477359400825208942000000000000000000000000000000000000007880de0b6b3a76400008082\
+         f4f5a05de9d5b6ec72637e54c2d4e7ad32b5f1845f31dc90edf47a940cec951d665913a034ae575243da23\
+         98d451a135a0c2a58b9d42aa2b58809786d2d72b744ef1c320",
+        "f866018477359400830186a0941000000000000000000000000000000000000007808082f4f5a06f389ef7\
+         7a4654cb59541da27cfb041b1478591fbfed2d637509b59d9a4a9bcaa073360eb405d92167280b2f31ee23\
+         3e9e0ed033b927e11a9296f803548072bee1",
+    ]
+    .iter()
+    .map(|tx| hex::decode(tx).unwrap().into())
+    .collect::<Vec<Bytes>>();
+
+    let simulation = provider.simulate_bundle(&txs, 1, None).await.unwrap();
+    assert_eq!(simulation.results.len(), 2);
+    let (transfer, call) = (&simulation.results[0], &simulation.results[1]);
+    assert_eq!(
+        transfer.tx_hash,
+        "0x3a5959285edff1f6635bda3f125210e9adb591ca7b232984ae120afa7b53c84d"
+            .parse()
+            .unwrap()
+    );
+    assert_eq!(transfer.from_address, from);
+    assert_eq!(transfer.gas_used, 21000);
+    assert!(transfer.error.is_none());
+    // 42 for the revert data copied into memory
+    assert_eq!(call.gas_used, 21042);
+    assert!(call.error.is_some());
+    assert_eq!(call.revert.as_deref(), Some("nope"));
+
+    // the tips of 1 gwei reach the coinbase, the base fee is burnt
+    let gwei = U256::exp10(9);
+    assert_eq!(simulation.total_gas_used, 42042);
+    assert_eq!(simulation.coinbase_diff, gwei * 42042);
+    assert_eq!(simulation.gas_fees, gwei * 42042);
+    assert_eq!(simulation.bundle_gas_price, gwei);
+    assert!(simulation.eth_sent_to_coinbase.is_zero());
+
+    // nothing is left behind
+    assert!(provider.get_balance(to, None).await.unwrap().is_zero());
+    assert!(provider
+        .get_transaction_count(from, None)
+        .await
+        .unwrap()
+        .is_zero());
+    assert_eq!(provider.get_block_number().await.unwrap(), 0.into());
+
+    // like geth, a nonce gap isn't reported as too low
+    assert!(matches!(
+        provider.simulate_bundle(&txs[1..], 1, None).await,
+        Err(ForkError::InvalidTransaction(reason)) if reason == "nonce too high"
+    ));
+
+    // the fork block itself can't be simulated on
+    assert!(matches!(
+        provider.simulate_bundle(&txs, 0, None).await,
+        Err(ForkError::InvalidTransaction(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_fork_manager() {
+    let manager = ForkManager::new(None);
+    let first = manager
+        .insert_fork(
+            "first",
+            ForkedEvmProvider::new_in_memory(Genesis::default())
+                .await
+                .unwrap(),
+        )
+        .await;
+    let second = ForkedEvmProvider::new_in_memory(Genesis {
+        chain_id: 5,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    manager.insert_fork("second", second).await;
+
+    // the first fork is selected until another one is
+    assert_eq!(manager.active_fork_name().as_deref(), Some("first"));
+    assert_eq!(manager.get_chainid().await.unwrap(), 31337.into());
+    assert_eq!(manager.fork_names().await, vec!["first", "second"]);
+    assert!(matches!(
+        manager.select_fork("third").await,
+        Err(ForkError::UnknownFork(_))
+    ));
+
+    let bot = addr!("0x3000000000000000000000000000000000000001");
+    let other = addr!("0x3000000000000000000000000000000000000002");
+    first.set_balance(bot, 100).await.unwrap();
+    first.set_balance(other, 200).await.unwrap();
+    manager.make_persistent(bot).await;
+
+    manager.select_fork("second").await.unwrap();
+    assert_eq!(manager.active_fork_name().as_deref(), Some("second"));
+    assert_eq!(manager.get_chainid().await.unwrap(), 5.into());
+    assert_eq!(manager.get_balance(bot, None).await.unwrap(), 100.into());
+    assert!(manager.get_balance(other, None).await.unwrap().is_zero());
+
+    // the changes of the first fork are kept while it isn't selected
+    manager.select_fork("first").await.unwrap();
+    assert_eq!(manager.get_balance(other, None).await.unwrap(), 200.into());
+}
+
+#[tokio::test]
+async fn test_checkpoint() {
+    let provider = ForkedEvmProvider::new_in_memory(Genesis::default())
+        .await
+        .unwrap();
+    let to = addr!("0x2000000000000000000000000000000000000008");
+    let funded = addr!("0x2000000000000000000000000000000000000009");
+    let ether = U256::exp10(18);
+
+    let tx = TransactionRequest::new()
+        .from(dev_accounts()[0])
+        .to(to)
+        .value(ether);
+    let hash = *provider.send_transaction(tx, None).await.unwrap();
+    // a change outside of any block, which is saved too
+    provider.set_balance(funded, ether * 2).await.unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("checkpoint.db");
+    provider.save_checkpoint(&path).await.unwrap();
+
+    let restored = ForkedEvmProvider::new_in_memory(Genesis::default())
+        .await
+        .unwrap();
+    restored.load_checkpoint(&path).await.unwrap();
+
+    assert_eq!(restored.get_block_number().await.unwrap(), 1.into());
+    assert_eq!(restored.get_balance(to, None).await.unwrap(), ether);
+    assert_eq!(restored.get_balance(funded, None).await.unwrap(), ether * 2);
+    assert!(restored.get_accounts().await.unwrap().contains(&funded));
+
+    let receipt = restored
+        .get_transaction_receipt(hash)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(receipt.block_number, Some(1.into()));
+
+    // the state of every block is restored, not only the latest one
+    let block = |n: u64| Some(BlockId::Number(n.into()));
+    assert!(restored.get_balance(to, block(0)).await.unwrap().is_zero());
+    assert_eq!(restored.get_balance(to, block(1)).await.unwrap(), ether);
+}
+
+#[tokio::test]
+async fn test_access_list_keeps_recipient_keys() {
+    // PUSH1 1 SLOAD STOP
+    let contract = addr!("0x100000000000000000000000000000000000000a");
+    let provider = in_memory_with(&[(contract, "60015400")]).await;
+
+    let tx = TransactionRequest::new()
+        .from(dev_accounts()[0])
+        .to(contract)
+        .into();
+    let ret = provider
+        .create_access_list_with_gas_comparison(&tx, None)
+        .await
+        .unwrap();
+
+    // the recipient is warm, but its slot isn't
+    assert_eq!(
+        ret.access_list,
+        AccessList(vec![AccessListItem {
+            address: contract,
+            storage_keys: vec![H256::from_low_u64_be(1)],
+        }])
+    );
+    // 2400 + 1900 for the list, 2000 less for the warm SLOAD
+    assert_eq!(ret.gas_used - ret.gas_used_without_access_list, 2300.into());
+
+    // it's the same on top of an earlier block
+    let transfer = TransactionRequest::new()
+        .from(dev_accounts()[1])
+        .to(dev_accounts()[2])
+        .value(1);
+    provider.send_transaction(transfer, None).await.unwrap();
+    let block = Some(BlockId::Number(0u64.into()));
+    assert_eq!(
+        provider
+            .create_access_list_with_gas_comparison(&tx, block)
+            .await
+            .unwrap(),
+        ret
+    );
+}
+
+#[tokio::test]
+async fn test_reject_overflowing_transactions() {
+    let provider = ForkedEvmProvider::new_in_memory(Genesis::default())
+        .await
+        .unwrap();
+    let tx = TransactionRequest::new()
+        .from(dev_accounts()[0])
+        .to(dev_accounts()[1])
+        .gas(21000)
+        .gas_price(1_000_000_000u64);
+
+    let overflowing = [
+        tx.clone().gas_price(U256::max_value()),
+        tx.clone().value(U256::max_value()),
+        tx.clone().nonce(U256::from(u64::MAX) + 1),
+    ];
+    for tx in overflowing {
+        let e = provider.send_transaction(tx, None).await.unwrap_err();
+        assert!(matches!(fork_error(e), ForkError::InvalidTransaction(_)));
+    }
+
+    // the provider is still there
+    provider.send_transaction(tx, None).await.unwrap();
+    assert_eq!(provider.get_block_number().await.unwrap(), 1.into());
+}
+
+#[tokio::test]
+async fn test_transact_with_automine_off() {
+    // PUSH1 1 PUSH1 0 SSTORE STOP
+    let store = addr!("0x1000000000000000000000000000000000000006");
+    let provider = in_memory_with(&[(store, "600160005500")]).await;
+    provider.set_automine(false).await;
+    let from = dev_accounts()[0];
+    let to = addr!("0x2000000000000000000000000000000000000006");
+
+    let transfer = TransactionRequest::new().from(from).to(to).value(1);
+    provider.send_transaction(transfer, None).await.unwrap();
+    assert_eq!(provider.get_block_number().await.unwrap(), 0.into());
+
+    // it's mined on its own, without the pending transfer
+    let tx = TransactionRequest::new().from(from).to(store).into();
+    provider.transact(&tx).await.unwrap();
+    assert_eq!(provider.get_block_number().await.unwrap(), 1.into());
+    assert_eq!(
+        provider
+            .get_storage_at(store, H256::zero(), None)
+            .await
+            .unwrap(),
+        H256::from_low_u64_be(1)
+    );
+    assert_eq!(provider.get_balance(to, None).await.unwrap(), 0.into());
+    assert_eq!(provider.txpool_status().await.unwrap().pending, 1.into());
+
+    assert_eq!(provider.mine_block().await.unwrap().len(), 1);
+    assert_eq!(provider.get_block_number().await.unwrap(), 2.into());
+    assert_eq!(provider.get_balance(to, None).await.unwrap(), 1.into());
+}
+
+#[tokio::test]
+async fn test_state_diff_of_overwritten_values() {
+    // PUSH1 7 PUSH1 0 SSTORE STOP, over slot 0 holding 5
+    let contract = addr!("0x1000000000000000000000000000000000000007");
+    let code = hex::decode("600760005500").unwrap();
+    let mut genesis = Genesis::default();
+    genesis.alloc.insert(
+        contract,
+        GenesisAccount {
+            balance: 10.into(),
+            code: code.clone().into(),
+            storage: std::iter::once((H256::zero(), H256::from_low_u64_be(5))).collect(),
+            ..Default::default()
+        },
+    );
+    let provider = ForkedEvmProvider::new_in_memory(genesis).await.unwrap();
+
+    let tx = TransactionRequest::new()
+        .from(dev_accounts()[0])
+        .to(contract)
+        .value(3)
+        .into();
+    let diff = provider.transact_with_state_diff(&tx).await.unwrap();
+
+    let slot = |value: u64| -> BTreeMap<H256, H256> {
+        std::iter::once((H256::zero(), H256::from_low_u64_be(value))).collect()
+    };
+    let account = &diff.0[&contract];
+    let (pre, post) = (
+        account.pre.as_ref().unwrap(),
+        account.post.as_ref().unwrap(),
+    );
+    assert_eq!((pre.balance, post.balance), (10.into(), 13.into()));
+    assert_eq!((&pre.storage, &post.storage), (&slot(5), &slot(7)));
+
+    let prestate = diff.to_prestate_diff();
+    assert_eq!(
+        prestate.pre[&contract],
+        PrestateAccount {
+            balance: Some(10.into()),
+            nonce: None,
+            code: Some(code.into()),
+            storage: slot(5),
+        }
+    );
+    assert_eq!(
+        prestate.post[&contract],
+        PrestateAccount {
+            balance: Some(13.into()),
+            storage: slot(7),
+            ..Default::default()
+        }
+    );
+
+    let parity = diff.to_parity_state_diff();
+    let account = &parity.0[&contract];
+    assert_eq!(
+        account.balance,
+        Diff::Changed(ChangedType {
+            from: 10.into(),
+            to: 13.into()
+        })
+    );
+    assert_eq!(
+        account.storage[&H256::zero()],
+        Diff::Changed(ChangedType {
+            from: H256::from_low_u64_be(5),
+            to: H256::from_low_u64_be(7)
+        })
+    );
+    assert_eq!(account.code, Diff::Same);
+
+    // it's kept
+    assert_eq!(
+        provider
+            .get_storage_at(contract, H256::zero(), None)
+            .await
+            .unwrap(),
+        H256::from_low_u64_be(7)
+    );
+}
+
+#[tokio::test]
+async fn test_reset_drops_local_blocks() {
+    let from = dev_accounts()[0];
+    let to = addr!("0x2000000000000000000000000000000000000008");
+    let mut genesis = Genesis::default();
+    genesis.alloc.insert(
+        to,
+        GenesisAccount {
+            balance: 100.into(),
+            ..Default::default()
+        },
+    );
+    let provider = ForkedEvmProvider::new_in_memory(genesis).await.unwrap();
+
+    let transfer = TransactionRequest::new().from(from).to(to).value(1);
+    let mined = *provider.send_transaction(transfer, None).await.unwrap();
+    provider.set_automine(false).await;
+    let pending = TransactionRequest::new().from(from).to(to).value(2);
+    provider.send_transaction(pending, None).await.unwrap();
+    provider.set_balance(to, 1000).await.unwrap();
+    assert_eq!(provider.get_block_number().await.unwrap(), 1.into());
+
+    provider.reset(None).await.unwrap();
+    assert_eq!(provider.get_block_number().await.unwrap(), 0.into());
+    assert!(provider.get_block(1).await.unwrap().is_none());
+    assert!(provider
+        .get_transaction_receipt(mined)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(provider.txpool_status().await.unwrap().pending, 0.into());
+    assert_eq!(provider.get_balance(to, None).await.unwrap(), 100.into());
+    assert_eq!(
+        provider.get_transaction_count(from, None).await.unwrap(),
+        0.into()
+    );
+}
+
+#[tokio::test]
+async fn test_alloc_round_trip() {
+    let source = ForkedEvmProvider::new_in_memory(Genesis::default())
+        .await
+        .unwrap();
+    // stores 0x2a at slot 1 and returns STOP as its code
+    let initcode = hex::decode("602a6001556001601160003960016000f300").unwrap();
+    let deployment = TransactionRequest::new()
+        .from(dev_accounts()[1])
+        .data(initcode)
+        .gas(100_000)
+        .into();
+    let contract = source.deploy(&deployment).await.unwrap();
+    let rich = addr!("0x2000000000000000000000000000000000000009");
+    source.set_balance(rich, 12345).await.unwrap();
+
+    let alloc = source.export_alloc().await.unwrap();
+    assert_eq!(alloc[&contract].code, Bytes::from(vec![0x00]));
+    assert_eq!(
+        alloc[&contract].storage,
+        std::iter::once((H256::from_low_u64_be(1), H256::from_low_u64_be(0x2a))).collect()
+    );
+    assert_eq!(alloc[&rich].balance, 12345.into());
+
+    // through geth's genesis format, like a file would be
+    let json = serde_json::to_string(&alloc).unwrap();
+    let imported = ForkedEvmProvider::new_in_memory(Genesis::default())
+        .await
+        .unwrap();
+    imported
+        .import_alloc(&parse_alloc(&json).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(imported.export_alloc().await.unwrap(), alloc);
+    assert_eq!(
+        imported.get_code(contract, None).await.unwrap(),
+        Bytes::from(vec![0x00])
+    );
+    assert_eq!(
+        imported
+            .get_storage_at(contract, H256::from_low_u64_be(1), None)
+            .await
+            .unwrap(),
+        H256::from_low_u64_be(0x2a)
+    );
+    assert_eq!(
+        imported
+            .get_transaction_count(dev_accounts()[1], None)
+            .await
+            .unwrap(),
+        1.into()
+    );
+
+    // and through anvil's state dump
+    let loaded = ForkedEvmProvider::new_in_memory(Genesis::default())
+        .await
+        .unwrap();
+    loaded
+        .load_state(&source.dump_state().await.unwrap())
+        .await
+        .unwrap();
+    assert_eq!(loaded.export_alloc().await.unwrap(), alloc);
+}